@@ -0,0 +1,197 @@
+// Minimal ACPI table lookup
+//
+// Finds the RSDP in the BIOS areas, follows the RSDT / XSDT and returns the physical address of
+// a table by its signature. Tables are read through temporary memory::mmio mappings,
+// nothing is parsed beyond what find_table needs. Table contents are interpreted by the drivers
+// (see time::hpet)
+
+use crate::memory::mmio::{map_mmio, MmioError, MmioRegion};
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+const EBDA_POINTER: u64 = 0x40e;     // BIOS data area: real mode segment of the extended BIOS data area
+const EBDA_SEARCH_LEN: usize = 1024;
+
+const HEADER_LEN: usize = 36;       // common header of all tables (signature, length, ..., checksum)
+
+#[derive(Debug)]
+pub enum AcpiError
+{
+    RsdpNotFound,
+    InvalidChecksum([u8; 4]),       // signature of the damaged table
+    TableNotFound([u8; 4]),
+    Mmio(MmioError),
+}
+
+impl From<MmioError> for AcpiError
+{
+    fn from(err: MmioError) -> Self
+    {
+        AcpiError::Mmio(err)
+    }
+}
+
+// maps `len` bytes of firmware memory. ACPI tables live in reserved RAM, never handed out by the frame allocator
+fn map(phys: u64, len: usize) -> Result<MmioRegion, AcpiError>
+{
+    Ok(unsafe { map_mmio(PhysAddr::new(phys), len) }?)
+}
+
+// tables are packed: fields are read byte wise
+fn read_bytes<const N: usize>(region: &MmioRegion, offset: usize) -> [u8; N]
+{
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate()
+    {
+        *byte = region.read::<u8>(offset + i);
+    }
+    bytes
+}
+
+fn read_u32(region: &MmioRegion, offset: usize) -> u32
+{
+    u32::from_le_bytes(read_bytes(region, offset))
+}
+
+fn read_u64(region: &MmioRegion, offset: usize) -> u64
+{
+    u64::from_le_bytes(read_bytes(region, offset))
+}
+
+// all bytes of a structure sum up to 0
+fn checksum_ok(region: &MmioRegion, offset: usize, len: usize) -> bool
+{
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(region.read::<u8>(offset + i))) == 0
+}
+
+// searches a memory area for the RSDP (16 byte aligned) and returns its physical address
+fn scan_for_rsdp(start: u64, len: usize) -> Result<Option<u64>, AcpiError>
+{
+    let area = map(start, len)?;
+    for offset in (0..len.saturating_sub(20)).step_by(16)
+    {
+        if &read_bytes::<8>(&area, offset) == RSDP_SIGNATURE && checksum_ok(&area, offset, 20)
+        {
+            return Ok(Some(start + offset as u64));
+        }
+    }
+    Ok(None)
+}
+
+fn find_rsdp() -> Result<u64, AcpiError>
+{
+    let bda = map(EBDA_POINTER, 2)?;
+    let ebda = u64::from(u16::from_le_bytes(read_bytes(&bda, 0))) << 4;
+    if ebda != 0
+    {
+        if let Some(rsdp) = scan_for_rsdp(ebda, EBDA_SEARCH_LEN)?
+        {
+            return Ok(rsdp);
+        }
+    }
+    scan_for_rsdp(BIOS_AREA_START, (BIOS_AREA_END - BIOS_AREA_START) as usize)?
+        .ok_or(AcpiError::RsdpNotFound)
+}
+
+// maps a whole table after validating its checksum
+fn map_table(phys: u64) -> Result<MmioRegion, AcpiError>
+{
+    let header = map(phys, HEADER_LEN)?;
+    let signature = read_bytes::<4>(&header, 0);
+    let len = read_u32(&header, 4) as usize;
+    drop(header);
+
+    let table = map(phys, len.max(HEADER_LEN))?;
+    if !checksum_ok(&table, 0, len)
+    {
+        return Err(AcpiError::InvalidChecksum(signature));
+    }
+    Ok(table)
+}
+
+// A validated ACPI table, mapped for reading
+// offsets passed to the read functions count from the start of the header
+pub struct AcpiTable
+{
+    region: MmioRegion,
+}
+
+impl AcpiTable
+{
+    pub fn phys_addr(&self) -> PhysAddr
+    {
+        self.region.phys_addr()
+    }
+
+    pub fn signature(&self) -> [u8; 4]
+    {
+        read_bytes(&self.region, 0)
+    }
+
+    // total length including the header
+    pub fn len(&self) -> usize
+    {
+        self.region.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.region.is_empty()
+    }
+
+    pub fn read_u8(&self, offset: usize) -> u8
+    {
+        self.region.read::<u8>(offset)
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16
+    {
+        u16::from_le_bytes(read_bytes(&self.region, offset))
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32
+    {
+        read_u32(&self.region, offset)
+    }
+
+    pub fn read_u64(&self, offset: usize) -> u64
+    {
+        read_u64(&self.region, offset)
+    }
+}
+
+// First table with `signature` (e.g. b"HPET") listed in the XSDT (ACPI 2.0+) or the RSDT
+// needs memory::mmio::init
+pub fn find_table(signature: &[u8; 4]) -> Result<AcpiTable, AcpiError>
+{
+    let rsdp_addr = find_rsdp()?;
+    let rsdp = map(rsdp_addr, 36)?;
+    let revision = rsdp.read::<u8>(15);
+    // revision 2+: 64 bit XSDT at offset 24. Otherwise 32 bit RSDT at offset 16
+    let (root_addr, entry_size) = if revision >= 2 && read_u64(&rsdp, 24) != 0
+    {
+        (read_u64(&rsdp, 24), 8)
+    }
+    else
+    {
+        (u64::from(read_u32(&rsdp, 16)), 4)
+    };
+    drop(rsdp);
+
+    let root = map_table(root_addr)?;
+    let entries = (root.len() - HEADER_LEN) / entry_size;
+    for i in 0..entries
+    {
+        let offset = HEADER_LEN + i * entry_size;
+        let table_addr = if entry_size == 8 { read_u64(&root, offset) } else { u64::from(read_u32(&root, offset)) };
+        let header = map(table_addr, HEADER_LEN)?;
+        if &read_bytes::<4>(&header, 0) == signature
+        {
+            drop(header);
+            return Ok(AcpiTable { region: map_table(table_addr)? });
+        }
+    }
+    Err(AcpiError::TableNotFound(*signature))
+}
@@ -101,6 +101,7 @@ pub fn init_heap(
     //---
     // must set required PRESENT flag and WRITABLE flag for page
     // with these flags, both read and write accesses are allowed 
+    // NO_EXECUTE: heap holds data only. Jumping into it causes a page fault (requires EFER.NXE, set by memory::init)
     // ---
     // uses map_to method to create mapping in active page table.
     // uses ? in case the method fails and forward the error to caller
@@ -110,7 +111,7 @@ pub fn init_heap(
         let frame = frame_allocator
             .allocate_frame()
             .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
         unsafe {
             mapper.map_to(page, frame, flags, frame_allocator)?.flush()
         };
@@ -1,5 +1,8 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use crate::{gdt, println, print};
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::instructions::interrupts;
+use alloc::{boxed::Box, vec::Vec};
+use x86_64::instructions::port::Port;
+use crate::{gdt, println};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;   // represents primary/secondary PIC layout
 use spin;
@@ -9,6 +12,10 @@ use crate::hlt_loop;
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+mod stats;
+
+pub use stats::{interrupt_count, report, spurious_count, InterruptReport, LineStats};
+
 pub static PICS: spin::Mutex<ChainedPics> = 
     spin::Mutex::new(unsafe{ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)});
 // ChainedPics is unsafe since wrong offsets can cause undefined behavior
@@ -32,6 +39,206 @@ impl InterruptIndex
     {
         usize::from(self.as_u8())
     }
+
+    // PIC line (0..16) the interrupt arrives on
+    pub fn irq(self) -> u8
+    {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+// number of interrupt lines of the chained PICs
+pub const IRQ_COUNT: usize = 16;
+
+// What a handler on a (possibly shared) IRQ line reports back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn
+{
+    Handled,    // the handler's device raised the interrupt and was serviced
+    NotMine,    // device did not raise it. Another handler on the line might have
+}
+
+// Handler for a hardware interrupt line. Runs in interrupt context:
+// must not block, allocate or take locks that tasks hold with interrupts enabled
+pub type IrqHandler = Box<dyn Fn() -> IrqReturn + Send + Sync>;
+
+// Identifies one registered handler. Pass it to unregister_irq to remove the handler again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle
+{
+    irq: u8,
+    id: u64,
+}
+
+impl IrqHandle
+{
+    pub fn irq(&self) -> u8
+    {
+        self.irq
+    }
+}
+
+struct IrqLine
+{
+    handlers: Vec<(u64, IrqHandler)>,   // chained in registration order
+    unhandled: u64,                     // interrupts no handler claimed
+}
+
+struct IrqTable
+{
+    next_id: u64,
+    lines: [IrqLine; IRQ_COUNT],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_LINE: IrqLine = IrqLine { handlers: Vec::new(), unhandled: 0 };
+
+// handlers registered through register_irq, indexed by IRQ line
+// only locked with interrupts disabled outside of handlers, so an interrupt never spins on it
+static IRQ_TABLE: spin::Mutex<IrqTable> = spin::Mutex::new(IrqTable { next_id: 0, lines: [EMPTY_LINE; IRQ_COUNT] });
+
+// IDT entries for IRQ 1..=15. They all dispatch to IRQ_TABLE (IRQ 0 is the timer, see timer_interrupt_handler)
+const IRQ_STUBS: [HandlerFunc; IRQ_COUNT - 1] = [
+    irq_interrupt_handler::<1>, irq_interrupt_handler::<2>, irq_interrupt_handler::<3>,
+    irq_interrupt_handler::<4>, irq_interrupt_handler::<5>, irq_interrupt_handler::<6>,
+    irq_interrupt_handler::<7>, irq_interrupt_handler::<8>, irq_interrupt_handler::<9>,
+    irq_interrupt_handler::<10>, irq_interrupt_handler::<11>, irq_interrupt_handler::<12>,
+    irq_interrupt_handler::<13>, irq_interrupt_handler::<14>, irq_interrupt_handler::<15>,
+];
+
+// Adds handler to IRQ line `irq`. The IDT entry already exists, so drivers do not touch the IDT or InterruptIndex
+// several drivers may share a line: every handler runs on each interrupt, in registration order.
+// the first handler unmasks the line, EOI is sent centrally after all handlers ran
+// IRQ 0 (timer) handlers are chained behind the built-in timer tick. Panics for the cascade line 2 or lines >= 16
+pub fn register_irq(irq: u8, handler: impl Fn() -> IrqReturn + Send + Sync + 'static) -> IrqHandle
+{
+    assert!(irq != 2 && usize::from(irq) < IRQ_COUNT, "IRQ {} cannot take a handler", irq);
+    let handler: IrqHandler = Box::new(handler);    // allocate before disabling interrupts
+    interrupts::without_interrupts(||
+    {
+        let mut table = IRQ_TABLE.lock();
+        let id = table.next_id;
+        table.next_id += 1;
+        let line = &mut table.lines[usize::from(irq)];
+        line.handlers.push((id, handler));
+        if line.handlers.len() == 1
+        {
+            set_irq_masked(irq, false);
+        }
+        IrqHandle { irq, id }
+    })
+}
+
+// Removes a handler added by register_irq. The line is masked again when its last handler is gone
+// (the timer line stays unmasked). Returns false if the handler was already removed
+pub fn unregister_irq(handle: IrqHandle) -> bool
+{
+    let handler = interrupts::without_interrupts(||
+    {
+        let mut table = IRQ_TABLE.lock();
+        let line = &mut table.lines[usize::from(handle.irq)];
+        let index = line.handlers.iter().position(|(id, _)| *id == handle.id)?;
+        let handler = line.handlers.remove(index);
+        if line.handlers.is_empty() && handle.irq != InterruptIndex::Timer.irq()
+        {
+            set_irq_masked(handle.irq, true);
+        }
+        Some(handler)
+    });
+    // free outside the critical section
+    handler.is_some()
+}
+
+// number of interrupts on `irq` that none of its handlers claimed (IrqReturn::NotMine from all)
+pub fn unhandled_count(irq: u8) -> u64
+{
+    interrupts::without_interrupts(|| IRQ_TABLE.lock().lines[usize::from(irq)].unhandled)
+}
+
+// runs all handlers of `irq` and sends EOI. Common path of every hardware interrupt
+fn dispatch_irq(irq: u8)
+{
+    let vector = PIC_1_OFFSET + irq;
+    if is_spurious(irq)
+    {
+        stats::count_spurious();
+        if irq == 15
+        {
+            // the secondary PIC did not raise anything, but the primary did see its cascade line.
+            // EOI only the primary (a vector of the primary PIC)
+            unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + 2) };
+        }
+        // spurious IRQ 7: no EOI at all, the primary has no interrupt in service
+        return;
+    }
+    stats::count_vector(vector);
+    {
+        let mut table = IRQ_TABLE.lock();
+        let line = &mut table.lines[usize::from(irq)];
+        let mut handled = false;
+        for (_, handler) in line.handlers.iter()
+        {
+            // no short cut: on a shared line several devices may have raised it at once
+            handled |= handler() == IrqReturn::Handled;
+        }
+        if !handled
+        {
+            line.unhandled += 1;
+        }
+    }
+    unsafe
+    {
+        // notify_end_of_interrupt figures out whether primary or secondary PIC sent the interrupt.
+        // then uses command and data port to send EOI signal to respective controllers
+        // May delete an important unsent interrupt or cause system to hang if wrong interrupt vector number is used
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
+}
+
+// command ports of the PICs and OCW3 command to read the in-service register (ISR) from them
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const OCW3_READ_ISR: u8 = 0x0b;
+
+// IRQ 7 and 15 are also raised by the PIC itself when an interrupt disappears before it is acknowledged
+// (e.g. electrical noise). Such a spurious interrupt has no bit set in the in-service register
+fn is_spurious(irq: u8) -> bool
+{
+    let command = match irq
+    {
+        7 => PIC_1_COMMAND,
+        15 => PIC_2_COMMAND,
+        _ => return false,
+    };
+    let _pics = PICS.lock();    // no one else talks to the PICs meanwhile
+    let mut port = Port::<u8>::new(command);
+    let isr = unsafe
+    {
+        port.write(OCW3_READ_ISR);
+        port.read()
+    };
+    isr & (1 << 7) == 0
+}
+
+// masks (disables) or unmasks a PIC line. Unmasking a secondary line also unmasks the cascade (IRQ 2)
+fn set_irq_masked(irq: u8, masked: bool)
+{
+    let mut pics = PICS.lock();
+    let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+    let (mask, bit) = if irq < 8 { (&mut primary, irq) } else { (&mut secondary, irq - 8) };
+    if masked
+    {
+        *mask |= 1 << bit;
+    }
+    else
+    {
+        *mask &= !(1 << bit);
+        if irq >= 8
+        {
+            primary &= !(1 << 2);
+        }
+    }
+    unsafe { pics.write_masks(primary, secondary) };
 }
 
 lazy_static!
@@ -49,37 +256,27 @@ lazy_static!
         }
 
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        for (i, stub) in IRQ_STUBS.iter().enumerate()
+        {
+            idt[usize::from(PIC_1_OFFSET) + 1 + i].set_handler_fn(*stub);
+        }
 
         idt
     };
 }
 
+// built-in tick (time::pit tick count), then handlers chained through register_irq(0, ..)
+// works before the heap exists, since nothing has to be registered for it
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame)
 {
-    print!(".");
-    unsafe 
-    {
-        // notify_end_of_interrupt figures out whether primary or secondary PIC sent the interrupt.
-        // then uses command and data port to send EOI signal to respective controllers
-        // May delete an important unsent interrupt or cause system to hang if wrong interrupt vector number is used
-        PICS.lock().
-        notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    crate::time::pit::tick();
+    dispatch_irq(InterruptIndex::Timer.irq());
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame)
+// common entry of IRQ 1..=15
+extern "x86-interrupt" fn irq_interrupt_handler<const IRQ: u8>(_stack_frame: InterruptStackFrame)
 {
-    use x86_64::instructions::port::Port;
-
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode); // new
-
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    dispatch_irq(IRQ);
 }
 
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
@@ -0,0 +1,108 @@
+// Interrupt counters per vector, and a /proc/interrupts style report of them
+//
+// The kernel runs on the bootstrap processor only, so there is one set of counters and the
+// report has a single CPU0 column. Counting per CPU needs application processor startup first
+
+use super::{IRQ_COUNT, IRQ_TABLE, PIC_1_OFFSET};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+
+// how often each IDT vector fired (exceptions are not counted)
+static VECTOR_COUNTS: [AtomicU64; 256] = [ZERO; 256];
+
+// spurious IRQ 7 / IRQ 15. Not included in VECTOR_COUNTS
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn count_vector(vector: u8)
+{
+    VECTOR_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+pub(super) fn count_spurious()
+{
+    SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+// number of times IDT vector `vector` fired since boot
+pub fn interrupt_count(vector: u8) -> u64
+{
+    VECTOR_COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+// number of spurious IRQ 7 and IRQ 15 seen
+pub fn spurious_count() -> u64
+{
+    SPURIOUS_COUNT.load(Ordering::Relaxed)
+}
+
+// Snapshot of all counters, formatted like /proc/interrupts:
+//
+//             CPU0
+//    0:       1234   XT-PIC   1 handler(s)
+//    1:         56   XT-PIC   1 handler(s)
+//  SPU:          2   spurious
+//  UNH:          0   unhandled
+pub fn report() -> InterruptReport
+{
+    let mut report = InterruptReport
+    {
+        lines: [LineStats::default(); IRQ_COUNT],
+        spurious: spurious_count(),
+    };
+    // handler counts and unhandled counters live behind the table lock
+    interrupts::without_interrupts(||
+    {
+        let table = IRQ_TABLE.lock();
+        for (stats, line) in report.lines.iter_mut().zip(table.lines.iter())
+        {
+            stats.handlers = line.handlers.len();
+            stats.unhandled = line.unhandled;
+        }
+    });
+    for (irq, stats) in report.lines.iter_mut().enumerate()
+    {
+        stats.count = interrupt_count(PIC_1_OFFSET + irq as u8);
+    }
+    report
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LineStats
+{
+    pub count: u64,
+    pub handlers: usize,
+    pub unhandled: u64,             // interrupts no handler claimed
+}
+
+#[derive(Debug, Clone)]
+pub struct InterruptReport
+{
+    pub lines: [LineStats; IRQ_COUNT],  // indexed by IRQ line
+    pub spurious: u64,
+}
+
+impl fmt::Display for InterruptReport
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(f, "      {:>9}0", "CPU")?;
+
+        for (irq, line) in self.lines.iter().enumerate()
+        {
+            // lines that never fired and have no handler are left out, like in /proc/interrupts
+            if line.handlers == 0 && line.count == 0
+            {
+                continue;
+            }
+            writeln!(f, "{:>4}: {:>10}   XT-PIC   {} handler(s)", irq, line.count, line.handlers)?;
+        }
+
+        writeln!(f, " SPU: {:>10}   spurious", self.spurious)?;
+        let unhandled: u64 = self.lines.iter().map(|line| line.unhandled).sum();
+        writeln!(f, " UNH: {:>10}   unhandled", unhandled)
+    }
+}
@@ -18,6 +18,8 @@ pub fn exit_qemu(exit_code: QemuExitCode)
 {
     use x86_64::instructions::port::Port;
 
+    // output still queued for the serial port would be lost
+    serial::flush();
     unsafe // writing to an I/O port could result in arbitrary behaviour
     {
         let mut port = Port::new(0xf4);
@@ -33,6 +35,9 @@ pub mod gdt;
 pub mod memory;
 pub mod allocator;
 pub mod task;
+pub mod time;
+pub mod acpi;
+pub mod ps2;
 
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
@@ -65,6 +70,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial::enter_panic_mode();
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
     exit_qemu(QemuExitCode::Failed);
@@ -84,6 +90,10 @@ pub fn init()
     gdt::init();
     interrupts::init_idt();
     unsafe{interrupts::PICS.lock().initialize()};
+    time::init();   // PIT tick rate and TSC calibration, before the first timer interrupt
+    serial::init(); // finds COM1..COM4
+    // keyboard in scancode set 1 (see task::keyboard::set_scancode_set). No controller: no PS/2 input
+    let _ = ps2::init(true);
     x86_64::instructions::interrupts::enable(); 
     // interrupts enable executes sti instruction to enable external interrupts
 }
@@ -7,11 +7,11 @@
 extern crate alloc;
 
 use core::panic::PanicInfo;
-use my_os::println;
+use my_os::{log, println};
 use bootloader::{BootInfo, entry_point};
 use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
-use my_os::task::{Task, simple_executor::SimpleExecutor};
-use my_os::task::keyboard;
+use my_os::task::{Task, Priority, simple_executor::SimpleExecutor};
+use my_os::task::{keyboard, serial};
 use my_os::task::executor::Executor;
 
 // compiler will transform it into state machine that implements Future
@@ -39,6 +39,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> !
 {
     use my_os::allocator;
     use my_os::memory::{self, BootInfoFrameAllocator};
+    use my_os::serial::{ComPort, Role};
+    use my_os::vga_buffer::console;
     use x86_64::VirtAddr;
 
     println!("Hellow world{}", "!");
@@ -49,11 +51,38 @@ fn kernel_main(boot_info: &'static BootInfo) -> !
     let mut frame_allocator = unsafe {
         BootInfoFrameAllocator::init(&boot_info.memory_map)
     };
+    // .text read only, everything else no execute
+    unsafe { memory::protect_kernel(&mut mapper) }
+        .expect("kernel section protection failed");
 
     // new
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
 
+    // lines scrolled off the screen stay reachable with Shift+PageUp
+    my_os::vga_buffer::enable_scrollback(200);
+    // serial_print! output goes out in the THR-empty interrupt from here on
+    my_os::serial::init_tx_buffer();
+    // a second serial port takes a copy of the kernel log (e.g. QEMU -serial file:log.txt)
+    if my_os::serial::is_present(ComPort::Com2)
+    {
+        my_os::serial::assign(Role::Log, Some(ComPort::Com2)).expect("COM2 is present");
+    }
+    // Alt+F1..F6. Shell on console 1, kernel log on console 6
+    console::init();
+    console::with(console::LOG, |writer| writer.enable_scrollback(100));
+
+    // from here on page table changes go through memory::mmio (map_mmio)
+    memory::mmio::init(mapper, frame_allocator);
+
+    // HPET counter as clock, its comparators for timer tick and timer deadlines. PIT and TSC otherwise,
+    // the PIT then also wakes the CPU from tickless idle
+    if let Err(err) = my_os::time::hpet::use_as_system_timer()
+    {
+        log!("no HPET: {:?}", err);
+        my_os::time::pit::use_as_wakeup_source();
+    }
+
     // allocate a number on the heap
     let heap_value = Box::new(41);
     println!("heap_value at {:p}", heap_value);
@@ -75,8 +104,10 @@ fn kernel_main(boot_info: &'static BootInfo) -> !
     println!("reference count is {} now", Rc::strong_count(&cloned_reference));
 
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses())); // new
+    executor.spawn(Task::new(example_task()).with_name("example").with_priority(Priority::Background));
+    executor.spawn(Task::new(keyboard::print_keypresses()).with_name("keyboard").with_priority(Priority::Interactive));
+    // lines typed on the serial console, e.g. with -serial stdio
+    executor.spawn(Task::new(serial::print_lines()).with_name("serial").with_priority(Priority::Interactive));
     executor.run();
 
     #[cfg(test)]
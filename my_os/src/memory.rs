@@ -4,6 +4,12 @@ use x86_64::structures::paging::OffsetPageTable;
 use x86_64::structures::paging::{Page, PhysFrame, Mapper, Size4KiB, FrameAllocator};
 use bootloader::bootinfo::MemoryMap;
 use bootloader::bootinfo::MemoryRegionType;
+use x86_64::structures::paging::{PageTableFlags, mapper::FlagUpdateError};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+
+// map_mmio: uncached mappings of device memory for drivers
+pub mod mmio;
 
 // Frame allocator returns usable frames from bootloader's memory map
 pub struct BootInfoFrameAllocator
@@ -106,29 +112,101 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
 // must only called once to avoid aliasing &mut references
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> 
 {
+    // NO_EXECUTE bit in page table entry is a reserved bit until EFER.NXE is set.
+    // must be enabled before any mapping (e.g. the heap) uses PageTableFlags::NO_EXECUTE
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+
     let level_4_table = active_level_4_table(physical_memory_offset);   // to retrieve mutable ref to level 4 page table
     OffsetPageTable::new(level_4_table, physical_memory_offset) // new function expects virtual address at which the mapping of physical memory starts
 }
 // takes physical_memory_offset as arg and returns new OffsetPageTable instance with a 'static lifetime
 // instance stays valid for complete runtime of kernel
 
-// expects a mutable reference to OffsetPageTable instance and frame_allocator
-// frame_allocator uses imple Trait syntax to be generic over all types that implement FrameAllocator trait
-pub fn create_example_mapping(page: Page, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>)
+// Section boundaries defined in linker.ld. Each one is 4 KiB aligned.
+// only the addresses of these symbols are meaningful, never read their values
+extern "C"
 {
-    use x86_64::structures::paging::PageTableFlags as Flags;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+}
+
+// Enforces write XOR execute on the kernel image
+// .text            -> read only, executable
+// .rodata          -> read only, no execute
+// .data and .bss   -> writable, no execute
+// then turns on CR0.WP so the kernel itself cannot write to read only pages,
+// and SMEP/SMAP when the CPU supports them
+// Unsafe
+// caller must gurantee that the kernel was linked with linker.ld and mapped with 4 KiB pages
+pub unsafe fn protect_kernel(mapper: &mut OffsetPageTable) -> Result<(), FlagUpdateError>
+{
+    use core::ptr::addr_of;
+
+    let sections = [
+        (addr_of!(__text_start), addr_of!(__text_end), PageTableFlags::PRESENT),
+        (addr_of!(__rodata_start), addr_of!(__rodata_end), PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE),
+        (addr_of!(__data_start), addr_of!(__data_end), PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE),
+        (addr_of!(__bss_start), addr_of!(__bss_end), PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE),
+    ];
+
+    for &(start, end, flags) in &sections
+    {
+        // empty section (e.g. no .data at all) -> nothing to remap
+        if start == end
+        {
+            continue;
+        }
+        let start_page: Page = Page::containing_address(VirtAddr::from_ptr(start));
+        let end_page: Page = Page::containing_address(VirtAddr::from_ptr(end) - 1u64);
+        for page in Page::range_inclusive(start_page, end_page)
+        {
+            mapper.update_flags(page, flags)?.flush();
+        }
+    }
 
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+    // WP: supervisor writes to read only pages fault as well (by default only user mode writes do)
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
 
-    // map_to is unsafe. Caller must ensure that frame is not already in use.
-    // Mapping same frame twice will result in undefined behavior
-    let map_to_result = unsafe 
+    // SMEP: kernel may not execute user pages
+    // SMAP: kernel may not access user pages
+    // CPUID leaf 7 (structured extended features) reports support in EBX bit 7 and 20
+    let (smep, smap) = supervisor_protection_support();
+    Cr4::update(|flags|
     {
-        // FIXME: this is not safe, we do it only for testing
-        mapper.map_to(page, frame, flags, frame_allocator)
-    };
-    map_to_result.expect("map_to failed").flush();
+        if smep
+        {
+            flags.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION);
+        }
+        if smap
+        {
+            flags.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+        }
+    });
+
+    Ok(())
+}
+
+// Returns (SMEP supported, SMAP supported)
+// __cpuid is an unsafe intrinsic on older toolchains and a safe one on newer ones
+#[allow(unused_unsafe)]
+fn supervisor_protection_support() -> (bool, bool)
+{
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+
+    // leaf 0 returns the highest supported standard leaf in EAX
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 7
+    {
+        return (false, false);
+    }
+    let ebx = unsafe { __cpuid_count(7, 0) }.ebx;
+    (ebx & (1 << 7) != 0, ebx & (1 << 20) != 0)
 }
 
 pub struct EmptyFrameAllocator;
@@ -0,0 +1,215 @@
+use super::BootInfoFrameAllocator;
+use alloc::vec::Vec;
+use core::mem;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::structures::paging::{
+    mapper::MapToError, Mapper, OffsetPageTable, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Dedicated virtual address range for device memory. Sits above the heap (HEAP_START)
+// every MmioRegion gets its own page aligned slice of this range
+pub const MMIO_START: u64 = 0x_4444_8000_0000;
+pub const MMIO_SIZE: u64 = 256 * 1024 * 1024;    // 256 MiB
+
+const PAGE_SIZE: u64 = 4096;
+
+// Device registers must not be cached: reads have side effects and values change without CPU writes
+// NO_EXECUTE: device memory never holds code
+const MMIO_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::NO_EXECUTE);
+
+#[derive(Debug)]
+pub enum MmioError
+{
+    NotInitialized,                 // mmio::init was not called yet
+    OutOfVirtualSpace,              // no free range in the MMIO area is large enough
+    TooLarge,                       // the range is larger than the whole MMIO area
+    MapFailed(MapToError<Size4KiB>),
+}
+
+// Page table and frame allocator used by map_mmio and MmioRegion::drop
+// stored globally since a region can be dropped anywhere in the kernel
+struct KernelPaging
+{
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BootInfoFrameAllocator,
+    free: Vec<Range<u64>>,  // free virtual ranges inside the MMIO area, sorted by start address
+}
+
+static KERNEL_PAGING: Mutex<Option<KernelPaging>> = Mutex::new(None);
+
+// Hands the kernel's mapper and frame allocator over to the MMIO allocator
+// Must be called after allocator::init_heap since the free list lives on the heap
+pub fn init(mapper: OffsetPageTable<'static>, frame_allocator: BootInfoFrameAllocator)
+{
+    let mut free = Vec::new();
+    free.push(MMIO_START..MMIO_START + MMIO_SIZE);
+    *KERNEL_PAGING.lock() = Some(KernelPaging { mapper, frame_allocator, free });
+}
+
+impl KernelPaging
+{
+    // first fit search through free list
+    fn allocate_virt(&mut self, size: u64) -> Option<u64>
+    {
+        let index = self.free.iter().position(|r| r.end - r.start >= size)?;
+        let range = &mut self.free[index];
+        let start = range.start;
+        range.start += size;
+        if range.start == range.end
+        {
+            self.free.remove(index);
+        }
+        Some(start)
+    }
+
+    // puts range back in sorted position and merges it with its neighbours
+    fn free_virt(&mut self, start: u64, size: u64)
+    {
+        let end = start + size;
+        let index = self.free.iter().position(|r| r.start > start).unwrap_or(self.free.len());
+        self.free.insert(index, start..end);
+
+        if index + 1 < self.free.len() && self.free[index].end == self.free[index + 1].start
+        {
+            self.free[index].end = self.free[index + 1].end;
+            self.free.remove(index + 1);
+        }
+        if index > 0 && self.free[index - 1].end == self.free[index].start
+        {
+            self.free[index - 1].end = self.free[index].end;
+            self.free.remove(index);
+        }
+    }
+}
+
+// Mapping of len bytes of device memory starting at physical address phys
+// all accesses are volatile and bounds checked against len
+// unmapped again (and its virtual range reused) when dropped
+#[derive(Debug)]
+pub struct MmioRegion
+{
+    phys: PhysAddr,
+    virt_base: u64,     // page aligned start of the mapping
+    offset: usize,      // offset of phys inside its first page
+    len: usize,
+    pages: u64,
+}
+
+// Maps the physical range [phys, phys + len) into the MMIO area, uncached
+// Unsafe
+// caller must gurantee that the range belongs to a device (not RAM used by the frame allocator)
+// and that accessing it has no unsafe side effects for the rest of the kernel
+pub unsafe fn map_mmio(phys: PhysAddr, len: usize) -> Result<MmioRegion, MmioError>
+{
+    let mut guard = KERNEL_PAGING.lock();
+    let paging = guard.as_mut().ok_or(MmioError::NotInitialized)?;
+
+    let first_frame: PhysFrame = PhysFrame::containing_address(phys);
+    let offset = (phys - first_frame.start_address()) as usize;
+    // checked before rounding up, so the page count cannot overflow either
+    let end = offset.checked_add(len.max(1))
+        .map(|end| end as u64)
+        .filter(|&end| end <= MMIO_SIZE)
+        .ok_or(MmioError::TooLarge)?;
+    let pages = (end + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    let virt_base = paging.allocate_virt(pages * PAGE_SIZE).ok_or(MmioError::OutOfVirtualSpace)?;
+
+    for i in 0..pages
+    {
+        let page: Page = Page::containing_address(VirtAddr::new(virt_base + i * PAGE_SIZE));
+        let frame = first_frame + i;
+        match paging.mapper.map_to(page, frame, MMIO_FLAGS, &mut paging.frame_allocator)
+        {
+            Ok(flush) => flush.flush(),
+            Err(err) =>
+            {
+                // roll back the pages mapped so far
+                for j in 0..i
+                {
+                    let page: Page = Page::containing_address(VirtAddr::new(virt_base + j * PAGE_SIZE));
+                    if let Ok((_, flush)) = paging.mapper.unmap(page)
+                    {
+                        flush.flush();
+                    }
+                }
+                paging.free_virt(virt_base, pages * PAGE_SIZE);
+                return Err(MmioError::MapFailed(err));
+            }
+        }
+    }
+
+    Ok(MmioRegion { phys, virt_base, offset, len, pages })
+}
+
+impl MmioRegion
+{
+    pub fn phys_addr(&self) -> PhysAddr
+    {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr
+    {
+        VirtAddr::new(self.virt_base + self.offset as u64)
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len == 0
+    }
+
+    // returns pointer to byte `offset` of the region for an access of type T
+    // panics if the access does not fit into the region or is misaligned
+    fn ptr<T>(&self, offset: usize) -> *mut T
+    {
+        let size = mem::size_of::<T>();
+        assert!(
+            offset.checked_add(size).map_or(false, |end| end <= self.len),
+            "MMIO access out of bounds: offset {:#x} size {} len {:#x}", offset, size, self.len
+        );
+        let addr = self.virt_base as usize + self.offset + offset;
+        assert!(addr % mem::align_of::<T>() == 0, "misaligned MMIO access at offset {:#x}", offset);
+        addr as *mut T
+    }
+
+    // Volatile read of a T at byte `offset` from the start of the region
+    pub fn read<T: Copy>(&self, offset: usize) -> T
+    {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    // Volatile write of a T at byte `offset` from the start of the region
+    pub fn write<T: Copy>(&self, offset: usize, value: T)
+    {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+}
+
+impl Drop for MmioRegion
+{
+    fn drop(&mut self)
+    {
+        let mut guard = KERNEL_PAGING.lock();
+        let paging = guard.as_mut().expect("MmioRegion exists without mmio::init");
+        for i in 0..self.pages
+        {
+            let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(self.virt_base + i * PAGE_SIZE));
+            // frame belongs to the device -> only remove the mapping, never hand it to the frame allocator
+            let (_frame, flush) = paging.mapper.unmap(page).expect("MMIO page was not mapped");
+            flush.flush();
+        }
+        paging.free_virt(self.virt_base, self.pages * PAGE_SIZE);
+    }
+}
@@ -0,0 +1,313 @@
+// 8042 PS/2 controller
+//
+// init resets the controller to a known state: self-test, detection and test of both ports,
+// then enables the ports, their interrupts (IRQ 1 keyboard, IRQ 12 mouse) and translation.
+// commands to the controller go to port 0x64, bytes to and from the devices through port 0x60.
+// Device bytes normally arrive by interrupt (task::keyboard reads them in its IRQ 1 handler).
+// The functions here talk to the controller synchronously: interrupts are disabled and
+// the status register is polled, so a reply is not taken by an interrupt handler
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;       // read
+const COMMAND: u16 = 0x64;      // write
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;  // byte waiting in DATA
+const STATUS_INPUT_FULL: u8 = 1 << 1;   // controller has not taken the last byte yet
+const STATUS_AUX: u8 = 1 << 5;          // the byte in DATA came from the second port
+
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_SECOND: u8 = 0xa7;
+const CMD_ENABLE_SECOND: u8 = 0xa8;
+const CMD_TEST_SECOND: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_FIRST: u8 = 0xab;
+const CMD_DISABLE_FIRST: u8 = 0xad;
+const CMD_ENABLE_FIRST: u8 = 0xae;
+const CMD_WRITE_SECOND: u8 = 0xd4;      // next data byte goes to the second port's device
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// controller configuration byte
+pub const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+pub const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+pub const CONFIG_SECOND_CLOCK_OFF: u8 = 1 << 5;
+pub const CONFIG_TRANSLATION: u8 = 1 << 6;  // first port: scancode set 2 translated to set 1
+
+// device replies
+pub const ACK: u8 = 0xfa;
+pub const RESEND: u8 = 0xfe;
+
+// polls of the status register before giving up. A port access takes about 1 us
+const POLL_LIMIT: usize = 100_000;
+const RESEND_LIMIT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error
+{
+    Timeout,            // controller or device did not respond
+    Resend(u8),         // device kept asking for the byte again
+    Unexpected(u8),     // reply was neither ACK nor RESEND
+    SelfTestFailed(u8), // controller self-test answer
+    NoPort,             // the device's port does not exist or failed its test
+}
+
+// Ports found by init
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ports
+{
+    pub first: bool,    // keyboard
+    pub second: bool,   // mouse
+}
+
+// 0: init not run, else 1 | first << 1 | second << 2
+static PORTS: AtomicU8 = AtomicU8::new(0);
+
+fn status() -> u8
+{
+    unsafe { Port::<u8>::new(STATUS).read() }
+}
+
+fn wait_input_empty() -> Result<(), Ps2Error>
+{
+    for _ in 0..POLL_LIMIT
+    {
+        if status() & STATUS_INPUT_FULL == 0
+        {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+fn wait_output_full() -> Result<(), Ps2Error>
+{
+    for _ in 0..POLL_LIMIT
+    {
+        if status() & STATUS_OUTPUT_FULL != 0
+        {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+pub(crate) fn write_command(command: u8) -> Result<(), Ps2Error>
+{
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(COMMAND).write(command) };
+    Ok(())
+}
+
+pub(crate) fn write_data(byte: u8) -> Result<(), Ps2Error>
+{
+    wait_input_empty()?;
+    unsafe { Port::<u8>::new(DATA).write(byte) };
+    Ok(())
+}
+
+pub(crate) fn read_data() -> Result<u8, Ps2Error>
+{
+    wait_output_full()?;
+    Ok(unsafe { Port::<u8>::new(DATA).read() })
+}
+
+// Reads the next byte of the mouse (second port)
+// keyboard bytes that arrive meanwhile are discarded, so they are not taken as the mouse's reply
+fn read_mouse_data() -> Result<u8, Ps2Error>
+{
+    for _ in 0..POLL_LIMIT
+    {
+        let status = status();
+        if status & STATUS_OUTPUT_FULL != 0
+        {
+            let byte = unsafe { Port::<u8>::new(DATA).read() };
+            if status & STATUS_AUX != 0
+            {
+                return Ok(byte);
+            }
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+// reads the next byte of the device on the first or second port
+fn read_device_data(second: bool) -> Result<u8, Ps2Error>
+{
+    if second { read_mouse_data() } else { read_data() }
+}
+
+// reads the controller configuration byte
+pub fn read_config() -> Result<u8, Ps2Error>
+{
+    interrupts::without_interrupts(||
+    {
+        write_command(CMD_READ_CONFIG)?;
+        read_data()
+    })
+}
+
+pub fn write_config(config: u8) -> Result<(), Ps2Error>
+{
+    interrupts::without_interrupts(||
+    {
+        write_command(CMD_WRITE_CONFIG)?;
+        write_data(config)
+    })
+}
+
+// Turns scancode translation of the first port on or off. Off, the keyboard's set 2 codes arrive unchanged
+pub fn set_translation(enabled: bool) -> Result<(), Ps2Error>
+{
+    let config = read_config()?;
+    let config = if enabled { config | CONFIG_TRANSLATION } else { config & !CONFIG_TRANSLATION };
+    write_config(config)
+}
+
+// Initializes the controller. `translation` selects scancode set 1 (translated) for the keyboard
+// called by crate::init. Devices keep their settings (task::mouse resets the mouse)
+pub fn init(translation: bool) -> Result<Ports, Ps2Error>
+{
+    let ports = interrupts::without_interrupts(|| init_controller(translation))?;
+    PORTS.store(1 | u8::from(ports.first) << 1 | u8::from(ports.second) << 2, Ordering::Relaxed);
+    Ok(ports)
+}
+
+// ports found by init. None before init (or if it failed)
+pub fn ports() -> Option<Ports>
+{
+    match PORTS.load(Ordering::Relaxed)
+    {
+        0 => None,
+        bits => Some(Ports { first: bits & 2 != 0, second: bits & 4 != 0 }),
+    }
+}
+
+fn init_controller(translation: bool) -> Result<Ports, Ps2Error>
+{
+    // no device may send anything meanwhile
+    write_command(CMD_DISABLE_FIRST)?;
+    write_command(CMD_DISABLE_SECOND)?;
+    flush();
+
+    // interrupts and translation off during the tests
+    write_command(CMD_READ_CONFIG)?;
+    let config = read_data()?;
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION))?;
+
+    write_command(CMD_SELF_TEST)?;
+    match read_data()?
+    {
+        SELF_TEST_PASSED => {}
+        other => return Err(Ps2Error::SelfTestFailed(other)),
+    }
+    // the self-test may reset the controller: write the configuration again
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION))?;
+
+    // a controller with a single port ignores the enable command and keeps its clock off
+    write_command(CMD_ENABLE_SECOND)?;
+    write_command(CMD_READ_CONFIG)?;
+    let has_second = read_data()? & CONFIG_SECOND_CLOCK_OFF == 0;
+    write_command(CMD_DISABLE_SECOND)?;
+
+    write_command(CMD_TEST_FIRST)?;
+    let first = read_data()? == PORT_TEST_PASSED;
+    let second = has_second &&
+    {
+        write_command(CMD_TEST_SECOND)?;
+        read_data()? == PORT_TEST_PASSED
+    };
+
+    let mut config = config & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ | CONFIG_TRANSLATION);
+    if first
+    {
+        write_command(CMD_ENABLE_FIRST)?;
+        config |= CONFIG_FIRST_IRQ;
+        if translation
+        {
+            config |= CONFIG_TRANSLATION;
+        }
+    }
+    if second
+    {
+        write_command(CMD_ENABLE_SECOND)?;
+        config |= CONFIG_SECOND_IRQ;
+        config &= !CONFIG_SECOND_CLOCK_OFF;
+    }
+    write_command(CMD_WRITE_CONFIG)?;
+    write_data(config)?;
+    Ok(Ports { first, second })
+}
+
+// discards bytes left in the output buffer
+fn flush()
+{
+    for _ in 0..16
+    {
+        if status() & STATUS_OUTPUT_FULL == 0
+        {
+            break;
+        }
+        unsafe { Port::<u8>::new(DATA).read() };
+    }
+}
+
+// sends one byte to the device on the first or second port and waits for its ACK, repeating it on RESEND
+fn send_device_byte(second: bool, byte: u8) -> Result<(), Ps2Error>
+{
+    for _ in 0..RESEND_LIMIT
+    {
+        if second
+        {
+            write_command(CMD_WRITE_SECOND)?;
+        }
+        write_data(byte)?;
+        match read_device_data(second)?
+        {
+            ACK => return Ok(()),
+            RESEND => continue,
+            other => return Err(Ps2Error::Unexpected(other)),
+        }
+    }
+    Err(Ps2Error::Resend(byte))
+}
+
+// Sends a command with its data bytes to the keyboard (first port)
+// the ACKs are consumed here. The keyboard interrupt still fires for them, its handler
+// then reads a stale ACK byte, which task::keyboard drops
+pub fn keyboard_command(command: u8, data: &[u8]) -> Result<(), Ps2Error>
+{
+    interrupts::without_interrupts(||
+    {
+        send_device_byte(false, command)?;
+        data.iter().try_for_each(|&byte| send_device_byte(false, byte))
+    })
+}
+
+// Sends a command to the mouse (second port) and reads `reply.len()` bytes of its answer
+// the IRQ 12 handler sees the bytes again, like keyboard_command. Keystrokes typed meanwhile are lost.
+// Fails with NoPort without a second port
+pub fn mouse_command(command: u8, data: &[u8], reply: &mut [u8]) -> Result<(), Ps2Error>
+{
+    if ports().map_or(false, |ports| !ports.second)
+    {
+        return Err(Ps2Error::NoPort);
+    }
+    interrupts::without_interrupts(||
+    {
+        send_device_byte(true, command)?;
+        data.iter().try_for_each(|&byte| send_device_byte(true, byte))?;
+        reply.iter_mut().try_for_each(|byte|
+        {
+            *byte = read_mouse_data()?;
+            Ok(())
+        })
+    })
+}
@@ -1,29 +1,780 @@
+// Legacy serial ports COM1..COM4 (16550 UARTs)
+//
+// init detects which ports exist and sets them up with LineConfig::DEFAULT, configure changes
+// baud rate, data bits, parity and stop bits at runtime. Every port has a transmit ring drained by
+// its THR-empty interrupt (after init_tx_buffer). Ports are used through roles: the console
+// (task::serial), the log sink (log!), the debugger link and the test channel (serial_print!).
+// At boot COM1 is console and test channel, the others are unassigned (see assign)
+
 use uart_16550::SerialPort;
 use spin::Mutex;
 use lazy_static::lazy_static;
+use core::convert::TryFrom;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::interrupts::{self as irq, IrqReturn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort
+{
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort
+{
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    // first I/O port of the UART
+    pub fn base(self) -> u16
+    {
+        match self
+        {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    // COM1 and COM3 share IRQ 4, COM2 and COM4 IRQ 3
+    pub fn irq(self) -> u8
+    {
+        match self
+        {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    fn index(self) -> usize
+    {
+        self as usize
+    }
+
+    fn bit(self) -> u8
+    {
+        1 << self.index()
+    }
+}
+
+impl fmt::Display for ComPort
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "COM{}", self.index() + 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity
+{
+    None,
+    Odd,
+    Even,
+    Mark,   // parity bit always 1
+    Space,  // parity bit always 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits
+{
+    One,
+    Two,    // 1.5 with 5 data bits
+}
+
+// Line settings of a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig
+{
+    pub baud: u32,          // a divisor of 115200
+    pub data_bits: u8,      // 5..=8
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl LineConfig
+{
+    // 38400 baud 8N1, what uart_16550 sets up for COM1
+    pub const DEFAULT: LineConfig = LineConfig { baud: 38_400, data_bits: 8, parity: Parity::None, stop_bits: StopBits::One };
+
+    // divisor latch value: the UART clock is 16 * 115200 Hz
+    fn divisor(&self) -> Result<u16, SerialError>
+    {
+        if self.baud == 0 || MAX_BAUD % self.baud != 0
+        {
+            return Err(SerialError::InvalidBaud(self.baud));
+        }
+        u16::try_from(MAX_BAUD / self.baud).map_err(|_| SerialError::InvalidBaud(self.baud))
+    }
+
+    // line control register without DLAB
+    fn line_control(&self) -> Result<u8, SerialError>
+    {
+        if !(5..=8).contains(&self.data_bits)
+        {
+            return Err(SerialError::InvalidDataBits(self.data_bits));
+        }
+        let stop_bits = match self.stop_bits
+        {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity
+        {
+            Parity::None => 0,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+            Parity::Mark => 0b101 << 3,
+            Parity::Space => 0b111 << 3,
+        };
+        Ok((self.data_bits - 5) | stop_bits | parity)
+    }
+}
+
+// e.g. "38400 8N1"
+impl fmt::Display for LineConfig
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let parity = match self.parity
+        {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+            Parity::Mark => 'M',
+            Parity::Space => 'S',
+        };
+        let stop_bits = match self.stop_bits
+        {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {}{}{}", self.baud, self.data_bits, parity, stop_bits)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError
+{
+    NotPresent(ComPort),    // init did not find the UART
+    InvalidBaud(u32),       // not a divisor of 115200
+    InvalidDataBits(u8),    // not 5..=8
+}
+
+impl fmt::Display for SerialError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            SerialError::NotPresent(port) => write!(f, "{} not present", port),
+            SerialError::InvalidBaud(baud) => write!(f, "unsupported baud rate {}", baud),
+            SerialError::InvalidDataBits(bits) => write!(f, "unsupported number of data bits {}", bits),
+        }
+    }
+}
+
+// What a port is used for. A role has at most one port, a port can have several roles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role
+{
+    Console,    // interactive input and output, see task::serial
+    Log,        // copy of the kernel log (log!)
+    Debugger,   // link to a remote debugger
+    Test,       // serial_print!: test results for the host
+}
+
+impl Role
+{
+    pub const ALL: [Role; 4] = [Role::Console, Role::Log, Role::Debugger, Role::Test];
+}
+
+// UART registers, offsets from the base port
+const DATA: u16 = 0;                // read: receive buffer, write: transmit holding register
+const INTERRUPT_ENABLE: u16 = 1;
+const DIVISOR_LOW: u16 = 0;         // while LCR_DLAB is set
+const DIVISOR_HIGH: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+const MODEM_STATUS: u16 = 6;
+const SCRATCH: u16 = 7;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;    // interrupt when the transmit holding register is empty
+const IER_MODEM_STATUS: u8 = 1 << 3; // interrupt when CTS (or another modem line) changes
+const FCR_ENABLE_CLEAR_14: u8 = 0xc7; // FIFOs on and cleared, receive interrupt at 14 bytes
+const LCR_DLAB: u8 = 1 << 7;        // data and interrupt enable registers become the divisor latch
+const MCR_DTR_RTS: u8 = 0b11;
+const MCR_OUT2: u8 = 1 << 3;        // connects the UART interrupt to the IRQ line on PCs
+const MCR_LOOPBACK: u8 = 1 << 4;    // transmitted bytes come back as received ones
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_TX_EMPTY: u8 = 1 << 5;    // transmit holding register (FIFO) empty
+const LSR_IDLE: u8 = 1 << 6;        // nothing left to shift out either
+const MSR_CTS: u8 = 1 << 4;         // clear to send: the other side takes data
+
+// baud rate with divisor 1
+const MAX_BAUD: u32 = 115_200;
+
+// bytes queued by serial_print! for the THR-empty interrupt. Full: the printing task waits
+const TX_BUFFER_SIZE: usize = 4096;
+// bytes written per THR-empty interrupt (size of the 16550 transmit FIFO)
+const TX_FIFO_SIZE: usize = 16;
+// line status polls before the blocking path sends anyway (stuck UART or CTS never coming)
+const TX_SPIN_LIMIT: u32 = 100_000;
 
 // use lazy static and spinlock to create a static writer instance
+// COM1 is set up through it, printing goes through the transmit ring (see _print)
 lazy_static! 
 {
     pub static ref SERIAL1: Mutex<SerialPort> = 
     {
         // SerialPort::new uses address of first I/O port of UART as argument
         // It calculates address of all needed ports
-        let mut serial_port = unsafe {SerialPort::new(0x3F8)};
+        let mut serial_port = unsafe {SerialPort::new(ComPort::Com1.base())};
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
-#[doc(hidden)]
-pub fn _print(args: ::core::fmt::Arguments) 
+// Transmit ring between serial_print! and the THR-empty interrupt
+struct TxRing
 {
-    use core::fmt::Write;
-    use x86_64::instructions::interrupts;
+    bytes: [u8; TX_BUFFER_SIZE],
+    head: usize,    // oldest byte
+    len: usize,
+}
+
+impl TxRing
+{
+    const fn new() -> Self
+    {
+        TxRing { bytes: [0; TX_BUFFER_SIZE], head: 0, len: 0 }
+    }
+
+    // queues as much of `bytes` as fits, returns how much that was
+    fn push(&mut self, bytes: &[u8]) -> usize
+    {
+        let count = bytes.len().min(TX_BUFFER_SIZE - self.len);
+        for &byte in &bytes[..count]
+        {
+            self.bytes[(self.head + self.len) % TX_BUFFER_SIZE] = byte;
+            self.len += 1;
+        }
+        count
+    }
+
+    fn pop(&mut self) -> Option<u8>
+    {
+        if self.len == 0
+        {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % TX_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+struct PortState
+{
+    ring: TxRing,
+    config: Option<LineConfig>,     // None: not set up by init / configure
+}
+
+impl PortState
+{
+    const fn new() -> Self
+    {
+        PortState { ring: TxRing::new(), config: None }
+    }
+}
+
+// per port, indexed by ComPort::index. Locked with interrupts disabled, also by the interrupt handlers.
+// The lock also serializes changes of the port's registers
+static PORTS: [Mutex<PortState>; 4] = [
+    Mutex::new(PortState::new()), Mutex::new(PortState::new()),
+    Mutex::new(PortState::new()), Mutex::new(PortState::new()),
+];
+
+// bit per port (ComPort::bit): found by init
+static PRESENT: AtomicU8 = AtomicU8::new(0);
+// bit per port: printing queues into the ring (init_tx_buffer). Otherwise it waits for the UART byte by byte
+static TX_BUFFERED: AtomicU8 = AtomicU8::new(0);
+// bit per port: hold transmission while CTS is low (set_flow_control)
+static FLOW_CONTROL: AtomicU8 = AtomicU8::new(0);
+// enter_panic_mode: no more locks and interrupts, every byte is sent right away
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// port of each role (indexed by Role), NO_PORT if unassigned
+const NO_PORT: u8 = u8::MAX;
+static ROLES: [AtomicU8; 4] = [AtomicU8::new(0), AtomicU8::new(NO_PORT), AtomicU8::new(NO_PORT), AtomicU8::new(0)];
+
+struct TxCounters
+{
+    queued: AtomicU64,
+    sent: AtomicU64,
+    full: AtomicU64,
+    blocking: AtomicU64,
+}
+
+impl TxCounters
+{
+    const fn new() -> Self
+    {
+        TxCounters { queued: AtomicU64::new(0), sent: AtomicU64::new(0), full: AtomicU64::new(0), blocking: AtomicU64::new(0) }
+    }
+}
+
+static TX_COUNTERS: [TxCounters; 4] = [TxCounters::new(), TxCounters::new(), TxCounters::new(), TxCounters::new()];
+
+// Counters of the transmit path of a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxStats
+{
+    pub queued: u64,    // bytes put into the ring
+    pub sent: u64,      // bytes the interrupt handler took out of the ring
+    pub full: u64,      // times a printing task found the ring full and had to wait
+    pub blocking: u64,  // bytes sent by polling the UART (before init_tx_buffer, flush, full ring with interrupts off, panic)
+}
+
+impl fmt::Display for TxStats
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "queued {}, sent {}, full {}, blocking {}", self.queued, self.sent, self.full, self.blocking)
+    }
+}
+
+pub fn tx_stats(port: ComPort) -> TxStats
+{
+    let counters = &TX_COUNTERS[port.index()];
+    TxStats
+    {
+        queued: counters.queued.load(Ordering::Relaxed),
+        sent: counters.sent.load(Ordering::Relaxed),
+        full: counters.full.load(Ordering::Relaxed),
+        blocking: counters.blocking.load(Ordering::Relaxed),
+    }
+}
+
+fn read_register(port: ComPort, offset: u16) -> u8
+{
+    unsafe { Port::<u8>::new(port.base() + offset).read() }
+}
+
+fn write_register(port: ComPort, offset: u16, value: u8)
+{
+    unsafe { Port::<u8>::new(port.base() + offset).write(value) }
+}
+
+// COM1 is set up on first use, even before init
+fn set_up(port: ComPort)
+{
+    if port == ComPort::Com1
+    {
+        lazy_static::initialize(&SERIAL1);
+    }
+}
+
+// changes a control register of `port` with its state locked
+fn update_register(port: ComPort, offset: u16, f: impl FnOnce(u8) -> u8)
+{
+    set_up(port);
+    interrupts::without_interrupts(||
+    {
+        let _state = PORTS[port.index()].lock();
+        write_register(port, offset, f(read_register(port, offset)));
+    });
+}
+
+// a UART keeps what is written to its scratch register. Without one the reads return 0xff
+fn detect(port: ComPort) -> bool
+{
+    let saved = read_register(port, SCRATCH);
+    let found = [0x55, 0xaa].iter().all(|&pattern|
+    {
+        write_register(port, SCRATCH, pattern);
+        read_register(port, SCRATCH) == pattern
+    });
+    write_register(port, SCRATCH, saved);
+    found && read_register(port, LINE_STATUS) != 0xff
+}
+
+// Detects COM1..COM4 and sets up the ones found with LineConfig::DEFAULT. No heap needed
+pub fn init()
+{
+    for port in ComPort::ALL
+    {
+        if detect(port)
+        {
+            PRESENT.fetch_or(port.bit(), Ordering::Relaxed);
+        }
+    }
+    for port in ComPort::ALL.iter().copied().filter(|&port| is_present(port))
+    {
+        match port
+        {
+            // already set up by SERIAL1. uart_16550 leaves the receive interrupt on: a byte nobody
+            // reads would keep the IRQ line up and swallow the edges of THR-empty interrupts.
+            // only set_receive_interrupt turns it on
+            ComPort::Com1 =>
+            {
+                set_up(port);
+                update_register(port, INTERRUPT_ENABLE, |ier| ier & !IER_RX_AVAILABLE);
+                interrupts::without_interrupts(|| PORTS[port.index()].lock().config = Some(LineConfig::DEFAULT));
+            }
+            _ => configure(port, LineConfig::DEFAULT).expect("default line settings are valid"),
+        }
+    }
+}
+
+// whether init found `port`
+pub fn is_present(port: ComPort) -> bool
+{
+    PRESENT.load(Ordering::Relaxed) & port.bit() != 0
+}
 
-    interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+// Sets baud rate, data bits, parity and stop bits of `port`. Output queued so far goes out with
+// the old settings first
+pub fn configure(port: ComPort, config: LineConfig) -> Result<(), SerialError>
+{
+    if !is_present(port)
+    {
+        return Err(SerialError::NotPresent(port));
+    }
+    let divisor = config.divisor()?;
+    let line_control = config.line_control()?;
+    set_up(port);
+    interrupts::without_interrupts(||
+    {
+        let mut state = PORTS[port.index()].lock();
+        drain_blocking(port, &mut state.ring);
+        wait_idle(port);
+        let interrupt_enable = read_register(port, INTERRUPT_ENABLE);
+        write_register(port, INTERRUPT_ENABLE, 0);
+        write_register(port, LINE_CONTROL, LCR_DLAB);
+        write_register(port, DIVISOR_LOW, divisor as u8);
+        write_register(port, DIVISOR_HIGH, (divisor >> 8) as u8);
+        write_register(port, LINE_CONTROL, line_control);
+        write_register(port, FIFO_CONTROL, FCR_ENABLE_CLEAR_14);
+        write_register(port, MODEM_CONTROL, read_register(port, MODEM_CONTROL) | MCR_DTR_RTS | MCR_OUT2);
+        write_register(port, INTERRUPT_ENABLE, interrupt_enable);
+        state.config = Some(config);
     });
+    Ok(())
+}
+
+// current line settings, None if the port is not set up
+pub fn config(port: ComPort) -> Option<LineConfig>
+{
+    interrupts::without_interrupts(|| PORTS[port.index()].lock().config)
+}
+
+// Gives `role` to `port`, None takes it away
+pub fn assign(role: Role, port: Option<ComPort>) -> Result<(), SerialError>
+{
+    let value = match port
+    {
+        Some(port) if !is_present(port) => return Err(SerialError::NotPresent(port)),
+        Some(port) => port.index() as u8,
+        None => NO_PORT,
+    };
+    ROLES[role as usize].store(value, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn port_for(role: Role) -> Option<ComPort>
+{
+    ComPort::ALL.get(usize::from(ROLES[role as usize].load(Ordering::Relaxed))).copied()
+}
+
+// next byte received by `port`, None if nothing is waiting. Does not block, usable in interrupt handlers
+pub fn try_receive(port: ComPort) -> Option<u8>
+{
+    if read_register(port, LINE_STATUS) & LSR_DATA_READY == 0
+    {
+        return None;
+    }
+    Some(read_register(port, DATA))
+}
+
+// `port` raises its IRQ for every received byte (see task::serial::SerialStream)
+pub fn set_receive_interrupt(port: ComPort, enabled: bool)
+{
+    update_register(port, MODEM_CONTROL, |mcr| mcr | MCR_OUT2);
+    update_register(port, INTERRUPT_ENABLE, |ier| if enabled { ier | IER_RX_AVAILABLE } else { ier & !IER_RX_AVAILABLE });
+}
+
+// Loopback mode: everything printed to `port` is received by it instead of going out. For tests
+pub fn set_loopback(port: ComPort, enabled: bool)
+{
+    update_register(port, MODEM_CONTROL, |mcr| if enabled { mcr | MCR_LOOPBACK } else { mcr & !MCR_LOOPBACK });
+}
+
+// Sends output from a ring buffer in the THR-empty interrupt of each port found by init, instead
+// of waiting for the UART with interrupts disabled. Needs the heap. Ports already buffered stay so
+pub fn init_tx_buffer()
+{
+    for port in ComPort::ALL.iter().copied().filter(|&port| is_present(port))
+    {
+        if TX_BUFFERED.load(Ordering::Relaxed) & port.bit() != 0
+        {
+            continue;
+        }
+        update_register(port, MODEM_CONTROL, |mcr| mcr | MCR_OUT2);
+        // stays installed for good. Shares the line with the other port on it and task::serial
+        irq::register_irq(port.irq(), move || if transmit_from_ring(port) { IrqReturn::Handled } else { IrqReturn::NotMine });
+        TX_BUFFERED.fetch_or(port.bit(), Ordering::Relaxed);
+    }
+}
+
+fn is_buffered(port: ComPort) -> bool
+{
+    TX_BUFFERED.load(Ordering::Relaxed) & port.bit() != 0
+}
+
+// Transmit on `port` only while the other side raises CTS. Bytes wait in the ring meanwhile,
+// the modem status interrupt resumes sending
+pub fn set_flow_control(port: ComPort, enabled: bool)
+{
+    if enabled
+    {
+        FLOW_CONTROL.fetch_or(port.bit(), Ordering::Relaxed);
+    }
+    else
+    {
+        FLOW_CONTROL.fetch_and(!port.bit(), Ordering::Relaxed);
+    }
+    interrupts::without_interrupts(|| start_transmit(port, &PORTS[port.index()].lock().ring));
+}
+
+fn clear_to_send(port: ComPort) -> bool
+{
+    FLOW_CONTROL.load(Ordering::Relaxed) & port.bit() == 0 || read_register(port, MODEM_STATUS) & MSR_CTS != 0
+}
+
+// enables the THR-empty interrupt if there is something to send. An empty THR interrupts right away
+// call with the port's state locked and interrupts disabled
+fn start_transmit(port: ComPort, ring: &TxRing)
+{
+    if ring.len > 0 && is_buffered(port)
+    {
+        write_register(port, INTERRUPT_ENABLE, read_register(port, INTERRUPT_ENABLE) | IER_TX_EMPTY);
+    }
+}
+
+// interrupt handler: refills the transmit FIFO from the ring. False if the interrupt was not for it
+fn transmit_from_ring(port: ComPort) -> bool
+{
+    let mut state = PORTS[port.index()].lock();
+    let ring = &mut state.ring;
+    let ier = read_register(port, INTERRUPT_ENABLE);
+    if ier & (IER_TX_EMPTY | IER_MODEM_STATUS) == 0
+    {
+        return false;
+    }
+    // reading the modem status also acknowledges its interrupt
+    if !clear_to_send(port)
+    {
+        // wait for CTS instead of the (empty) THR, which would interrupt over and over
+        write_register(port, INTERRUPT_ENABLE, ier & !IER_TX_EMPTY | IER_MODEM_STATUS);
+        return true;
+    }
+    if read_register(port, LINE_STATUS) & LSR_TX_EMPTY == 0
+    {
+        // not for us unless CTS came back: then wait for the THR again
+        write_register(port, INTERRUPT_ENABLE, ier & !IER_MODEM_STATUS | IER_TX_EMPTY);
+        return ier & IER_MODEM_STATUS != 0;
+    }
+    let mut sent = 0;
+    while sent < TX_FIFO_SIZE
+    {
+        match ring.pop()
+        {
+            Some(byte) => write_register(port, DATA, byte),
+            None => break,
+        }
+        sent += 1;
+    }
+    TX_COUNTERS[port.index()].sent.fetch_add(sent as u64, Ordering::Relaxed);
+    let ier = ier & !IER_MODEM_STATUS;
+    write_register(port, INTERRUPT_ENABLE, if ring.len > 0 { ier | IER_TX_EMPTY } else { ier & !IER_TX_EMPTY });
+    true
+}
+
+// waits until the UART takes a byte (and CTS, with flow control), then sends it. Gives up waiting
+// after TX_SPIN_LIMIT polls, output must not hang a panic
+fn send_blocking(port: ComPort, byte: u8)
+{
+    for _ in 0..TX_SPIN_LIMIT
+    {
+        if read_register(port, LINE_STATUS) & LSR_TX_EMPTY != 0 && clear_to_send(port)
+        {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    write_register(port, DATA, byte);
+    TX_COUNTERS[port.index()].blocking.fetch_add(1, Ordering::Relaxed);
+}
+
+// empties the ring by polling. Call with the port's state locked and interrupts disabled
+fn drain_blocking(port: ComPort, ring: &mut TxRing)
+{
+    while let Some(byte) = ring.pop()
+    {
+        send_blocking(port, byte);
+    }
+}
+
+// waits (a bounded time) until the UART has shifted out everything
+fn wait_idle(port: ComPort)
+{
+    for _ in 0..TX_SPIN_LIMIT
+    {
+        if read_register(port, LINE_STATUS) & LSR_IDLE != 0
+        {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// puts `bytes` into the ring of `port`. A full ring is drained by the interrupt while the caller spins,
+// or by polling if interrupts are disabled (or the interrupt does not come)
+fn queue(port: ComPort, mut bytes: &[u8])
+{
+    let counters = &TX_COUNTERS[port.index()];
+    let mut spins = 0;
+    while !bytes.is_empty()
+    {
+        let queued = interrupts::without_interrupts(||
+        {
+            let mut state = PORTS[port.index()].lock();
+            let queued = state.ring.push(bytes);
+            start_transmit(port, &state.ring);
+            queued
+        });
+        counters.queued.fetch_add(queued as u64, Ordering::Relaxed);
+        bytes = &bytes[queued..];
+        if bytes.is_empty()
+        {
+            break;
+        }
+        if spins == 0
+        {
+            counters.full.fetch_add(1, Ordering::Relaxed);
+        }
+        spins += 1;
+        if interrupts::are_enabled() && spins < TX_SPIN_LIMIT
+        {
+            core::hint::spin_loop();
+        }
+        else
+        {
+            interrupts::without_interrupts(|| drain_blocking(port, &mut PORTS[port.index()].lock().ring));
+            spins = 0;
+        }
+    }
+}
+
+// Sends everything queued on all ports and waits until the UARTs are done. E.g. before exit_qemu
+pub fn flush()
+{
+    if PANICKING.load(Ordering::Relaxed)
+    {
+        return;
+    }
+    for port in ComPort::ALL.iter().copied().filter(|&port| is_buffered(port))
+    {
+        interrupts::without_interrupts(|| drain_blocking(port, &mut PORTS[port.index()].lock().ring));
+        wait_idle(port);
+    }
+}
+
+// For panic handlers: sends what is queued, then every byte right away without taking locks
+// (the panicking code may hold them) or relying on interrupts
+pub fn enter_panic_mode()
+{
+    // initializes COM1 without locking it
+    set_up(ComPort::Com1);
+    if PANICKING.swap(true, Ordering::Relaxed)
+    {
+        return;
+    }
+    interrupts::without_interrupts(||
+    {
+        for port in ComPort::ALL
+        {
+            // if the ring is locked, its contents are lost
+            if let Some(mut state) = PORTS[port.index()].try_lock()
+            {
+                drain_blocking(port, &mut state.ring);
+            }
+        }
+    });
+}
+
+// Writes to a port: queues into its ring, polls the UART before init_tx_buffer and after a panic
+struct PortWriter(ComPort);
+
+impl fmt::Write for PortWriter
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        let port = self.0;
+        if PANICKING.load(Ordering::Relaxed)
+        {
+            s.bytes().for_each(|byte| send_blocking(port, byte));
+        }
+        else if is_buffered(port)
+        {
+            queue(port, s.as_bytes());
+        }
+        else
+        {
+            set_up(port);
+            interrupts::without_interrupts(||
+            {
+                // the lock keeps bytes of concurrent writers apart
+                let _state = PORTS[port.index()].lock();
+                s.bytes().for_each(|byte| send_blocking(port, byte));
+            });
+        }
+        Ok(())
+    }
+}
+
+// prints to `port`
+pub fn print_to_port(port: ComPort, args: fmt::Arguments)
+{
+    use core::fmt::Write;
+
+    PortWriter(port).write_fmt(args).expect("Printing to serial failed");
+}
+
+// prints to the port of `role`. Nothing if the role has no port
+pub fn print_to(role: Role, args: fmt::Arguments)
+{
+    if let Some(port) = port_for(role)
+    {
+        print_to_port(port, args);
+    }
+}
+
+// serial_print! writes to the test channel
+#[doc(hidden)]
+pub fn _print(args: ::core::fmt::Arguments) 
+{
+    print_to(Role::Test, args);
 }
 
 // Prints to the host through the serial interface.
@@ -44,4 +795,4 @@ macro_rules! serial_println
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
@@ -1,24 +1,181 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc};
+use super::{Task, TaskId, JoinHandle, Priority};
+use core::future::Future;
+use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc, vec::Vec};
 use core::task::Waker;
-use crossbeam_queue::ArrayQueue;
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use conquer_once::spin::OnceCell;
 use core::task::{Context, Poll};
 use alloc::task::Wake;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::fmt;
+use crate::time::timer;
+use super::sync::Notify;
+
+// Capacity of the ready queue (per priority). Not a limit on the number of tasks:
+// each task is queued at most once, and when the queue is full the executor falls back to scanning all tasks
+const READY_QUEUE_CAPACITY: usize = 100;
+
+// How often a task may be polled within one scheduling round (one run_ready_tasks call)
+// a task that keeps waking itself is moved to the next round once it used up its budget
+const POLL_BUDGET: u32 = 4;
+
+// After a waiting priority class was passed over this many times in favour of higher classes,
+// it gets the next poll. Keeps background work from starving under constant interactive load
+const STARVATION_LIMIT: u32 = 16;
 
 pub struct Executor 
 {
     // map is indexed by TaskID to allow efficient continuation of specific task
     tasks: BTreeMap<TaskId, Task>,          // contains actual Task instances
-    task_queue: Arc<ArrayQueue<TaskId>>,    // array queue of taskIDs wrapped into Arc type (implements ref counting)
+    task_queue: Arc<ReadyQueue>,            // queue of woken taskIDs wrapped into Arc type (implements ref counting)
                                             // ref counting makes it possible to share ownership among multiple owners
                                             // allocates value on heap and counting number of active ref to it
-    waker_cache: BTreeMap<TaskId, Waker>,   // caches Waker of a task after its creation
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,  // caches TaskWaker of a task after its creation
                                             // improves performance by reusing same waker multiple wake ups of same task instead of creating new waker each time
                                             // ensures ref counted wakers are not deallocated inside interrupt handlers which may lead to deadlocks
+                                            // also holds the per task `queued` flag used to deduplicate wakeups
+    spawn_queue: Arc<SegQueue<Task>>,       // tasks handed over by Spawner handles. moved into tasks map by run_ready_tasks
+    run_queues: [VecDeque<TaskId>; Priority::COUNT],    // executor side of task_queue, one FIFO per priority
+                                            // task_queue is drained into these since ArrayQueue is fixed size and cannot be reordered
+    next_round: Vec<TaskId>,                // tasks that used up their POLL_BUDGET. requeued when next round starts
+    round_polls: BTreeMap<TaskId, u32>,     // polls per task in the current round
+    skipped: [u32; Priority::COUNT],        // how often each waiting class was passed over (starvation avoidance)
+    shared_stats: Arc<SharedStats>,         // stats requested through a StatsHandle, built at the end of a round
 }
-// Arc<ArrayQueue> because it is shared with executor and wakers
+// Arc<ReadyQueue> because it is shared with executor and wakers
 // waker pushes ID of woken task to queue -> executor sits on receiveing end of queue -> retrieves woken tasks by their ID from tasks map and runs them
 
+// Queue of woken tasks shared between executor and wakers, one ArrayQueue per priority
+// wakers run in interrupt handlers, so pushing must never allocate (ArrayQueue) and never panic.
+// if the queue is full the push is dropped and `overflowed` is set instead.
+// the task keeps its `queued` flag, so executor finds it again by scanning all tasks
+struct ReadyQueue
+{
+    queues: [ArrayQueue<TaskId>; Priority::COUNT],
+    overflowed: AtomicBool,
+    wakeups: AtomicU64,         // wake calls, including deduplicated ones
+    deduplicated: AtomicU64,    // wake calls for a task that was already queued
+    overflows: AtomicU64,       // pushes that did not fit into queue
+    high_water: AtomicUsize,    // largest queue length seen
+}
+
+impl ReadyQueue
+{
+    fn new(capacity: usize) -> Self
+    {
+        ReadyQueue
+        {
+            queues: [ArrayQueue::new(capacity), ArrayQueue::new(capacity), ArrayQueue::new(capacity)],
+            overflowed: AtomicBool::new(false),
+            wakeups: AtomicU64::new(0),
+            deduplicated: AtomicU64::new(0),
+            overflows: AtomicU64::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, task_id: TaskId, priority: Priority)
+    {
+        let queue = &self.queues[priority.index()];
+        match queue.push(task_id)
+        {
+            Ok(()) =>
+            {
+                self.high_water.fetch_max(queue.len(), Ordering::Relaxed);
+            }
+            Err(_) =>
+            {
+                self.overflows.fetch_add(1, Ordering::Relaxed);
+                self.overflowed.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    fn len(&self) -> usize
+    {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    fn is_empty(&self) -> bool
+    {
+        self.queues.iter().all(|queue| queue.is_empty()) && !self.overflowed.load(Ordering::Acquire)
+    }
+}
+
+// Snapshot of ready queue pressure, returned by Executor::queue_stats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStats
+{
+    pub tasks: usize,           // tasks currently owned by executor
+    pub queued: usize,          // task ids currently in ready queue
+    pub capacity: usize,        // ready queue capacity per priority
+    pub high_water: usize,      // largest queue length seen
+    pub wakeups: u64,
+    pub deduplicated: u64,      // wakeups ignored because task was already queued
+    pub overflows: u64,         // wakeups that found queue full and were recovered by a full scan
+}
+
+// Scheduling state of a task at the time of an Executor::stats snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState
+{
+    Ready,      // woken, waiting for its next poll
+    Waiting,    // returned Pending and nobody woke it yet
+}
+
+// Per task counters, one entry of ExecutorStats
+#[derive(Debug, Clone)]
+pub struct TaskStats
+{
+    pub id: TaskId,
+    pub name: &'static str,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub polls: u64,
+    pub poll_cycles: u64,   // TSC cycles spent in poll
+    pub wakeups: u64,
+}
+
+// Snapshot returned by Executor::stats. Display prints a ps style table
+#[derive(Debug, Clone)]
+pub struct ExecutorStats
+{
+    pub queue: QueueStats,
+    pub tasks: Vec<TaskStats>,
+}
+
+impl fmt::Display for ExecutorStats
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(f, "{:>5} {:<16} {:<11} {:<7} {:>8} {:>8} {:>14}",
+            "ID", "NAME", "PRIORITY", "STATE", "POLLS", "WAKEUPS", "CYCLES")?;
+        for task in &self.tasks
+        {
+            writeln!(f, "{:>5} {:<16} {:<11} {:<7} {:>8} {:>8} {:>14}",
+                task.id, task.name, format_args!("{:?}", task.priority), format_args!("{:?}", task.state),
+                task.polls, task.wakeups, task.poll_cycles)?;
+        }
+        write!(f, "queue: {} queued, high water {}/{}, {} wakeups ({} deduplicated), {} overflows",
+            self.queue.queued, self.queue.high_water, self.queue.capacity,
+            self.queue.wakeups, self.queue.deduplicated, self.queue.overflows)
+    }
+}
+
+// id of task currently being polled (u64::MAX = none)
+// atomic so interrupt handlers (and panic handler) can tell which task was running
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(u64::MAX);
+
+// task that the executor is polling right now, None when outside of a poll
+pub fn current_task() -> Option<TaskId>
+{
+    match CURRENT_TASK.load(Ordering::Relaxed)
+    {
+        u64::MAX => None,
+        id => Some(TaskId(id)),
+    }
+}
+
 impl Executor 
 {
     pub fn new() -> Self 
@@ -26,8 +183,28 @@ impl Executor
         Executor 
         {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(ReadyQueue::new(READY_QUEUE_CAPACITY)),
             waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(SegQueue::new()),
+            run_queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            next_round: Vec::new(),
+            round_polls: BTreeMap::new(),
+            skipped: [0; Priority::COUNT],
+            shared_stats: Arc::new(SharedStats
+            {
+                requested: AtomicBool::new(false),
+                ready: Notify::new(),
+                last: spin::Mutex::new(None),
+            }),
+        }
+    }
+
+    // returns handle that can add tasks to this executor while it is running
+    pub fn spawner(&self) -> Spawner
+    {
+        Spawner
+        {
+            spawn_queue: self.spawn_queue.clone(),
         }
     }
 
@@ -35,77 +212,250 @@ impl Executor
     pub fn spawn(&mut self, task: Task)
     {
         let task_id = task.id;
+        let priority = task.priority;
 
         // if task with same id is in map,  
         if self.tasks.insert(task.id, task).is_some() 
         {
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        let waker = Arc::new(TaskWaker::new(task_id, priority, self.task_queue.clone()));
+        waker.wake_task();
+        self.waker_cache.insert(task_id, waker);
+    }
+
+    // current ready queue pressure
+    pub fn queue_stats(&self) -> QueueStats
+    {
+        let ready = &self.task_queue;
+        QueueStats
+        {
+            tasks: self.tasks.len(),
+            queued: ready.len() + self.run_queues.iter().map(|queue| queue.len()).sum::<usize>(),
+            capacity: READY_QUEUE_CAPACITY,
+            high_water: ready.high_water.load(Ordering::Relaxed),
+            wakeups: ready.wakeups.load(Ordering::Relaxed),
+            deduplicated: ready.deduplicated.load(Ordering::Relaxed),
+            overflows: ready.overflows.load(Ordering::Relaxed),
+        }
+    }
+
+    // returns handle that reads the stats of this executor while it is running
+    pub fn stats_handle(&self) -> StatsHandle
+    {
+        StatsHandle
+        {
+            shared: self.shared_stats.clone(),
+        }
+    }
+
+    // snapshot of all tasks and queue counters
+    pub fn stats(&self) -> ExecutorStats
+    {
+        let tasks = self.tasks
+            .values()
+            .map(|task|
+            {
+                let waker = self.waker_cache.get(&task.id);
+                let queued = waker.map_or(false, |waker| waker.queued.load(Ordering::Relaxed));
+                TaskStats
+                {
+                    id: task.id,
+                    name: task.name,
+                    priority: task.priority,
+                    state: if queued { TaskState::Ready } else { TaskState::Waiting },
+                    polls: task.polls,
+                    poll_cycles: task.poll_cycles,
+                    wakeups: waker.map_or(0, |waker| waker.wakeups.load(Ordering::Relaxed)),
+                }
+            })
+            .collect();
+        ExecutorStats { queue: self.queue_stats(), tasks }
+    }
+
+    // answers a StatsHandle request at the end of a round. Costs nothing when nobody asked
+    fn publish_stats(&self)
+    {
+        let shared = &self.shared_stats;
+        if !shared.requested.swap(false, Ordering::AcqRel)
+        {
+            return;
+        }
+        // built with interrupts enabled, only the swap happens under the lock
+        let stats = self.stats();
+        let previous = x86_64::instructions::interrupts::without_interrupts(|| shared.last.lock().replace(stats));
+        drop(previous);
+        shared.ready.notify_waiters();
     }
 
     // to execute all tasks in task_queue
     // loops over all tasks in task_queue, create waker for each task, poll them.
     // instead of adding pending task back to queue, TaskWaker take care of adding woken tasks back to queue
-    fn run_ready_tasks(&mut self) 
+    // one call is one scheduling round: higher priorities first, every task polled at most POLL_BUDGET times
+    // public so tests can drive the executor without entering run (which never returns)
+    pub fn run_ready_tasks(&mut self) 
     {
-        // destructure `self` to avoid borrow checker errors
-        let Self 
+        // timers that expired since the last round (time::timer::sleep and friends)
+        timer::wake_expired();
+
+        // take over tasks spawned through Spawner since the last round
+        while let Some(task) = self.spawn_queue.pop()
         {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
+            self.spawn(task);
+        }
 
-        // for each popped task id, retrieve mutable ref to corresponding task from tasks map
-        while let Some(task_id) = task_queue.pop() 
+        // tasks that ran out of budget last round are still marked as queued
+        self.round_polls.clear();
+        for task_id in core::mem::take(&mut self.next_round)
         {
-            let task = match tasks.get_mut(&task_id) 
+            if let Some(waker) = self.waker_cache.get(&task_id)
             {
-                Some(task) => task,
-                None => continue, // task no longer exists
+                self.run_queues[waker.priority.index()].push_back(task_id);
+            }
+        }
+
+        loop 
+        {
+            self.collect_woken();
+            let task_id = match self.pick_next()
+            {
+                Some(task_id) => task_id,
+                None => break,
             };
 
-            // to avoid performance overhead of creating waker on each poll, 
-            // use waker_cache map to store waker for each task after it has been created
-            // or_insert_with to create new waker if it doesnt exists yet then get mutable reference to it
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            
-            match task.poll(&mut context) 
+            let polls = self.round_polls.entry(task_id).or_insert(0);
+            if *polls >= POLL_BUDGET
+            {
+                // keep `queued` set so wakeups stay deduplicated until next round polls it
+                self.next_round.push(task_id);
+                continue;
+            }
+            *polls += 1;
+
+            Self::poll_task(&mut self.tasks, &mut self.waker_cache, task_id);
+        }
+        self.publish_stats();
+    }
+
+    // moves woken task ids from the interrupt safe task_queue into the executor's run_queues
+    fn collect_woken(&mut self)
+    {
+        let Self { task_queue, run_queues, waker_cache, .. } = self;
+
+        // for each popped task id, put it in the run queue of its priority
+        for (queue, run_queue) in task_queue.queues.iter().zip(run_queues.iter_mut())
+        {
+            while let Some(task_id) = queue.pop()
+            {
+                run_queue.push_back(task_id);
+            }
+        }
+
+        // some wakeups did not fit into queue. Their tasks still have `queued` set -> find them by scanning
+        // tasks already in a run queue are found too. polling them twice is harmless
+        if task_queue.overflowed.swap(false, Ordering::AcqRel)
+        {
+            for (&task_id, waker) in waker_cache.iter()
             {
-                // task is finished when it returns Ready
-                Poll::Ready(()) => 
+                if waker.queued.load(Ordering::Acquire)
                 {
-                    // task done -> remove it and its cached waker
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
+                    run_queues[waker.priority.index()].push_back(task_id);
                 }
-                Poll::Pending => {}
             }
         }
     }
+
+    // highest non empty priority, unless a lower waiting class was skipped STARVATION_LIMIT times
+    // no allocation: runs once per poll
+    fn pick_next(&mut self) -> Option<TaskId>
+    {
+        let mut waiting = [false; Priority::COUNT];
+        for (waiting, queue) in waiting.iter_mut().zip(self.run_queues.iter())
+        {
+            *waiting = !queue.is_empty();
+        }
+        let chosen = (0..Priority::COUNT)
+            .rev()
+            .find(|&index| waiting[index] && self.skipped[index] >= STARVATION_LIMIT)
+            .or_else(|| (0..Priority::COUNT).find(|&index| waiting[index]))?;
+
+        for index in (0..Priority::COUNT).filter(|&index| waiting[index])
+        {
+            if index == chosen
+            {
+                self.skipped[index] = 0;
+            }
+            else
+            {
+                self.skipped[index] += 1;
+            }
+        }
+        self.run_queues[chosen].pop_front()
+    }
+
+    // polls a single task once
+    fn poll_task(tasks: &mut BTreeMap<TaskId, Task>, waker_cache: &mut BTreeMap<TaskId, Arc<TaskWaker>>, task_id: TaskId)
+    {
+        let (task, task_waker) = match (tasks.get_mut(&task_id), waker_cache.get(&task_id))
+        {
+            (Some(task), Some(task_waker)) => (task, task_waker),
+            _ => return, // task no longer exists
+        };
+
+        // clear flag before polling. a wakeup during poll must queue the task again
+        task_waker.queued.store(false, Ordering::Release);
+
+        // waker only clones the cached Arc. executor keeps the last reference,
+        // so it is never deallocated inside an interrupt handler
+        let waker = Waker::from(task_waker.clone());
+        let mut context = Context::from_waker(&waker);
+
+        CURRENT_TASK.store(task_id.0, Ordering::Relaxed);
+        let result = task.poll(&mut context);
+        CURRENT_TASK.store(u64::MAX, Ordering::Relaxed);
+
+        match result 
+        {
+            // task is finished when it returns Ready
+            Poll::Ready(()) => 
+            {
+                // task done -> remove it and its cached waker
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
+            }
+            Poll::Pending => {}
+        }
+    }
     pub fn run(&mut self) -> ! 
     {
+        // makes task::spawn work for code running inside tasks of this executor
+        // only first executor to run is registered. run never returns so there is at most one
+        let _ = SPAWNER.try_init_once(|| self.spawner());
+        let _ = STATS.try_init_once(|| self.stats_handle());
+
         loop 
         {
             self.run_ready_tasks();
             self.sleep_if_idle();   // when queue is empty
         }
-    }    
+    }
     fn sleep_if_idle(&self)
     {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         // disable interrupt before checking whether task_queue is empty.
         interrupts::disable();
-        if self.task_queue.is_empty() 
+        // an expired timer is woken by the next run_ready_tasks, no need to wait for an interrupt
+        if self.task_queue.is_empty() && self.spawn_queue.is_empty() && self.next_round.is_empty()
+            && !timer::has_expired()
         {
+            // tickless idle: no periodic tick while nothing is runnable, the next timer deadline
+            // (or a device interrupt) wakes the CPU
+            timer::idle_enter();
             // enables interrupts and put CPU to sleep as a single atomic operation
             enable_and_hlt();
-        } 
+            timer::idle_exit();
+        }
         else 
         {
             // interrupt woke a task after run_ready_tasks returned
@@ -115,26 +465,128 @@ impl Executor
     }
 }
 
+// Cloneable handle to push new tasks to an Executor from anywhere (including other tasks)
+// Executor picks them up at the start of the next run_ready_tasks round
+#[derive(Clone)]
+pub struct Spawner
+{
+    spawn_queue: Arc<SegQueue<Task>>,
+}
+
+impl Spawner
+{
+    // spawns future and returns JoinHandle resolving to its output
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let (task, handle) = Task::joinable(future);
+        self.spawn_task(task);
+        handle
+    }
+
+    // spawns already constructed task (no JoinHandle)
+    pub fn spawn_task(&self, task: Task)
+    {
+        self.spawn_queue.push(task);
+    }
+}
+
+// Spawner of the running executor, used by task::spawn
+static SPAWNER: OnceCell<Spawner> = OnceCell::uninit();
+
+pub(crate) fn global_spawner() -> Option<&'static Spawner>
+{
+    SPAWNER.try_get().ok()
+}
+
+struct SharedStats
+{
+    requested: AtomicBool,                      // a StatsHandle asked, the round's end builds the stats
+    ready: Notify,                              // stats of a request are in `last`
+    last: spin::Mutex<Option<ExecutorStats>>,
+}
+
+// Cloneable handle that asks an Executor for its stats while run() keeps it to itself
+// the executor builds them at the end of the round after a request, so unrequested rounds cost nothing.
+// a task stuck inside poll ends no round and gets no stats: see current_task for that case
+#[derive(Clone)]
+pub struct StatsHandle
+{
+    shared: Arc<SharedStats>,
+}
+
+impl StatsHandle
+{
+    // asks for stats at the end of the current round. Does not allocate, usable from interrupt
+    // handlers (e.g. a debug key). Read them with last afterwards
+    pub fn request(&self)
+    {
+        self.shared.requested.store(true, Ordering::Release);
+    }
+
+    // stats of the latest request the executor answered, None before the first one. Allocates,
+    // not for interrupt handlers
+    pub fn last(&self) -> Option<ExecutorStats>
+    {
+        x86_64::instructions::interrupts::without_interrupts(|| self.shared.last.lock().clone())
+    }
+
+    // requests stats and waits for the end of the round. For tasks of the executor (or others)
+    pub async fn fetch(&self) -> ExecutorStats
+    {
+        let ready = self.shared.ready.notified();
+        self.request();
+        ready.await;
+        self.last().expect("stats published before notify")
+    }
+}
+
+// StatsHandle of the running executor
+static STATS: OnceCell<StatsHandle> = OnceCell::uninit();
+
+// stats handle of the executor in run(). None before run
+pub fn running_stats() -> Option<&'static StatsHandle>
+{
+    STATS.try_get().ok()
+}
+
 struct TaskWaker // since ownership of task_queue is shared, use Arc to implement shared ref counted ownership
 {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,     // selects the ready queue to push to
+    task_queue: Arc<ReadyQueue>,
+    queued: AtomicBool,     // set while task waits in task_queue (or was lost to an overflow)
+    wakeups: AtomicU64,     // wake calls for this task, including deduplicated ones
 }
 impl TaskWaker 
 {
     fn wake_task(&self) 
     {
+        self.task_queue.wakeups.fetch_add(1, Ordering::Relaxed);
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+
+        // already queued -> one entry is enough, the next poll sees all progress
+        if self.queued.swap(true, Ordering::AcqRel)
+        {
+            self.task_queue.deduplicated.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
         // pushing task_id to reference task_queue
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.task_queue.push(self.task_id, self.priority);
     }
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker 
+    // Waker::from (on Arc<TaskWaker>) takes care of constructing RawWakerVTable and RawWaker for TaskWaker
+    fn new(task_id: TaskId, priority: Priority, task_queue: Arc<ReadyQueue>) -> TaskWaker
     {
-        // from method takes care of constructing RawWakerVTable and RawWaker for TaskWaker
-        Waker::from(Arc::new(TaskWaker 
+        TaskWaker 
         {
             task_id,
+            priority,
             task_queue,
-        }))
+            queued: AtomicBool::new(false),
+            wakeups: AtomicU64::new(0),
+        }
     }
 }
 
@@ -149,4 +601,4 @@ impl Wake for TaskWaker
     fn wake_by_ref(self: &Arc<Self>) {
         self.wake_task();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,336 @@
+// Bridge from interrupt handlers to async tasks
+//
+// IrqStream<T>: handler pushes values (e.g. bytes read from a device), a task reads them as a Stream
+// IrqEvent: handler only signals that something happened, a task awaits the signal
+//
+// register(irq, ..) adds the handler to the IRQ line (interrupts::register_irq), so a new driver
+// only has to say how to read its device. Dropping the stream / event removes the handler again.
+// Both sides never allocate or block in interrupt context.
+// Values that do not fit into the queue are dropped and counted instead (see IrqStream::stats)
+
+use crate::interrupts::{self, IrqHandle, IrqReturn};
+use alloc::sync::Arc;
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+struct StreamShared<T>
+{
+    queue: ArrayQueue<T>,
+    waker: AtomicWaker,
+    received: AtomicU64,    // values pushed by the handler, including dropped ones
+    dropped: AtomicU64,     // values lost because the queue was full
+}
+
+// Interrupt side of an IrqStream
+pub struct IrqSender<T>
+{
+    shared: Arc<StreamShared<T>>,
+}
+
+impl<T> IrqSender<T>
+{
+    // queues value and wakes the reading task. Returns false if the queue was full and value was dropped
+    // must not block or allocate: called from interrupt handlers
+    pub fn push(&self, value: T) -> bool
+    {
+        let shared = &self.shared;
+        shared.received.fetch_add(1, Ordering::Relaxed);
+        if shared.queue.push(value).is_err()
+        {
+            shared.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        // wake only after pushing, otherwise the task may find the queue still empty
+        shared.waker.wake();
+        true
+    }
+}
+
+impl<T> Clone for IrqSender<T>
+{
+    fn clone(&self) -> Self
+    {
+        IrqSender { shared: self.shared.clone() }
+    }
+}
+
+// Counters of an IrqStream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqStreamStats
+{
+    pub received: u64,
+    pub dropped: u64,
+    pub queued: usize,
+    pub capacity: usize,
+}
+
+impl fmt::Display for IrqStreamStats
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "received {}, dropped {}, queued {}/{}", self.received, self.dropped, self.queued, self.capacity)
+    }
+}
+
+// Task side: stream of values pushed by an interrupt handler. Never ends
+pub struct IrqStream<T>
+{
+    shared: Arc<StreamShared<T>>,
+    handle: Option<IrqHandle>,  // handler installed by register
+}
+
+impl<T: Send + 'static> IrqStream<T>
+{
+    // Creates a stream holding up to `capacity` unread values, for a handler that is installed elsewhere
+    pub fn new(capacity: usize) -> (IrqSender<T>, IrqStream<T>)
+    {
+        let shared = Arc::new(StreamShared
+        {
+            queue: ArrayQueue::new(capacity),
+            waker: AtomicWaker::new(),
+            received: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        (IrqSender { shared: shared.clone() }, IrqStream { shared, handle: None })
+    }
+
+    // Adds `handler` to IRQ line `irq` and returns the stream it feeds
+    // handler runs in interrupt context, reads the device and pushes through the given sender.
+    // On a shared line it returns IrqReturn::NotMine if its device did not raise the interrupt
+    pub fn register<F>(irq: u8, capacity: usize, handler: F) -> IrqStream<T>
+    where
+        F: Fn(&IrqSender<T>) -> IrqReturn + Send + Sync + 'static,
+    {
+        let (sender, mut stream) = IrqStream::new(capacity);
+        stream.handle = Some(interrupts::register_irq(irq, move || handler(&sender)));
+        stream
+    }
+}
+
+impl<T> IrqStream<T>
+{
+    pub fn stats(&self) -> IrqStreamStats
+    {
+        let shared = &self.shared;
+        IrqStreamStats
+        {
+            received: shared.received.load(Ordering::Relaxed),
+            dropped: shared.dropped.load(Ordering::Relaxed),
+            queued: shared.queue.len(),
+            capacity: shared.queue.capacity(),
+        }
+    }
+
+    // non blocking
+    pub fn try_next(&mut self) -> Option<T>
+    {
+        self.shared.queue.pop()
+    }
+}
+
+impl<T> Drop for IrqStream<T>
+{
+    fn drop(&mut self)
+    {
+        if let Some(handle) = self.handle.take()
+        {
+            interrupts::unregister_irq(handle);
+        }
+    }
+}
+
+impl<T> Stream for IrqStream<T>
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>>
+    {
+        let shared = &self.shared;
+        // fast path
+        if let Some(value) = shared.queue.pop()
+        {
+            return Poll::Ready(Some(value));
+        }
+
+        // register before the second check: a push in between is not lost
+        shared.waker.register(cx.waker());
+        match shared.queue.pop()
+        {
+            Some(value) =>
+            {
+                shared.waker.take();
+                Poll::Ready(Some(value))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+struct EventShared
+{
+    pending: AtomicU64,     // signals not yet consumed by wait
+    waker: AtomicWaker,
+    signals: AtomicU64,     // total
+    coalesced: AtomicU64,   // signals that arrived while an earlier one was still pending
+}
+
+// Interrupt side of an IrqEvent
+#[derive(Clone)]
+pub struct IrqSignal
+{
+    shared: Arc<EventShared>,
+}
+
+impl IrqSignal
+{
+    // marks the event as happened and wakes the waiting task. Usable from interrupt handlers
+    pub fn signal(&self)
+    {
+        let shared = &self.shared;
+        shared.signals.fetch_add(1, Ordering::Relaxed);
+        if shared.pending.fetch_add(1, Ordering::Release) > 0
+        {
+            shared.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+        shared.waker.wake();
+    }
+}
+
+// Counters of an IrqEvent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqEventStats
+{
+    pub signals: u64,
+    pub coalesced: u64,
+}
+
+impl fmt::Display for IrqEventStats
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "signals {}, coalesced {}", self.signals, self.coalesced)
+    }
+}
+
+// Task side: event signaled by an interrupt handler, carries no data
+// signals arriving before the task waits are not lost, but several of them are reported as one wakeup
+pub struct IrqEvent
+{
+    shared: Arc<EventShared>,
+    handle: Option<IrqHandle>,  // handler installed by register
+}
+
+impl IrqEvent
+{
+    // Creates an event for a handler that is installed elsewhere
+    pub fn new() -> (IrqSignal, IrqEvent)
+    {
+        let shared = Arc::new(EventShared
+        {
+            pending: AtomicU64::new(0),
+            waker: AtomicWaker::new(),
+            signals: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        });
+        (IrqSignal { shared: shared.clone() }, IrqEvent { shared, handle: None })
+    }
+
+    // Adds a handler to IRQ line `irq` that signals the event
+    // `acknowledge` runs first in interrupt context (e.g. to clear the device's interrupt status)
+    // the event is only signaled if it returns IrqReturn::Handled
+    pub fn register<F>(irq: u8, acknowledge: F) -> IrqEvent
+    where
+        F: Fn() -> IrqReturn + Send + Sync + 'static,
+    {
+        let (signal, mut event) = IrqEvent::new();
+        event.handle = Some(interrupts::register_irq(irq, move ||
+        {
+            let result = acknowledge();
+            if result == IrqReturn::Handled
+            {
+                signal.signal();
+            }
+            result
+        }));
+        event
+    }
+
+    // waits for the next signal. Resolves to the number of signals since the last wait (at least 1)
+    pub fn wait(&mut self) -> Wait
+    {
+        Wait { event: self }
+    }
+
+    pub fn stats(&self) -> IrqEventStats
+    {
+        IrqEventStats
+        {
+            signals: self.shared.signals.load(Ordering::Relaxed),
+            coalesced: self.shared.coalesced.load(Ordering::Relaxed),
+        }
+    }
+
+    fn poll_signal(&self, cx: &mut Context) -> Poll<u64>
+    {
+        let shared = &self.shared;
+        let pending = shared.pending.swap(0, Ordering::Acquire);
+        if pending > 0
+        {
+            return Poll::Ready(pending);
+        }
+        shared.waker.register(cx.waker());
+        match shared.pending.swap(0, Ordering::Acquire)
+        {
+            0 => Poll::Pending,
+            pending =>
+            {
+                shared.waker.take();
+                Poll::Ready(pending)
+            }
+        }
+    }
+}
+
+// Future returned by IrqEvent::wait
+pub struct Wait<'a>
+{
+    event: &'a mut IrqEvent,
+}
+
+impl<'a> Future for Wait<'a>
+{
+    type Output = u64;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<u64>
+    {
+        self.event.poll_signal(cx)
+    }
+}
+
+impl Drop for IrqEvent
+{
+    fn drop(&mut self)
+    {
+        if let Some(handle) = self.handle.take()
+        {
+            interrupts::unregister_irq(handle);
+        }
+    }
+}
+
+// every item is the number of signals since the previous one
+impl Stream for IrqEvent
+{
+    type Item = u64;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>>
+    {
+        self.poll_signal(cx).map(Some)
+    }
+}
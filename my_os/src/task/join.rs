@@ -0,0 +1,203 @@
+use super::Task;
+use alloc::{boxed::Box, sync::Arc};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+
+// Returned by awaiting a JoinHandle whose task never produced an output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError
+{
+    Cancelled,  // JoinHandle::abort was called or the executor dropped the task
+}
+
+enum Output<T>
+{
+    Running,
+    Finished(T),
+    Cancelled,
+    Consumed,   // output already handed out by JoinHandle::poll
+}
+
+// shared between the running task (Joinable) and its JoinHandle
+struct JoinState<T>
+{
+    aborted: AtomicBool,
+    inner: Mutex<JoinInner<T>>,
+}
+
+struct JoinInner<T>
+{
+    output: Output<T>,
+    join_waker: Option<Waker>,  // task waiting on the JoinHandle
+    task_waker: Option<Waker>,  // the joinable task itself. abort wakes it so it can finish as cancelled
+}
+
+impl<T> JoinState<T>
+{
+    // stores the result (None = cancelled) and wakes whoever waits on the JoinHandle
+    fn finish(&self, output: Option<T>)
+    {
+        let waker = {
+            let mut inner = self.inner.lock();
+            inner.output = match output
+            {
+                Some(output) => Output::Finished(output),
+                None => Output::Cancelled,
+            };
+            inner.task_waker = None;
+            inner.join_waker.take()
+        };
+        // wake outside the lock. woken task may poll the JoinHandle right away on another executor
+        if let Some(waker) = waker
+        {
+            waker.wake();
+        }
+    }
+}
+
+// Future that runs inside the executor. Drives the user future and reports its output to JoinState
+struct Joinable<F: Future>
+{
+    future: Option<Pin<Box<F>>>,    // None once finished or cancelled
+    state: Arc<JoinState<F::Output>>,
+}
+
+impl<F: Future> Future for Joinable<F>
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>
+    {
+        let this = &mut *self;
+
+        // register waker before checking the abort flag.
+        // an abort after the check is then guranteed to see the waker and wake us again
+        {
+            let mut inner = this.state.inner.lock();
+            match &inner.task_waker
+            {
+                Some(waker) if waker.will_wake(cx.waker()) => {}
+                _ => inner.task_waker = Some(cx.waker().clone()),
+            }
+        }
+
+        let future = match this.future.as_mut()
+        {
+            Some(future) => future,
+            None => return Poll::Ready(()),
+        };
+
+        if this.state.aborted.load(Ordering::Acquire)
+        {
+            // drop the user future right away to release whatever it holds
+            this.future = None;
+            this.state.finish(None);
+            return Poll::Ready(());
+        }
+
+        match future.as_mut().poll(cx)
+        {
+            Poll::Ready(output) =>
+            {
+                this.future = None;
+                this.state.finish(Some(output));
+                Poll::Ready(())
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F: Future> Drop for Joinable<F>
+{
+    // executor dropped the task before it finished -> JoinHandle resolves to Cancelled instead of hanging
+    fn drop(&mut self)
+    {
+        if self.future.take().is_some()
+        {
+            self.state.finish(None);
+        }
+    }
+}
+
+// Future resolving to the output of a spawned task
+// Ok(output) when the task completed, Err(JoinError::Cancelled) when it was aborted
+// dropping the handle detaches the task. it keeps running, its output is discarded
+pub struct JoinHandle<T>
+{
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T>
+{
+    // Requests cancellation. The task is dropped the next time the executor polls it
+    // (abort wakes it, so that happens in the next scheduling round)
+    // no effect when the task already finished
+    pub fn abort(&self)
+    {
+        self.state.aborted.store(true, Ordering::Release);
+        let waker = self.state.inner.lock().task_waker.take();
+        if let Some(waker) = waker
+        {
+            waker.wake();
+        }
+    }
+
+    // true once the task completed or was cancelled
+    pub fn is_finished(&self) -> bool
+    {
+        !matches!(self.state.inner.lock().output, Output::Running)
+    }
+}
+
+impl<T> Future for JoinHandle<T>
+{
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, JoinError>>
+    {
+        let mut inner = self.state.inner.lock();
+        match core::mem::replace(&mut inner.output, Output::Consumed)
+        {
+            Output::Finished(output) => Poll::Ready(Ok(output)),
+            Output::Cancelled => Poll::Ready(Err(JoinError::Cancelled)),
+            Output::Running =>
+            {
+                inner.output = Output::Running;
+                inner.join_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Output::Consumed => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+impl Task
+{
+    // Creates a task whose output can be awaited through the returned JoinHandle
+    pub fn joinable<F>(future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let state = Arc::new(JoinState
+        {
+            aborted: AtomicBool::new(false),
+            inner: Mutex::new(JoinInner
+            {
+                output: Output::Running,
+                join_waker: None,
+                task_waker: None,
+            }),
+        });
+        let task = Task::new(Joinable
+        {
+            future: Some(Box::pin(future)),
+            state: state.clone(),
+        });
+        (task, JoinHandle { state })
+    }
+}
@@ -1,148 +1,343 @@
-use conquer_once::spin::OnceCell;
-use crossbeam_queue::ArrayQueue;
 use core::{pin::Pin, task::{Poll, Context}};
 use futures_util::stream::Stream;
-use futures_util::task::AtomicWaker;
 use futures_util::stream::StreamExt;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-
-use crate::print;
-use crate::println;
-
-// ArrayQueue::new performs heap allocation. Not possible at compile time.
-// cannot initialize static variable directly.
-// Use OnceCall type of conquer_once -> makes it possible to perform safe one-time initialization of static values
-// initialization doesnt happen in interrupt handler. Prevents the handler from performing heap allocation
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-
-// To implement Waker notif for ScancodeStream, 
-// store Waker between poll calls 
-// Idea: poll_next stores current waker in this static and add_scancode function calls wake function on it when new scancode is added to queue
-static WAKER: AtomicWaker = AtomicWaker::new();
-
-/// Called by the keyboard interrupt handler
-/// Must not block or allocate.
-/// shouldnt initialize queue in this function since it will be called by interrupt handler (shouldnt perform heap allocation)
-pub(crate) fn add_scancode(scancode: u8) 
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::{HandleControl, Keyboard};
+use x86_64::instructions::port::Port;
+
+use super::irq::{IrqStream, IrqStreamStats};
+use crate::interrupts::{InterruptIndex, IrqReturn};
+use crate::{print, vga_buffer};
+use crate::vga_buffer::console;
+use crate::ps2::{self, Ps2Error};
+
+// read_line: line editing with history
+mod line;
+// runtime switchable layout and scancode set
+mod layout;
+
+pub use layout::{layout, scancode_set, set_layout, set_scancode_set, ActiveLayout, ActiveScancodeSet, Layout, ScancodeSetKind};
+pub use line::{read_line_from, read_line_on, with_history, Edit, History, LineEditor, ReadLineError, HISTORY_SIZE};
+pub use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+
+// scancodes buffered between the keyboard interrupt and the reading task
+const SCANCODE_QUEUE_SIZE: usize = 100;
+
+// Scancodes of the PS/2 keyboard (IRQ1) as asynchronous stream
+// interrupt handler reads port 0x60 and pushes the byte into an IrqStream (no allocation in the handler)
+// the queue is allocated in new, outside of interrupt context. Dropping the stream removes the handler
+pub struct ScancodeStream 
+{
+    scancodes: IrqStream<u8>,
+}
+
+impl ScancodeStream 
 {
-    // try_get: gets reference to initialized queue 
-    if let Ok(queue) = SCANCODE_QUEUE.try_get() 
+    // registers the keyboard interrupt handler
+    // only one ScancodeStream should exist at a time: each interrupt reads a single byte from the port
+    pub fn new() -> Self 
     {
-        // case when queue is full
-        if let Err(_) = queue.push(scancode) 
+        let scancodes = IrqStream::register(InterruptIndex::Keyboard.irq(), SCANCODE_QUEUE_SIZE, |queue|
         {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        }
-        else 
-        {
-            // to wake stored Waker if the push to scancode queue succeeds
-            // if waker is registered in WAKER static, method call the equally-named wake method on it
-            // and notifies executor.
-            WAKER.wake();    
-
-            // important to call wake only after pushing to queue.
-            // otherwise, task might be woken too early when queue is still empty
-            // can happen when using multi-threaded executor that starts woken task concurrently on diff CPU core
-        }
-    } 
-    else 
+            let mut port = Port::new(0x60);
+            let scancode: u8 = unsafe { port.read() };
+            // full queue: scancode is dropped and counted in stats
+            queue.push(scancode);
+            IrqReturn::Handled
+        });
+        ScancodeStream { scancodes }
+    }
+
+    // received and dropped scancodes
+    pub fn stats(&self) -> IrqStreamStats
     {
-        // if queue not initialized, prints warning. no keyboard scancode
-        println!("WARNING: scancode queue uninitialized");
+        self.scancodes.stats()
     }
 }
 
-// To initialize SCANCODE_QUEUE and read scancodes from queue in asynchoronous way
-// creates new ScancodeStream type
-pub struct ScancodeStream 
+impl Stream for ScancodeStream 
 {
-    _private: (),   // prevents construction of struct from outside of module
-                    // new function is the only way to construct the type
+    type Item = u8;
+
+    // waker registration and the race with the interrupt handler are handled by IrqStream
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> 
+    {
+        Pin::new(&mut self.scancodes).poll_next(cx)
+    }
 }
 
-impl ScancodeStream 
+// Modifier and lock key state at the time of a KeyEvent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers
 {
-    pub fn new() -> Self 
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub alt_gr: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+// One key going down or up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent
+{
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,       // including the change made by this key
+    pub key: Option<DecodedKey>,    // character or key in the current layout. None for releases and modifiers
+    pub console: usize,             // virtual console with the input focus when the key was pressed
+}
+
+impl KeyEvent
+{
+    pub fn is_down(&self) -> bool
     {
-        // initialize SCANCODE_QUEUE static. Panics if already initialized to ensure only single ScancodeStrea instance can be created
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
-        ScancodeStream { _private: () }
+        self.state != KeyState::Up
     }
-    // to make scancodes availble to asynchronous tasks, need poll like method that tries to pop next scancode off the queue
-    // Future isnt a choice: only abstracts over single asynchornous value
 }
 
-impl Stream for ScancodeStream 
+// Keyboard LEDs, set with set_leds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds
 {
-    type Item = u8;
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+const CMD_SET_LEDS: u8 = 0xed;
+
+impl Leds
+{
+    // data byte of CMD_SET_LEDS
+    fn bits(self) -> u8
+    {
+        u8::from(self.scroll_lock) | u8::from(self.num_lock) << 1 | u8::from(self.caps_lock) << 2
+    }
+}
+
+// Switches the keyboard LEDs. KeyEventStream keeps them in sync with the lock keys on its own
+// waits for the keyboard's ACKs, not for use inside a KeyEventStream task
+pub fn set_leds(leds: Leds) -> Result<(), Ps2Error>
+{
+    ps2::keyboard_command(CMD_SET_LEDS, &[leds.bits()])
+}
+
+// LED update of a KeyEventStream. The keyboard's ACKs arrive in the stream like scancodes,
+// each one sends the next byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedUpdate
+{
+    Idle,
+    CommandSent(u8),    // CMD_SET_LEDS written, LED bits follow on ACK
+    BitsSent(u8),       // LED bits written
+}
+
+// lines per Shift+PageUp/PageDown: half a screen
+const SCROLL_STEP: isize = (vga_buffer::BUFFER_HEIGHT / 2) as isize;
+
+// virtual console selected by Alt + `code`
+fn console_index(code: KeyCode) -> Option<usize>
+{
+    match code
+    {
+        KeyCode::F1 => Some(0),
+        KeyCode::F2 => Some(1),
+        KeyCode::F3 => Some(2),
+        KeyCode::F4 => Some(3),
+        KeyCode::F5 => Some(4),
+        KeyCode::F6 => Some(5),
+        _ => None,
+    }
+}
+
+// scroll lock is not tracked by pc_keyboard
+static SCROLL_LOCK: AtomicBool = AtomicBool::new(false);
+
+// Decoded key events of the PS/2 keyboard in the active layout and scancode set
+// like ScancodeStream, only one should exist at a time
+pub struct KeyEventStream
+{
+    scancodes: ScancodeStream,
+    keyboard: Keyboard<ActiveLayout, ActiveScancodeSet>,
+    leds: LedUpdate,
+}
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> 
+impl KeyEventStream
+{
+    pub fn new() -> Self
     {
-        // try_get: to get ref to initalized scancode queue
-        // queue.pop to try getting next element from queue
-        // if fails on first pop call, queue is potentially empty.
-        // interrupt handler might have filled queue asynchronously immediately after check
-        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
-        
-        // fast path
-        if let Some(scancode) = queue.pop() 
+        KeyEventStream
         {
-            return Poll::Ready(Some(scancode));
+            scancodes: ScancodeStream::new(),
+            // Ctrl + letter stays the letter, ctrl is visible in the modifiers
+            keyboard: Keyboard::new(ActiveScancodeSet::new(), ActiveLayout, HandleControl::Ignore),
+            leds: LedUpdate::Idle,
         }
+    }
 
-        // since race condition can occur again, register Waker in WAKER static before second check.
-        // wakeup might happen before returning Poll::Pending, but guranteed to get wakeup for any scancode pushed after the check
-        WAKER.register(&cx.waker());
+    // received and dropped scancodes (including the keyboard's replies)
+    pub fn stats(&self) -> IrqStreamStats
+    {
+        self.scancodes.stats()
+    }
 
-        // tries to pop the queue for second time.
+    pub fn modifiers(&self) -> Modifiers
+    {
+        let modifiers = self.keyboard.get_modifiers();
+        Modifiers
+        {
+            shift: modifiers.is_shifted(),
+            ctrl: modifiers.is_ctrl(),
+            alt: modifiers.lalt || modifiers.ralt,
+            alt_gr: modifiers.ralt,
+            caps_lock: modifiers.capslock,
+            num_lock: modifiers.numlock,
+            scroll_lock: SCROLL_LOCK.load(Ordering::Relaxed),
+        }
+    }
 
-        match queue.pop() 
+    // decodes one byte. None while a multi byte scancode is incomplete
+    fn decode(&mut self, scancode: u8) -> Option<KeyEvent>
+    {
+        // replies to the LED update, or late ACKs of keyboard commands (see ps2::keyboard_command)
+        if scancode == ps2::ACK || scancode == ps2::RESEND
         {
-            // if succeeds, return scancode wrapped in Poll::Ready(Some(...))
-            // removes registered waker using take because notif is no longer needed
-            Some(scancode) => 
+            self.led_reply(scancode);
+            return None;
+        }
+        // a key instead of the reply: the keyboard did not take the command
+        self.leds = LedUpdate::Idle;
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        let (code, state) = (event.code, event.state);
+        let key = self.keyboard.process_keyevent(event);
+        // Shift+PageUp/PageDown scroll the console for every reader and are not passed on
+        if state == KeyState::Down && self.modifiers().shift
+        {
+            match code
             {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
+                KeyCode::PageUp => { vga_buffer::scroll(SCROLL_STEP); return None; }
+                KeyCode::PageDown => { vga_buffer::scroll(-SCROLL_STEP); return None; }
+                _ => {}
             }
-            // if fails, return pending with a registered wakeup
-            None => Poll::Pending,
         }
+        // so do Alt+F1..F6, they switch the virtual console
+        if state == KeyState::Down && self.modifiers().alt
+        {
+            if let Some(index) = console_index(code)
+            {
+                console::switch_to(index);
+                return None;
+            }
+        }
+        if state == KeyState::Down && matches!(code, KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock)
+        {
+            if code == KeyCode::ScrollLock
+            {
+                SCROLL_LOCK.fetch_xor(true, Ordering::Relaxed);
+            }
+            self.update_leds();
+        }
+        Some(KeyEvent { code, state, modifiers: self.modifiers(), key, console: console::active() })
     }
-}
 
-// ways for wakeup to happen for task that did not return Poll::Pending yet.
-// 1. race condition when wakeup happens immediately before returning Poll::Pending
-// 2. queue is no longr empty after registering waker so Poll::Ready is returned
-// They arent preventable. executor must handle them
+    fn led_bits(&self) -> u8
+    {
+        let modifiers = self.modifiers();
+        Leds { scroll_lock: modifiers.scroll_lock, num_lock: modifiers.num_lock, caps_lock: modifiers.caps_lock }.bits()
+    }
 
-// instead of reading scancode from I/O port, take it from ScancodeStream
-pub async fn print_keypresses() 
+    // starts sending the lock key state to the LEDs without waiting for the keyboard. An update in
+    // flight picks up the new state when it ends
+    fn update_leds(&mut self)
+    {
+        if self.leds == LedUpdate::Idle
+        {
+            self.send_led_byte(LedUpdate::CommandSent(self.led_bits()));
+        }
+    }
+
+    // writes the byte of `next` to the keyboard. A keyboard that does not take it is left alone
+    fn send_led_byte(&mut self, next: LedUpdate)
+    {
+        let byte = match next
+        {
+            LedUpdate::Idle => return,
+            LedUpdate::CommandSent(_) => CMD_SET_LEDS,
+            LedUpdate::BitsSent(bits) => bits,
+        };
+        self.leds = if ps2::write_data(byte).is_ok() { next } else { LedUpdate::Idle };
+    }
+
+    // ACK or RESEND from the keyboard
+    fn led_reply(&mut self, reply: u8)
+    {
+        match (self.leds, reply)
+        {
+            (LedUpdate::CommandSent(bits), ps2::ACK) => self.send_led_byte(LedUpdate::BitsSent(bits)),
+            (LedUpdate::BitsSent(bits), ps2::ACK) =>
+            {
+                self.leds = LedUpdate::Idle;
+                // a lock key changed meanwhile
+                if self.led_bits() != bits
+                {
+                    self.update_leds();
+                }
+            }
+            // repeat the last byte
+            (update, ps2::RESEND) => self.send_led_byte(update),
+            // late ACK of ps2::keyboard_command
+            _ => {}
+        }
+    }
+}
+
+impl Stream for KeyEventStream
 {
-    // Creates Scancode stream then repeatedly use next method provided by StreamExt trait to get Future that resolves to next element in stream
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(ScancodeSet1::new(),
-        layouts::Us104Key, HandleControl::Ignore);
+    type Item = KeyEvent;
 
-    // while let to loop until the stream returns None to signal its 
-    // Since poll_next method never returns None, it is an endless loop.
-    // print_keypresses task never finishes
-    while let Some(scancode) = scancodes.next().await 
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>>
     {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) 
+        loop
         {
-            if let Some(key) = keyboard.process_keyevent(key_event) 
+            match Pin::new(&mut self.scancodes).poll_next(cx)
             {
-                match key 
+                Poll::Ready(Some(scancode)) =>
                 {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                    if let Some(event) = self.decode(scancode)
+                    {
+                        return Poll::Ready(Some(event));
+                    }
                 }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
             }
         }
     }
 }
+
+// prints every key pressed on the shell console, in the active layout
+pub async fn print_keypresses() 
+{
+    let mut keys = KeyEventStream::new();
+
+    // KeyEventStream never returns None, so print_keypresses task never finishes
+    while let Some(event) = keys.next().await 
+    {
+        if event.console != console::SHELL
+        {
+            continue;
+        }
+        match event.key 
+        {
+            Some(DecodedKey::Unicode(character)) => print!("{}", character),
+            Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+            None => {}
+        }
+    }
+}
 // Keeps CPU busy even if no keys are pressed on keyboard (SimpleExecutor keeps calling pll task in a loop)
 // need executor that properly utilizes Waker notification (executor is notified when next keyboard interrupt occurs)
 // no need to keep poling print_keypresses task repeatedly
\ No newline at end of file
@@ -0,0 +1,132 @@
+// Keyboard layout and scancode set, switchable at runtime
+//
+// every decoder (KeyEventStream, read_line_from) uses ActiveLayout and ActiveScancodeSet,
+// which look up the current setting on each key, so set_layout takes effect immediately
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use pc_keyboard::{layouts, DecodedKey, Error, HandleControl, KeyCode, KeyEvent, KeyboardLayout, Modifiers};
+use pc_keyboard::{ScancodeSet, ScancodeSet1, ScancodeSet2};
+
+use crate::ps2::{self, Ps2Error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout
+{
+    Us104,
+    Uk105,
+    De105,
+    Azerty,
+    Dvorak,
+}
+
+impl Layout
+{
+    fn from_u8(value: u8) -> Layout
+    {
+        match value
+        {
+            1 => Layout::Uk105,
+            2 => Layout::De105,
+            3 => Layout::Azerty,
+            4 => Layout::Dvorak,
+            _ => Layout::Us104,
+        }
+    }
+}
+
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us104 as u8);
+
+pub fn layout() -> Layout
+{
+    Layout::from_u8(LAYOUT.load(Ordering::Relaxed))
+}
+
+pub fn set_layout(layout: Layout)
+{
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+// Layout that follows set_layout
+pub struct ActiveLayout;
+
+impl KeyboardLayout for ActiveLayout
+{
+    fn map_keycode(&self, keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey
+    {
+        match layout()
+        {
+            Layout::Us104 => layouts::Us104Key.map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Uk105 => layouts::Uk105Key.map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::De105 => layouts::De105Key.map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Azerty => layouts::Azerty.map_keycode(keycode, modifiers, handle_ctrl),
+            Layout::Dvorak => layouts::Dvorak104Key.map_keycode(keycode, modifiers, handle_ctrl),
+        }
+    }
+}
+
+// Scancode set the keyboard bytes are decoded with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ScancodeSetKind
+{
+    Set1,   // keyboard sends set 2, the controller translates it (PC default)
+    Set2,   // translation off: set 2 as sent by the keyboard
+}
+
+static SCANCODE_SET: AtomicU8 = AtomicU8::new(ScancodeSetKind::Set1 as u8);
+
+pub fn scancode_set() -> ScancodeSetKind
+{
+    match SCANCODE_SET.load(Ordering::Relaxed)
+    {
+        1 => ScancodeSetKind::Set2,
+        _ => ScancodeSetKind::Set1,
+    }
+}
+
+// Switches the controller translation and the decoders to `set`
+// a key held down during the switch may be decoded wrongly once
+pub fn set_scancode_set(set: ScancodeSetKind) -> Result<(), Ps2Error>
+{
+    ps2::set_translation(set == ScancodeSetKind::Set1)?;
+    SCANCODE_SET.store(set as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+// Decoder that follows set_scancode_set. Keeps one state machine per set,
+// the one of the inactive set is reset when the set changes
+pub struct ActiveScancodeSet
+{
+    current: ScancodeSetKind,
+    set1: ScancodeSet1,
+    set2: ScancodeSet2,
+}
+
+impl ActiveScancodeSet
+{
+    pub fn new() -> Self
+    {
+        ActiveScancodeSet { current: scancode_set(), set1: ScancodeSet1::new(), set2: ScancodeSet2::new() }
+    }
+}
+
+impl ScancodeSet for ActiveScancodeSet
+{
+    fn advance_state(&mut self, code: u8) -> Result<Option<KeyEvent>, Error>
+    {
+        let set = scancode_set();
+        if set != self.current
+        {
+            // drop a half received multi byte sequence of the old set
+            self.current = set;
+            self.set1 = ScancodeSet1::new();
+            self.set2 = ScancodeSet2::new();
+        }
+        match set
+        {
+            ScancodeSetKind::Set1 => self.set1.advance_state(code),
+            ScancodeSetKind::Set2 => self.set2.advance_state(code),
+        }
+    }
+}
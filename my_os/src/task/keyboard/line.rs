@@ -0,0 +1,384 @@
+// Line editor on top of the keyboard stream: read_line_from(&mut keys).await
+//
+// LineEditor holds the edited line and the cursor and turns decoded keys into edits.
+// read_line_from feeds it keys and redraws the line on the last VGA row after each one,
+// with the writer's position as cursor. Submitted lines go into a shared History
+//
+// keys: Left/Right, Home/End, Backspace, Delete, Up/Down (history),
+// Ctrl-C (cancel the line), Ctrl-U (delete everything before the cursor), Enter
+
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::fmt;
+use futures_util::stream::StreamExt;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::{KeyEventStream, Modifiers};
+use crate::vga_buffer::{console, BUFFER_WIDTH};
+
+// lines kept by the shared history of read_line
+pub const HISTORY_SIZE: usize = 32;
+
+// control characters, see control_key
+const CTRL_C: char = '\u{3}';
+const CTRL_U: char = '\u{15}';
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+
+// a shorter rest of the row starts the line on a new row
+const MIN_LINE_WIDTH: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadLineError
+{
+    Interrupted,    // Ctrl-C
+    NoConsole,      // read_line_on a console that does not exist
+}
+
+impl fmt::Display for ReadLineError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            ReadLineError::Interrupted => write!(f, "interrupted"),
+            ReadLineError::NoConsole => write!(f, "no such console"),
+        }
+    }
+}
+
+// Previously entered lines, oldest first
+pub struct History
+{
+    entries: VecDeque<String>,
+    capacity: usize,
+}
+
+impl History
+{
+    pub const fn new(capacity: usize) -> Self
+    {
+        History { entries: VecDeque::new(), capacity }
+    }
+
+    // empty lines and repetitions of the last line are not recorded
+    pub fn push(&mut self, line: &str)
+    {
+        if line.is_empty() || self.entries.back().map_or(false, |last| last == line) || self.capacity == 0
+        {
+            return;
+        }
+        if self.entries.len() == self.capacity
+        {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(String::from(line));
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.entries.is_empty()
+    }
+
+    // `index` 0 is the oldest line
+    pub fn get(&self, index: usize) -> Option<&str>
+    {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.entries.clear();
+    }
+}
+
+// history of read_line. Only used by tasks
+static HISTORY: spin::Mutex<History> = spin::Mutex::new(History::new(HISTORY_SIZE));
+
+// Result of LineEditor::handle_key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit
+{
+    Changed,            // line or cursor changed, redraw
+    Unchanged,
+    Submit(String),     // Enter: the finished line
+    Cancel,             // Ctrl-C
+}
+
+fn changed_if(changed: bool) -> Edit
+{
+    if changed { Edit::Changed } else { Edit::Unchanged }
+}
+
+// Edited line and cursor, independent of keyboard and screen
+pub struct LineEditor
+{
+    line: Vec<char>,
+    cursor: usize,              // index into line, line.len() is behind the last character
+    max_len: usize,
+    browsing: Option<usize>,    // history entry shown by Up/Down
+    draft: Vec<char>,           // line typed before browsing the history
+}
+
+impl LineEditor
+{
+    // line of at most `max_len` characters
+    pub fn new(max_len: usize) -> Self
+    {
+        LineEditor { line: Vec::new(), cursor: 0, max_len, browsing: None, draft: Vec::new() }
+    }
+
+    pub fn line(&self) -> String
+    {
+        self.line.iter().collect()
+    }
+
+    pub fn chars(&self) -> &[char]
+    {
+        &self.line
+    }
+
+    pub fn cursor(&self) -> usize
+    {
+        self.cursor
+    }
+
+    pub fn handle_key(&mut self, key: DecodedKey, history: &History) -> Edit
+    {
+        match key
+        {
+            DecodedKey::Unicode('\n') => Edit::Submit(self.line()),
+            DecodedKey::Unicode(CTRL_C) => Edit::Cancel,
+            DecodedKey::Unicode(CTRL_U) => self.kill_to_start(),
+            DecodedKey::Unicode(BACKSPACE) => self.backspace(),
+            DecodedKey::Unicode(DELETE) | DecodedKey::RawKey(KeyCode::Delete) => self.delete(),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.move_to(self.cursor.saturating_sub(1)),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => self.move_to(self.cursor + 1),
+            DecodedKey::RawKey(KeyCode::Home) => self.move_to(0),
+            DecodedKey::RawKey(KeyCode::End) => self.move_to(self.line.len()),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.history_previous(history),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.history_next(history),
+            DecodedKey::Unicode(character) if !character.is_control() => self.insert(character),
+            _ => Edit::Unchanged,
+        }
+    }
+
+    fn insert(&mut self, character: char) -> Edit
+    {
+        // full line: key is ignored
+        if self.line.len() >= self.max_len
+        {
+            return Edit::Unchanged;
+        }
+        self.line.insert(self.cursor, character);
+        self.cursor += 1;
+        Edit::Changed
+    }
+
+    fn backspace(&mut self) -> Edit
+    {
+        if self.cursor == 0
+        {
+            return Edit::Unchanged;
+        }
+        self.cursor -= 1;
+        self.line.remove(self.cursor);
+        Edit::Changed
+    }
+
+    fn delete(&mut self) -> Edit
+    {
+        if self.cursor == self.line.len()
+        {
+            return Edit::Unchanged;
+        }
+        self.line.remove(self.cursor);
+        Edit::Changed
+    }
+
+    fn kill_to_start(&mut self) -> Edit
+    {
+        let changed = self.cursor > 0;
+        self.line.drain(..self.cursor);
+        self.cursor = 0;
+        changed_if(changed)
+    }
+
+    fn move_to(&mut self, cursor: usize) -> Edit
+    {
+        let cursor = cursor.min(self.line.len());
+        let changed = cursor != self.cursor;
+        self.cursor = cursor;
+        changed_if(changed)
+    }
+
+    fn replace_line(&mut self, line: Vec<char>)
+    {
+        self.line = line;
+        self.line.truncate(self.max_len);
+        self.cursor = self.line.len();
+    }
+
+    fn history_previous(&mut self, history: &History) -> Edit
+    {
+        let index = match self.browsing
+        {
+            Some(0) => return Edit::Unchanged,
+            Some(index) => index - 1,
+            None if history.is_empty() => return Edit::Unchanged,
+            None =>
+            {
+                self.draft = self.line.clone();
+                history.len() - 1
+            }
+        };
+        self.browsing = Some(index);
+        self.replace_line(history.get(index).unwrap_or("").chars().collect());
+        Edit::Changed
+    }
+
+    fn history_next(&mut self, history: &History) -> Edit
+    {
+        match self.browsing
+        {
+            None => Edit::Unchanged,
+            Some(index) if index + 1 < history.len() =>
+            {
+                self.browsing = Some(index + 1);
+                self.replace_line(history.get(index + 1).unwrap_or("").chars().collect());
+                Edit::Changed
+            }
+            // past the newest entry: back to what was typed before
+            Some(_) =>
+            {
+                self.browsing = None;
+                let draft = core::mem::take(&mut self.draft);
+                self.replace_line(draft);
+                Edit::Changed
+            }
+        }
+    }
+}
+
+// Ctrl + letter as control character (Ctrl-C is '\u{3}'), like HandleControl::MapLettersToUnicode
+fn control_key(key: DecodedKey, modifiers: Modifiers) -> DecodedKey
+{
+    match key
+    {
+        DecodedKey::Unicode(letter) if modifiers.ctrl && letter.is_ascii_alphabetic() =>
+            DecodedKey::Unicode(char::from(letter.to_ascii_lowercase() as u8 - b'a' + 1)),
+        key => key,
+    }
+}
+
+// VGA cell of a character. Like Writer::write_string, non ASCII shows as a box
+fn screen_byte(character: char) -> u8
+{
+    match character
+    {
+        ' '..='~' => character as u8,
+        _ => 0xfe,
+    }
+}
+
+// redraws the line from column `start` of the last row and puts the writer position at the cursor
+// `drawn` is the length of the previous drawing, characters beyond the new line are blanked
+fn redraw(index: usize, editor: &LineEditor, start: usize, drawn: usize)
+{
+    console::with(index, |writer|
+    {
+        writer.set_column(start);
+        for &character in editor.chars()
+        {
+            writer.write_byte(screen_byte(character));
+        }
+        for _ in editor.chars().len()..drawn
+        {
+            writer.write_byte(b' ');
+        }
+        writer.set_column(start + editor.cursor());
+    });
+}
+
+// Reads one line with keys of the caller's KeyEventStream, with editing and history
+// Returns the line without the newline, or ReadLineError::Interrupted on Ctrl-C.
+// the line has to fit into the rest of the screen row. Keys typed ahead stay in `keys` for the next
+// line, and so does the modifier state. No other task should read `keys` meanwhile
+pub async fn read_line_from(keys: &mut KeyEventStream) -> Result<String, ReadLineError>
+{
+    read_line_on(keys, console::SHELL).await
+}
+
+// read_line_from on virtual console `index`: echoes there and only takes keys pressed while it has the focus
+pub async fn read_line_on(keys: &mut KeyEventStream, index: usize) -> Result<String, ReadLineError>
+{
+    let start = console::with(index, |writer|
+    {
+        if BUFFER_WIDTH - writer.column() < MIN_LINE_WIDTH
+        {
+            writer.write_byte(b'\n');
+        }
+        writer.column()
+    }).ok_or(ReadLineError::NoConsole)?;
+    // one column stays free for the cursor behind the last character
+    let mut editor = LineEditor::new(BUFFER_WIDTH - start - 1);
+    let mut drawn = 0;
+
+    while let Some(event) = keys.next().await
+    {
+        if event.console != index
+        {
+            continue;
+        }
+        let key = match event.key
+        {
+            Some(key) => control_key(key, event.modifiers),
+            None => continue,
+        };
+        // history lock is not held across an await
+        let edit = editor.handle_key(key, &HISTORY.lock());
+        match edit
+        {
+            Edit::Changed =>
+            {
+                redraw(index, &editor, start, drawn);
+                drawn = editor.chars().len();
+            }
+            Edit::Unchanged => {}
+            Edit::Submit(line) =>
+            {
+                HISTORY.lock().push(&line);
+                end_line(index, &editor, start, "");
+                return Ok(line);
+            }
+            Edit::Cancel =>
+            {
+                end_line(index, &editor, start, "^C");
+                return Err(ReadLineError::Interrupted);
+            }
+        }
+    }
+    // keyboard stream never ends
+    Err(ReadLineError::Interrupted)
+}
+
+// moves behind the line, prints `suffix` and a newline
+fn end_line(index: usize, editor: &LineEditor, start: usize, suffix: &str)
+{
+    console::with(index, |writer|
+    {
+        writer.set_column(start + editor.chars().len());
+        writer.write_string(suffix);
+        writer.write_byte(b'\n');
+    });
+}
+
+// gives access to the history shared by all read_line calls (e.g. to list or clear it)
+pub fn with_history<R>(f: impl FnOnce(&mut History) -> R) -> R
+{
+    f(&mut HISTORY.lock())
+}
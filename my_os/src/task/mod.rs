@@ -1,14 +1,35 @@
-use core::{future::Future, pin::Pin};
+use core::{fmt, future::Future, pin::Pin};
 use alloc::boxed::Box;
 use core::task::{Context, Poll};
 use core::sync::atomic::{AtomicU64, Ordering};
+use crate::time::tsc;
 
 pub mod simple_executor;
 pub mod keyboard;
 pub mod executor;
+pub mod join;
+pub mod sync;
+pub mod irq;
+pub mod mouse;
+pub mod serial;
+
+pub use executor::Spawner;
+pub use join::{JoinHandle, JoinError};
+
+// Spawns a future on the running Executor and returns handle to await its output
+// for code running inside a task. Panics if Executor::run was not called yet
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    executor::global_spawner()
+        .expect("task::spawn called without a running executor")
+        .spawn(future)
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64); // simple wrapper type around u64. 
+pub struct TaskId(u64); // simple wrapper type around u64. 
 // derives a number of traits for it to make it printable, copyable, comparable, and sortable
 
 impl TaskId 
@@ -23,6 +44,41 @@ impl TaskId
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(self) -> u64
+    {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Scheduling class of a task. Executor always prefers ready tasks of a higher class
+// (see executor::STARVATION_LIMIT for how lower classes still get to run)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority
+{
+    Realtime,       // must react immediately (e.g. timer driven work)
+    #[default]
+    Interactive,    // user facing, e.g. keyboard input
+    Background,     // long running work that may wait
+}
+
+impl Priority
+{
+    pub(crate) const COUNT: usize = 3;
+
+    // index into per priority queues. Realtime = 0
+    pub(crate) fn index(self) -> usize
+    {
+        self as usize
+    }
 }
 
 // newtype wrapper around pinned, heap allocated and dynamically dispatched future with empty type () as output
@@ -32,11 +88,19 @@ pub struct Task
     // tasks dont return any result. just exected for their side effects
     // dyn: indicates that we store trait object in Box (methods on future are dynamically dispatched. allows diff types of futures to be stored in Task type)
     // Pin<Box> ensures value cannot be moved in memory by placing it on heap and prevents creation of &mut ref to it
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    // Send: tasks can be handed to the executor from other tasks through a Spawner
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
     
     // id field makes it possible to uniquely name a task
     // required for waking a specific task
     id: TaskId,
+
+    priority: Priority,
+
+    // introspection (Executor::stats)
+    name: &'static str,
+    polls: u64,         // number of poll calls
+    poll_cycles: u64,   // TSC cycles spent inside poll, summed over all calls
 }
 impl Task 
 {
@@ -44,21 +108,50 @@ impl Task
     // pins it in memory through Box::pin function
     // then wraps boxed future in Task struct and returns it.
     // static lifetime because returned Task can live for arbitrary time
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task 
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task 
     {
         Task 
         {
             future: Box::pin(future),
             id: TaskId::new(),
+            priority: Priority::default(),
+            name: "",
+            polls: 0,
+            poll_cycles: 0,
         }
     }
 
+    // builder style: Task::new(future).with_name("keyboard")
+    // name shows up in Executor::stats listings
+    pub fn with_name(mut self, name: &'static str) -> Task
+    {
+        self.name = name;
+        self
+    }
+
+    pub fn id(&self) -> TaskId
+    {
+        self.id
+    }
+
+    // builder style: Task::new(future).with_priority(Priority::Background)
+    pub fn with_priority(mut self, priority: Priority) -> Task
+    {
+        self.priority = priority;
+        self
+    }
+
     // poll method on Future trait expect toe be called on Pin<&mut T>.
     // must use Pin::as_mut to convert self.future field of type Pin<Box<T>>.
     // then call poll on converted self.future and returns result
+    // counts polls and TSC cycles spent in them, so a spinning task can be found in Executor::stats
     fn poll(&mut self, context: &mut Context) -> Poll<()> 
     {
-        self.future.as_mut().poll(context)
+        let start = tsc::read();
+        let result = self.future.as_mut().poll(context);
+        self.poll_cycles += tsc::read().wrapping_sub(start);
+        self.polls += 1;
+        result
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,253 @@
+// PS/2 mouse on the second port of the 8042 (IRQ 12)
+//
+// MouseStream::new resets the mouse, switches it to IntelliMouse mode if it supports it
+// (4 byte packets with wheel, optionally buttons 4 and 5) and enables reporting.
+// the interrupt handler only queues the raw bytes. Packets are assembled and decoded in poll_next
+
+use alloc::collections::VecDeque;
+use core::{pin::Pin, task::{Context, Poll}};
+use futures_util::stream::Stream;
+use x86_64::instructions::port::Port;
+
+use super::irq::{IrqStream, IrqStreamStats};
+use crate::interrupts::IrqReturn;
+use crate::ps2::{self, Ps2Error};
+
+pub const IRQ: u8 = 12;
+
+// bytes buffered between the interrupt and the reading task (a few hundred packets)
+const BYTE_QUEUE_SIZE: usize = 1024;
+
+const CMD_SET_SAMPLE_RATE: u8 = 0xf3;
+const CMD_GET_ID: u8 = 0xf2;
+const CMD_ENABLE_REPORTING: u8 = 0xf4;
+const CMD_SET_DEFAULTS: u8 = 0xf6;
+const CMD_RESET: u8 = 0xff;
+
+const SELF_TEST_PASSED: u8 = 0xaa;
+const SAMPLE_RATE: u8 = 100;
+
+// device ids reported by CMD_GET_ID
+const ID_STANDARD: u8 = 0;
+const ID_WHEEL: u8 = 3;             // IntelliMouse: wheel in 4th byte
+const ID_FIVE_BUTTONS: u8 = 4;      // IntelliMouse Explorer: wheel and buttons 4, 5
+
+// first packet byte
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_OVERFLOW: u8 = 0b1100_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton
+{
+    Left,
+    Right,
+    Middle,
+    Fourth,
+    Fifth,
+}
+
+const BUTTONS: [MouseButton; 5] = [MouseButton::Left, MouseButton::Right, MouseButton::Middle, MouseButton::Fourth, MouseButton::Fifth];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent
+{
+    Moved { dx: i16, dy: i16 },     // screen directions: positive dx right, positive dy down
+    Scrolled(i8),                   // wheel, positive towards the user
+    ButtonDown(MouseButton),
+    ButtonUp(MouseButton),
+}
+
+// Kind of mouse found by MouseStream::new
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKind
+{
+    Standard,       // 3 buttons, 3 byte packets
+    Wheel,          // 4 byte packets
+    FiveButtons,    // 4 byte packets
+}
+
+// Events of the PS/2 mouse as asynchronous stream. Only one should exist at a time
+// dropping it removes the interrupt handler, the mouse keeps sending until the next reset
+pub struct MouseStream
+{
+    bytes: IrqStream<u8>,
+    kind: MouseKind,
+    packet: [u8; 4],
+    received: usize,
+    buttons: u8,                    // bit n: BUTTONS[n] held
+    pending: VecDeque<MouseEvent>,  // events of the last packet not returned yet
+}
+
+fn command(command: u8, data: &[u8]) -> Result<(), Ps2Error>
+{
+    ps2::mouse_command(command, data, &mut [])
+}
+
+fn device_id() -> Result<u8, Ps2Error>
+{
+    let mut id = [0];
+    ps2::mouse_command(CMD_GET_ID, &[], &mut id)?;
+    Ok(id[0])
+}
+
+// the magic sample rate sequences unlock the IntelliMouse extensions
+fn detect_kind() -> Result<MouseKind, Ps2Error>
+{
+    for &rate in &[200, 100, 80]
+    {
+        command(CMD_SET_SAMPLE_RATE, &[rate])?;
+    }
+    if device_id()? != ID_WHEEL
+    {
+        return Ok(MouseKind::Standard);
+    }
+    for &rate in &[200, 200, 80]
+    {
+        command(CMD_SET_SAMPLE_RATE, &[rate])?;
+    }
+    Ok(if device_id()? == ID_FIVE_BUTTONS { MouseKind::FiveButtons } else { MouseKind::Wheel })
+}
+
+impl MouseStream
+{
+    // resets and configures the mouse, then registers the IRQ 12 handler
+    pub fn new() -> Result<Self, Ps2Error>
+    {
+        let mut reply = [0; 2];
+        ps2::mouse_command(CMD_RESET, &[], &mut reply)?;
+        if reply != [SELF_TEST_PASSED, ID_STANDARD]
+        {
+            return Err(Ps2Error::Unexpected(reply[0]));
+        }
+        command(CMD_SET_DEFAULTS, &[])?;
+        let kind = detect_kind()?;
+        command(CMD_SET_SAMPLE_RATE, &[SAMPLE_RATE])?;
+
+        let bytes = IrqStream::register(IRQ, BYTE_QUEUE_SIZE, |queue|
+        {
+            let mut port = Port::new(0x60);
+            let byte: u8 = unsafe { port.read() };
+            queue.push(byte);
+            IrqReturn::Handled
+        });
+        command(CMD_ENABLE_REPORTING, &[])?;
+        Ok(MouseStream { bytes, kind, packet: [0; 4], received: 0, buttons: 0, pending: VecDeque::new() })
+    }
+
+    pub fn kind(&self) -> MouseKind
+    {
+        self.kind
+    }
+
+    // received and dropped bytes
+    pub fn stats(&self) -> IrqStreamStats
+    {
+        self.bytes.stats()
+    }
+
+    fn packet_len(&self) -> usize
+    {
+        if self.kind == MouseKind::Standard { 3 } else { 4 }
+    }
+
+    // collects one byte, decodes the packet once it is complete
+    fn add_byte(&mut self, byte: u8)
+    {
+        // late command replies (see ps2::mouse_command) would pass as first byte. Both have the
+        // overflow bits set, so no packet worth decoding starts with them
+        if self.received == 0 && (byte == ps2::ACK || byte == ps2::RESEND)
+        {
+            return;
+        }
+        // lost synchronization: wait for a plausible first byte
+        if self.received == 0 && byte & PACKET_ALWAYS_ONE == 0
+        {
+            return;
+        }
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received == self.packet_len()
+        {
+            self.received = 0;
+            self.decode();
+        }
+    }
+
+    fn decode(&mut self)
+    {
+        let [flags, x, y, extra] = self.packet;
+        // overflowed movement is meaningless, the packet is dropped
+        if flags & PACKET_OVERFLOW != 0
+        {
+            return;
+        }
+        let dx = i16::from(x) - if flags & PACKET_X_SIGN != 0 { 256 } else { 0 };
+        let dy = i16::from(y) - if flags & PACKET_Y_SIGN != 0 { 256 } else { 0 };
+        if dx != 0 || dy != 0
+        {
+            // PS/2 counts y upwards
+            self.pending.push_back(MouseEvent::Moved { dx, dy: -dy });
+        }
+
+        let mut buttons = flags & (PACKET_LEFT | PACKET_RIGHT | PACKET_MIDDLE);
+        let wheel = match self.kind
+        {
+            MouseKind::Standard => 0,
+            MouseKind::Wheel => extra as i8,
+            MouseKind::FiveButtons =>
+            {
+                buttons |= (extra >> 1) & 0b1_1000;     // bits 4, 5 -> buttons 4, 5 (bits 3, 4)
+                // 4 bit two's complement
+                ((extra << 4) as i8) >> 4
+            }
+        };
+        if wheel != 0
+        {
+            self.pending.push_back(MouseEvent::Scrolled(wheel));
+        }
+
+        let changed = buttons ^ self.buttons;
+        for (bit, &button) in BUTTONS.iter().enumerate()
+        {
+            if changed & (1 << bit) != 0
+            {
+                self.pending.push_back(if buttons & (1 << bit) != 0
+                {
+                    MouseEvent::ButtonDown(button)
+                }
+                else
+                {
+                    MouseEvent::ButtonUp(button)
+                });
+            }
+        }
+        self.buttons = buttons;
+    }
+}
+
+impl Stream for MouseStream
+{
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<MouseEvent>>
+    {
+        loop
+        {
+            if let Some(event) = self.pending.pop_front()
+            {
+                return Poll::Ready(Some(event));
+            }
+            match Pin::new(&mut self.bytes).poll_next(cx)
+            {
+                Poll::Ready(Some(byte)) => self.add_byte(byte),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
@@ -0,0 +1,293 @@
+// Serial port as console: input of a COM port as asynchronous stream and read_line on it
+//
+// SerialStream queues the received bytes in the interrupt handler. SerialKeys turns what a terminal
+// sends (characters, control characters, VT100 cursor key sequences) into the keys LineEditor knows,
+// so read_line works on the serial port like keyboard::read_line_from on the screen.
+// lets the kernel be driven headlessly, e.g. QEMU with -serial stdio.
+// new() uses the port with serial::Role::Console (COM1 if none has it), open(port) any other
+
+use alloc::string::String;
+use core::{pin::Pin, task::{Context, Poll}};
+use futures_util::stream::{Stream, StreamExt};
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::irq::{IrqStream, IrqStreamStats};
+use super::keyboard::{self, Edit, LineEditor, ReadLineError};
+use crate::interrupts::IrqReturn;
+use crate::vga_buffer::ansi::{Action, Parser};
+use crate::println;
+use crate::serial::{self, ComPort, Role};
+
+// bytes buffered between the interrupt and the reading task (a pasted line or two)
+const BYTE_QUEUE_SIZE: usize = 256;
+
+// read_line: longest line. Terminals wrap by themselves, the line does not have to fit into a row
+const MAX_LINE_LEN: usize = 256;
+
+const BACKSPACE: char = '\u{8}';
+
+// the console port: input of SerialStream::new and SerialKeys::new
+fn console_port() -> ComPort
+{
+    serial::port_for(Role::Console).unwrap_or(ComPort::Com1)
+}
+
+// Bytes received by a COM port as asynchronous stream. Only one per port should exist at a time
+// dropping it turns the receive interrupt off and removes the handler
+pub struct SerialStream
+{
+    bytes: IrqStream<u8>,
+    port: ComPort,
+}
+
+impl SerialStream
+{
+    // input of the console port
+    pub fn new() -> Self
+    {
+        SerialStream::open(console_port())
+    }
+
+    pub fn open(port: ComPort) -> Self
+    {
+        let bytes = IrqStream::register(port.irq(), BYTE_QUEUE_SIZE, move |queue|
+        {
+            // the FIFO may hold several bytes per interrupt
+            let mut handled = false;
+            while let Some(byte) = serial::try_receive(port)
+            {
+                queue.push(byte);
+                handled = true;
+            }
+            if handled { IrqReturn::Handled } else { IrqReturn::NotMine }
+        });
+        serial::set_receive_interrupt(port, true);
+        SerialStream { bytes, port }
+    }
+
+    pub fn port(&self) -> ComPort
+    {
+        self.port
+    }
+
+    pub fn stats(&self) -> IrqStreamStats
+    {
+        self.bytes.stats()
+    }
+}
+
+impl Drop for SerialStream
+{
+    fn drop(&mut self)
+    {
+        serial::set_receive_interrupt(self.port, false);
+    }
+}
+
+impl Stream for SerialStream
+{
+    type Item = u8;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>>
+    {
+        Pin::new(&mut self.bytes).poll_next(cx)
+    }
+}
+
+// Keys typed on a terminal connected to a COM port
+// Enter (CR, LF or CR LF) is '\n', DEL and BS are backspace, control characters stay what they are
+// (Ctrl-C is '\u{3}'), cursor keys, Home, End and Delete come as DecodedKey::RawKey
+pub struct SerialKeys
+{
+    bytes: SerialStream,
+    parser: Parser,
+    ss3: bool,      // ESC O: the next character names the key (application cursor mode)
+    after_cr: bool, // LF right after CR belongs to the same Enter
+}
+
+impl SerialKeys
+{
+    // keys of the console port
+    pub fn new() -> Self
+    {
+        SerialKeys::open(console_port())
+    }
+
+    pub fn open(port: ComPort) -> Self
+    {
+        SerialKeys { bytes: SerialStream::open(port), parser: Parser::new(), ss3: false, after_cr: false }
+    }
+
+    pub fn port(&self) -> ComPort
+    {
+        self.bytes.port()
+    }
+
+    pub fn stats(&self) -> IrqStreamStats
+    {
+        self.bytes.stats()
+    }
+
+    // decodes one byte. None for bytes that are part of a sequence or mean nothing
+    fn decode(&mut self, byte: u8) -> Option<DecodedKey>
+    {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        let ss3 = core::mem::replace(&mut self.ss3, false);
+        match self.parser.advance(byte)?
+        {
+            Action::Control(b'\n') if after_cr => None,
+            Action::Control(b'\r') | Action::Control(b'\n') => Some(DecodedKey::Unicode('\n')),
+            Action::Control(0x08) | Action::Control(0x7f) => Some(DecodedKey::Unicode(BACKSPACE)),
+            Action::Control(byte) => Some(DecodedKey::Unicode(char::from(byte))),
+            Action::Print(byte) if ss3 => cursor_key(byte).map(DecodedKey::RawKey),
+            Action::Print(byte) if byte.is_ascii() => Some(DecodedKey::Unicode(char::from(byte))),
+            // no UTF-8 decoding
+            Action::Print(_) => None,
+            Action::Escape(b'O') =>
+            {
+                self.ss3 = true;
+                None
+            }
+            Action::Escape(_) => None,
+            Action::Csi(csi) => match csi.action
+            {
+                b'~' => match csi.param(0, 0)
+                {
+                    1 | 7 => Some(DecodedKey::RawKey(KeyCode::Home)),
+                    3 => Some(DecodedKey::RawKey(KeyCode::Delete)),
+                    4 | 8 => Some(DecodedKey::RawKey(KeyCode::End)),
+                    _ => None,
+                },
+                action => cursor_key(action).map(DecodedKey::RawKey),
+            },
+        }
+    }
+}
+
+// final byte of the VT100 cursor key sequences (CSI or SS3)
+fn cursor_key(byte: u8) -> Option<KeyCode>
+{
+    match byte
+    {
+        b'A' => Some(KeyCode::ArrowUp),
+        b'B' => Some(KeyCode::ArrowDown),
+        b'C' => Some(KeyCode::ArrowRight),
+        b'D' => Some(KeyCode::ArrowLeft),
+        b'H' => Some(KeyCode::Home),
+        b'F' => Some(KeyCode::End),
+        _ => None,
+    }
+}
+
+impl Stream for SerialKeys
+{
+    type Item = DecodedKey;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>>
+    {
+        loop
+        {
+            match Pin::new(&mut self.bytes).poll_next(cx)
+            {
+                Poll::Ready(Some(byte)) =>
+                {
+                    if let Some(key) = self.decode(byte)
+                    {
+                        return Poll::Ready(Some(key));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+// terminal cell of a character. Non ASCII shows as '?'
+fn terminal_char(character: char) -> char
+{
+    if character.is_ascii() && !character.is_ascii_control() { character } else { '?' }
+}
+
+// redraws the line with VT100 sequences. The terminal cursor is `shown_cursor` characters into the
+// line before, at the editor's cursor afterwards
+fn redraw(port: ComPort, editor: &LineEditor, shown_cursor: usize)
+{
+    if shown_cursor > 0
+    {
+        serial::print_to_port(port, format_args!("\x1b[{}D", shown_cursor));
+    }
+    for &character in editor.chars()
+    {
+        serial::print_to_port(port, format_args!("{}", terminal_char(character)));
+    }
+    // erase the rest of a longer previous line
+    serial::print_to_port(port, format_args!("\x1b[K"));
+    let back = editor.chars().len() - editor.cursor();
+    if back > 0
+    {
+        serial::print_to_port(port, format_args!("\x1b[{}D", back));
+    }
+}
+
+// moves behind the line, prints `suffix` and a newline
+fn end_line(port: ComPort, editor: &LineEditor, suffix: &str)
+{
+    let forward = editor.chars().len() - editor.cursor();
+    if forward > 0
+    {
+        serial::print_to_port(port, format_args!("\x1b[{}C", forward));
+    }
+    serial::print_to_port(port, format_args!("{}\r\n", suffix));
+}
+
+// Reads one line from the terminal on the console port, with the editing keys and the history of keyboard::read_line_from
+// Returns the line without the newline, or ReadLineError::Interrupted on Ctrl-C.
+// No other task should read the port meanwhile
+pub async fn read_line() -> Result<String, ReadLineError>
+{
+    read_line_from(&mut SerialKeys::new()).await
+}
+
+// read_line with keys of an existing SerialKeys. For reading line after line: nothing typed ahead
+// gets lost between the lines
+pub async fn read_line_from(keys: &mut SerialKeys) -> Result<String, ReadLineError>
+{
+    let mut editor = LineEditor::new(MAX_LINE_LEN);
+
+    while let Some(key) = keys.next().await
+    {
+        let shown_cursor = editor.cursor();
+        match keyboard::with_history(|history| editor.handle_key(key, history))
+        {
+            Edit::Changed => redraw(keys.port(), &editor, shown_cursor),
+            Edit::Unchanged => {}
+            Edit::Submit(line) =>
+            {
+                keyboard::with_history(|history| history.push(&line));
+                end_line(keys.port(), &editor, "");
+                return Ok(line);
+            }
+            Edit::Cancel =>
+            {
+                end_line(keys.port(), &editor, "^C");
+                return Err(ReadLineError::Interrupted);
+            }
+        }
+    }
+    // serial stream never ends
+    Err(ReadLineError::Interrupted)
+}
+
+// prints every line entered on the serial console to the screen
+pub async fn print_lines()
+{
+    let mut keys = SerialKeys::new();
+    loop
+    {
+        if let Ok(line) = read_line_from(&mut keys).await
+        {
+            println!("serial: {}", line);
+        }
+    }
+}
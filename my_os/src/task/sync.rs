@@ -0,0 +1,207 @@
+// Async synchronization primitives for tasks
+// Mutex, RwLock, Semaphore, Notify and mpsc / oneshot / broadcast channels
+//
+// Everything here may also be used from interrupt handlers through the non blocking
+// calls (try_lock, try_send, notify_one, add_permits, ...). To make that safe on a single core,
+// internal spinlocks are only taken with interrupts disabled, so a handler can never spin on a
+// lock held by the task it interrupted. Calls that allocate (unbounded channels) are documented as such
+
+use alloc::collections::VecDeque;
+use core::task::Waker;
+use x86_64::instructions::interrupts;
+
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod notify;
+pub mod mpsc;
+pub mod oneshot;
+pub mod broadcast;
+
+pub use mutex::{Mutex, MutexGuard, MutexLock};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard, RwLockRead, RwLockWrite};
+pub use semaphore::{Semaphore, SemaphorePermit, Acquire};
+pub use notify::{Notify, Notified};
+
+// locks `lock` with interrupts disabled and runs f on the protected value
+fn lock_irq<T, R>(lock: &spin::Mutex<T>, f: impl FnOnce(&mut T) -> R) -> R
+{
+    interrupts::without_interrupts(|| f(&mut lock.lock()))
+}
+
+// List of tasks waiting for a primitive to change state
+// every waiting future owns a key into the list, so it can update its waker on re-poll
+// and remove itself when dropped before being woken. Woken entries stay in the list until their
+// future polls or drops again, remembering whether wake_one picked them (see take_notified)
+struct WaitList
+{
+    inner: spin::Mutex<WaitListInner>,
+}
+
+struct WaitListInner
+{
+    next_key: u64,
+    waiters: VecDeque<Waiter>,
+}
+
+struct Waiter
+{
+    key: u64,
+    waker: Option<Waker>,   // None once woken
+    notified: bool,         // woken by wake_one, not wake_all
+}
+
+impl WaitList
+{
+    const fn new() -> Self
+    {
+        WaitList
+        {
+            inner: spin::Mutex::new(WaitListInner { next_key: 0, waiters: VecDeque::new() }),
+        }
+    }
+
+    // registers (or updates) the waker of the future owning `key`
+    // a future woken before waits again at the back of the list
+    fn register(&self, key: &mut Option<u64>, waker: &Waker)
+    {
+        lock_irq(&self.inner, |inner|
+        {
+            if let Some(k) = *key
+            {
+                if let Some(index) = inner.waiters.iter().position(|waiter| waiter.key == k)
+                {
+                    match &mut inner.waiters[index].waker
+                    {
+                        Some(entry) =>
+                        {
+                            if !entry.will_wake(waker)
+                            {
+                                *entry = waker.clone();
+                            }
+                            return;
+                        }
+                        None =>
+                        {
+                            inner.waiters.remove(index);
+                        }
+                    }
+                }
+            }
+            let k = inner.next_key;
+            inner.next_key += 1;
+            inner.waiters.push_back(Waiter { key: k, waker: Some(waker.clone()), notified: false });
+            *key = Some(k);
+        });
+    }
+
+    // removes entry of a future that stops waiting
+    // returns false if wake_one picked it (so it may have consumed a wakeup meant for someone)
+    fn remove(&self, key: u64) -> bool
+    {
+        lock_irq(&self.inner, |inner|
+        {
+            match inner.waiters.iter().position(|waiter| waiter.key == key)
+            {
+                Some(index) => inner.waiters.remove(index).map_or(false, |waiter| !waiter.notified),
+                None => false,
+            }
+        })
+    }
+
+    // whether wake_one picked the future owning `key`. If so, removes its entry and clears `key`
+    fn take_notified(&self, key: &mut Option<u64>) -> bool
+    {
+        let k = match *key
+        {
+            Some(k) => k,
+            None => return false,
+        };
+        let notified = lock_irq(&self.inner, |inner|
+        {
+            match inner.waiters.iter().position(|waiter| waiter.key == k && waiter.notified)
+            {
+                Some(index) =>
+                {
+                    inner.waiters.remove(index);
+                    true
+                }
+                None => false,
+            }
+        });
+        if notified
+        {
+            *key = None;
+        }
+        notified
+    }
+
+    // unregisters a dropped future. If its wakeup was never used, pass it on to the next waiter
+    fn cancel(&self, key: &mut Option<u64>)
+    {
+        if let Some(k) = key.take()
+        {
+            if !self.remove(k)
+            {
+                self.wake_one();
+            }
+        }
+    }
+
+    // wakes the longest waiting future and marks it notified. Returns false if nobody waits
+    fn wake_one(&self) -> bool
+    {
+        self.wake_one_or(|| {})
+    }
+
+    // wake_one, or runs `none_waiting` under the list's lock if nobody waits. So a future that
+    // registers and then checks what `none_waiting` did cannot miss both
+    fn wake_one_or(&self, none_waiting: impl FnOnce()) -> bool
+    {
+        let waker = lock_irq(&self.inner, |inner|
+        {
+            match inner.waiters.iter_mut().find(|waiter| waiter.waker.is_some())
+            {
+                Some(waiter) =>
+                {
+                    waiter.notified = true;
+                    waiter.waker.take()
+                }
+                None =>
+                {
+                    none_waiting();
+                    None
+                }
+            }
+        });
+        // wake outside the lock: waking may run arbitrary waker code
+        match waker
+        {
+            Some(waker) =>
+            {
+                waker.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // wakes every future waiting right now, without marking them notified
+    // Does not allocate, so usable from interrupt handlers
+    fn wake_all(&self)
+    {
+        let count = lock_irq(&self.inner, |inner| inner.waiters.len());
+        for _ in 0..count
+        {
+            let waker = lock_irq(&self.inner, |inner|
+            {
+                inner.waiters.iter_mut().find_map(|waiter| waiter.waker.take())
+            });
+            match waker
+            {
+                Some(waker) => waker.wake(),
+                None => break,
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+// Multi producer, multi consumer channel where every receiver sees every value
+// values are kept in a ring of fixed capacity. A receiver that falls more than `capacity`
+// values behind loses the oldest ones and gets RecvError::Lagged with the number it missed
+// Sender::send does not allocate and can be called from interrupt handlers
+
+use super::{lock_irq, WaitList};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+// value could not be sent because there are no receivers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError
+{
+    Lagged(u64),    // receiver fell behind, this many values were skipped. Next recv continues with the oldest kept one
+    Closed,         // all senders dropped and every value received
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError
+{
+    Empty,
+    Lagged(u64),
+    Closed,
+}
+
+struct Ring<T>
+{
+    values: VecDeque<(u64, T)>, // (sequence number, value), oldest first
+    capacity: usize,
+    next_seq: u64,              // sequence number of the next sent value
+}
+
+struct Shared<T>
+{
+    ring: spin::Mutex<Ring<T>>,
+    waiters: WaitList,          // receivers waiting for the next value
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+}
+
+// Creates a channel keeping the last `capacity` values. Panics if capacity is 0
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>)
+{
+    assert!(capacity > 0, "broadcast channel capacity must not be 0");
+    let shared = Arc::new(Shared
+    {
+        // allocate the whole ring up front. send never grows it
+        ring: spin::Mutex::new(Ring { values: VecDeque::with_capacity(capacity), capacity, next_seq: 0 }),
+        waiters: WaitList::new(),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared, next: 0 })
+}
+
+pub struct Sender<T>
+{
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Clone> Sender<T>
+{
+    // sends value to all current receivers and returns how many there are
+    // fails if there are none
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>>
+    {
+        let receivers = self.shared.receivers.load(Ordering::Acquire);
+        if receivers == 0
+        {
+            return Err(SendError(value));
+        }
+        // the overwritten value is dropped outside the lock
+        let dropped = lock_irq(&self.shared.ring, |ring|
+        {
+            let dropped = if ring.values.len() == ring.capacity { ring.values.pop_front() } else { None };
+            let seq = ring.next_seq;
+            ring.values.push_back((seq, value));
+            ring.next_seq += 1;
+            dropped
+        });
+        drop(dropped);
+        self.shared.waiters.wake_all();
+        Ok(receivers)
+    }
+
+    // creates a receiver that sees all values sent from now on
+    pub fn subscribe(&self) -> Receiver<T>
+    {
+        self.shared.receivers.fetch_add(1, Ordering::AcqRel);
+        let next = lock_irq(&self.shared.ring, |ring| ring.next_seq);
+        Receiver { shared: self.shared.clone(), next }
+    }
+
+    pub fn receiver_count(&self) -> usize
+    {
+        self.shared.receivers.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T>
+{
+    fn clone(&self) -> Self
+    {
+        self.shared.senders.fetch_add(1, Ordering::Relaxed);
+        Sender { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T>
+{
+    fn drop(&mut self)
+    {
+        // last sender gone -> waiting receivers return Closed
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1
+        {
+            self.shared.waiters.wake_all();
+        }
+    }
+}
+
+pub struct Receiver<T>
+{
+    shared: Arc<Shared<T>>,
+    next: u64,  // sequence number of the next value to receive
+}
+
+impl<T: Clone> Receiver<T>
+{
+    pub fn recv(&mut self) -> Recv<T>
+    {
+        Recv { receiver: self, key: None }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError>
+    {
+        let next = self.next;
+        let result = lock_irq(&self.shared.ring, |ring|
+        {
+            let oldest = ring.values.front().map_or(ring.next_seq, |(seq, _)| *seq);
+            if next < oldest
+            {
+                return Err((oldest, TryRecvError::Lagged(oldest - next)));
+            }
+            if next < ring.next_seq
+            {
+                let value = ring.values[(next - oldest) as usize].1.clone();
+                return Ok(value);
+            }
+            Err((next, TryRecvError::Empty))
+        });
+        match result
+        {
+            Ok(value) =>
+            {
+                self.next += 1;
+                Ok(value)
+            }
+            Err((next, TryRecvError::Empty)) =>
+            {
+                self.next = next;
+                if self.shared.senders.load(Ordering::Acquire) == 0
+                {
+                    Err(TryRecvError::Closed)
+                }
+                else
+                {
+                    Err(TryRecvError::Empty)
+                }
+            }
+            Err((next, err)) =>
+            {
+                self.next = next;
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T>
+{
+    fn drop(&mut self)
+    {
+        self.shared.receivers.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Future returned by Receiver::recv
+pub struct Recv<'a, T>
+{
+    receiver: &'a mut Receiver<T>,
+    key: Option<u64>,
+}
+
+impl<'a, T: Clone> Recv<'a, T>
+{
+    fn poll_once(&mut self) -> Poll<Result<T, RecvError>>
+    {
+        match self.receiver.try_recv()
+        {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Lagged(n)) => Poll::Ready(Err(RecvError::Lagged(n))),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError::Closed)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: Clone> Future for Recv<'a, T>
+{
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>>
+    {
+        let this = &mut *self;
+        if this.key.is_none()
+        {
+            if let Poll::Ready(result) = this.poll_once()
+            {
+                return Poll::Ready(result);
+            }
+        }
+        // register first, then check again: a send between the two calls is not lost
+        this.receiver.shared.waiters.register(&mut this.key, cx.waker());
+        let result = this.poll_once();
+        if result.is_ready()
+        {
+            if let Some(key) = this.key.take()
+            {
+                this.receiver.shared.waiters.remove(key);
+            }
+        }
+        result
+    }
+}
+
+impl<'a, T> Drop for Recv<'a, T>
+{
+    fn drop(&mut self)
+    {
+        // send wakes every receiver, no wakeup to pass on
+        if let Some(key) = self.key.take()
+        {
+            self.receiver.shared.waiters.remove(key);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+// Multi producer, single consumer channels
+//
+// channel(capacity): bounded. Sender::try_send does not allocate or block and can be
+// called from interrupt handlers. Sender::send().await waits for free space instead
+// unbounded_channel(): UnboundedSender::send never waits, but allocates (not for interrupt handlers)
+
+use super::WaitList;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+// value could not be sent because the receiver was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T>
+{
+    Full(T),    // channel at capacity. Only returned by bounded channels
+    Closed(T),  // receiver dropped
+}
+
+impl<T> TrySendError<T>
+{
+    // gets the value that could not be sent back
+    pub fn into_inner(self) -> T
+    {
+        match self
+        {
+            TrySendError::Full(value) | TrySendError::Closed(value) => value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError
+{
+    Empty,          // nothing queued right now
+    Disconnected,   // nothing queued and all senders dropped
+}
+
+enum Buffer<T>
+{
+    Bounded(ArrayQueue<T>),
+    Unbounded(SegQueue<T>),
+}
+
+// shared between all senders and the receiver
+struct Chan<T>
+{
+    buffer: Buffer<T>,
+    rx_waker: AtomicWaker,      // receiver waiting for a value
+    send_waiters: WaitList,     // senders waiting for free space (bounded only)
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+impl<T> Chan<T>
+{
+    fn new(buffer: Buffer<T>) -> Arc<Self>
+    {
+        Arc::new(Chan
+        {
+            buffer,
+            rx_waker: AtomicWaker::new(),
+            send_waiters: WaitList::new(),
+            senders: AtomicUsize::new(1),
+            receiver_alive: AtomicBool::new(true),
+        })
+    }
+
+    fn try_send(&self, value: T) -> Result<(), TrySendError<T>>
+    {
+        if !self.receiver_alive.load(Ordering::Acquire)
+        {
+            return Err(TrySendError::Closed(value));
+        }
+        match &self.buffer
+        {
+            Buffer::Bounded(queue) => queue.push(value).map_err(TrySendError::Full)?,
+            Buffer::Unbounded(queue) => queue.push(value),
+        }
+        // wake only after pushing, so the receiver finds the value
+        self.rx_waker.wake();
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<T>
+    {
+        let value = match &self.buffer
+        {
+            Buffer::Bounded(queue) => queue.pop(),
+            Buffer::Unbounded(queue) => queue.pop(),
+        };
+        if value.is_some()
+        {
+            // one slot became free
+            self.send_waiters.wake_one();
+        }
+        value
+    }
+
+    fn len(&self) -> usize
+    {
+        match &self.buffer
+        {
+            Buffer::Bounded(queue) => queue.len(),
+            Buffer::Unbounded(queue) => queue.len(),
+        }
+    }
+
+    fn add_sender(&self)
+    {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn drop_sender(&self)
+    {
+        // last sender gone -> receiver must see the channel as closed
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1
+        {
+            self.rx_waker.wake();
+        }
+    }
+}
+
+// Creates a channel that holds at most `capacity` values. Panics if capacity is 0
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>)
+{
+    assert!(capacity > 0, "mpsc channel capacity must not be 0");
+    let chan = Chan::new(Buffer::Bounded(ArrayQueue::new(capacity)));
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+// Creates a channel without capacity limit
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>)
+{
+    let chan = Chan::new(Buffer::Unbounded(SegQueue::new()));
+    (UnboundedSender { chan: chan.clone() }, Receiver { chan })
+}
+
+// Sending half of a bounded channel. Clone it to get more producers
+pub struct Sender<T>
+{
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Sender<T>
+{
+    // non blocking and no allocation. Usable from interrupt handlers
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>>
+    {
+        self.chan.try_send(value)
+    }
+
+    // waits until there is space in the channel
+    pub fn send(&self, value: T) -> SendFuture<T>
+    {
+        SendFuture { chan: &self.chan, value: Some(value), key: None }
+    }
+
+    pub fn is_closed(&self) -> bool
+    {
+        !self.chan.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T>
+{
+    fn clone(&self) -> Self
+    {
+        self.chan.add_sender();
+        Sender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T>
+{
+    fn drop(&mut self)
+    {
+        self.chan.drop_sender();
+    }
+}
+
+// Future returned by Sender::send
+pub struct SendFuture<'a, T>
+{
+    chan: &'a Chan<T>,
+    value: Option<T>,   // None once sent
+    key: Option<u64>,
+}
+
+// value is never pinned, it is only moved into the queue
+impl<'a, T> Unpin for SendFuture<'a, T> {}
+
+impl<'a, T> Future for SendFuture<'a, T>
+{
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), SendError<T>>>
+    {
+        let this = &mut *self;
+        let mut value = this.value.take().expect("SendFuture polled after completion");
+        let mut registered = false;
+        loop
+        {
+            match this.chan.try_send(value)
+            {
+                Ok(()) =>
+                {
+                    if let Some(key) = this.key.take()
+                    {
+                        this.chan.send_waiters.remove(key);
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Err(TrySendError::Closed(v)) =>
+                {
+                    if let Some(key) = this.key.take()
+                    {
+                        this.chan.send_waiters.remove(key);
+                    }
+                    return Poll::Ready(Err(SendError(v)));
+                }
+                Err(TrySendError::Full(v)) if registered =>
+                {
+                    this.value = Some(v);
+                    return Poll::Pending;
+                }
+                Err(TrySendError::Full(v)) =>
+                {
+                    // register first, then try again: a recv between the two calls is not lost
+                    this.chan.send_waiters.register(&mut this.key, cx.waker());
+                    registered = true;
+                    value = v;
+                }
+            }
+        }
+    }
+}
+
+impl<'a, T> Drop for SendFuture<'a, T>
+{
+    fn drop(&mut self)
+    {
+        self.chan.send_waiters.cancel(&mut self.key);
+    }
+}
+
+// Sending half of an unbounded channel. Clone it to get more producers
+pub struct UnboundedSender<T>
+{
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> UnboundedSender<T>
+{
+    // never waits, but may allocate. Not usable from interrupt handlers
+    pub fn send(&self, value: T) -> Result<(), SendError<T>>
+    {
+        self.chan.try_send(value).map_err(|err| SendError(err.into_inner()))
+    }
+
+    pub fn is_closed(&self) -> bool
+    {
+        !self.chan.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Clone for UnboundedSender<T>
+{
+    fn clone(&self) -> Self
+    {
+        self.chan.add_sender();
+        UnboundedSender { chan: self.chan.clone() }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T>
+{
+    fn drop(&mut self)
+    {
+        self.chan.drop_sender();
+    }
+}
+
+// Receiving half of a channel (bounded or unbounded)
+pub struct Receiver<T>
+{
+    chan: Arc<Chan<T>>,
+}
+
+impl<T> Receiver<T>
+{
+    // waits for the next value. None once all senders are dropped and the channel is empty
+    pub fn recv(&mut self) -> Recv<T>
+    {
+        Recv { receiver: self }
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError>
+    {
+        if let Some(value) = self.chan.pop()
+        {
+            return Ok(value);
+        }
+        if self.chan.senders.load(Ordering::Acquire) == 0
+        {
+            // a sender may have pushed right before dropping
+            return self.chan.pop().ok_or(TryRecvError::Disconnected);
+        }
+        Err(TryRecvError::Empty)
+    }
+
+    // number of values queued right now
+    pub fn len(&self) -> usize
+    {
+        self.chan.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>>
+    {
+        // fast path
+        match self.try_recv()
+        {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        // register waker, then check again (same pattern as ScancodeStream)
+        self.chan.rx_waker.register(cx.waker());
+        match self.try_recv()
+        {
+            Ok(value) =>
+            {
+                self.chan.rx_waker.take();
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T>
+{
+    fn drop(&mut self)
+    {
+        self.chan.receiver_alive.store(false, Ordering::Release);
+        // blocked senders return SendError
+        self.chan.send_waiters.wake_all();
+    }
+}
+
+impl<T> Stream for Receiver<T>
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>>
+    {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+// Future returned by Receiver::recv
+pub struct Recv<'a, T>
+{
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<'a, T> Future for Recv<'a, T>
+{
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<T>>
+    {
+        self.receiver.poll_recv(cx)
+    }
+}
@@ -0,0 +1,138 @@
+use super::WaitList;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+
+// Async mutex: lock().await suspends the task instead of spinning while another task holds the lock
+// holding the guard across .await points is fine (unlike spin::Mutex)
+pub struct Mutex<T>
+{
+    locked: AtomicBool,
+    waiters: WaitList,
+    value: UnsafeCell<T>,
+}
+
+// access to value is serialized through `locked`
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T>
+{
+    pub const fn new(value: T) -> Self
+    {
+        Mutex
+        {
+            locked: AtomicBool::new(false),
+            waiters: WaitList::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexLock<T>
+    {
+        MutexLock { mutex: self, key: None }
+    }
+
+    // non blocking. Usable from interrupt handlers
+    pub fn try_lock(&self) -> Option<MutexGuard<T>>
+    {
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        {
+            Some(MutexGuard { mutex: self, _marker: PhantomData })
+        }
+        else
+        {
+            None
+        }
+    }
+
+    pub fn into_inner(self) -> T
+    {
+        self.value.into_inner()
+    }
+}
+
+// Future returned by Mutex::lock
+pub struct MutexLock<'a, T>
+{
+    mutex: &'a Mutex<T>,
+    key: Option<u64>,   // entry in mutex.waiters while waiting
+}
+
+impl<'a, T> Future for MutexLock<'a, T>
+{
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<MutexGuard<'a, T>>
+    {
+        let mutex = self.mutex;
+        if let Some(guard) = mutex.try_lock()
+        {
+            if let Some(key) = self.key.take()
+            {
+                mutex.waiters.remove(key);
+            }
+            return Poll::Ready(guard);
+        }
+
+        // register first, then try again: an unlock between the two calls is not lost
+        mutex.waiters.register(&mut self.key, cx.waker());
+        match mutex.try_lock()
+        {
+            Some(guard) =>
+            {
+                if let Some(key) = self.key.take()
+                {
+                    mutex.waiters.remove(key);
+                }
+                Poll::Ready(guard)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T> Drop for MutexLock<'a, T>
+{
+    fn drop(&mut self)
+    {
+        self.mutex.waiters.cancel(&mut self.key);
+    }
+}
+
+pub struct MutexGuard<'a, T>
+{
+    mutex: &'a Mutex<T>,
+    _marker: PhantomData<&'a mut T>,    // guard is only Sync if T is (it hands out &T)
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T>
+{
+    fn deref_mut(&mut self) -> &mut T
+    {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T>
+{
+    fn drop(&mut self)
+    {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
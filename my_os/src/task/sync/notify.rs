@@ -0,0 +1,121 @@
+use super::WaitList;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+// Wakes tasks waiting for an event without carrying data
+// notify_one hands its notification to the longest waiting task, or stores a single permit if
+// nobody waits, so a notification sent before notified().await is not lost.
+// notify_waiters only wakes tasks already waiting
+// both calls do not allocate and can be used from interrupt handlers
+pub struct Notify
+{
+    permit: AtomicBool,
+    generation: AtomicU64,  // bumped by notify_waiters
+    waiters: WaitList,
+}
+
+impl Notify
+{
+    pub const fn new() -> Self
+    {
+        Notify
+        {
+            permit: AtomicBool::new(false),
+            generation: AtomicU64::new(0),
+            waiters: WaitList::new(),
+        }
+    }
+
+    // wakes one waiting task, or stores a permit for the next call of notified()
+    pub fn notify_one(&self)
+    {
+        self.waiters.wake_one_or(|| self.permit.store(true, Ordering::Release));
+    }
+
+    // wakes all tasks currently waiting. Does not store a permit
+    pub fn notify_waiters(&self)
+    {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    pub fn notified(&self) -> Notified
+    {
+        Notified
+        {
+            notify: self,
+            generation: self.generation.load(Ordering::Acquire),
+            key: None,
+        }
+    }
+}
+
+impl Default for Notify
+{
+    fn default() -> Self
+    {
+        Notify::new()
+    }
+}
+
+// Future returned by Notify::notified
+pub struct Notified<'a>
+{
+    notify: &'a Notify,
+    generation: u64,    // notify_waiters calls seen when created
+    key: Option<u64>,
+}
+
+impl<'a> Notified<'a>
+{
+    // a notification picked for this future comes first, the shared permit only if there is none
+    fn is_notified(&mut self) -> bool
+    {
+        self.notify.generation.load(Ordering::Acquire) != self.generation
+            || self.notify.waiters.take_notified(&mut self.key)
+            || self.notify.permit.swap(false, Ordering::Acquire)
+    }
+
+    // leaves the wait list. A notify_one that picked this future but was not used goes to the next one
+    fn release(&mut self)
+    {
+        if let Some(key) = self.key.take()
+        {
+            if !self.notify.waiters.remove(key)
+            {
+                self.notify.notify_one();
+            }
+        }
+    }
+}
+
+impl<'a> Future for Notified<'a>
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>
+    {
+        let notify = self.notify;
+        if !self.is_notified()
+        {
+            // register first, then check again: a notification between the two calls is not lost
+            notify.waiters.register(&mut self.key, cx.waker());
+            if !self.is_notified()
+            {
+                return Poll::Pending;
+            }
+        }
+        self.release();
+        Poll::Ready(())
+    }
+}
+
+impl<'a> Drop for Notified<'a>
+{
+    fn drop(&mut self)
+    {
+        self.release();
+    }
+}
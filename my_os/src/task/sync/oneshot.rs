@@ -0,0 +1,154 @@
+// Channel for sending exactly one value, e.g. a reply to a request
+// Sender::send does not allocate and can be called from interrupt handlers
+
+use super::lock_irq;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use futures_util::task::AtomicWaker;
+
+// sender was dropped without sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError
+{
+    Empty,  // not sent yet
+    Closed, // sender dropped without sending, or value already received
+}
+
+enum State<T>
+{
+    Empty,
+    Value(T),
+    Closed,
+}
+
+struct Inner<T>
+{
+    state: spin::Mutex<State<T>>,
+    rx_waker: AtomicWaker,
+    receiver_alive: AtomicBool,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>)
+{
+    let inner = Arc::new(Inner
+    {
+        state: spin::Mutex::new(State::Empty),
+        rx_waker: AtomicWaker::new(),
+        receiver_alive: AtomicBool::new(true),
+    });
+    (Sender { inner: inner.clone() }, Receiver { inner })
+}
+
+pub struct Sender<T>
+{
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Sender<T>
+{
+    // hands the value to the receiver. Gives it back if the receiver was dropped
+    pub fn send(self, value: T) -> Result<(), T>
+    {
+        if !self.inner.receiver_alive.load(Ordering::Acquire)
+        {
+            return Err(value);
+        }
+        lock_irq(&self.inner.state, |state| *state = State::Value(value));
+        self.inner.rx_waker.wake();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool
+    {
+        !self.inner.receiver_alive.load(Ordering::Acquire)
+    }
+}
+
+impl<T> Drop for Sender<T>
+{
+    fn drop(&mut self)
+    {
+        // also runs after send. Only close the channel if nothing was sent
+        let closed = lock_irq(&self.inner.state, |state|
+        {
+            if let State::Empty = state
+            {
+                *state = State::Closed;
+                true
+            }
+            else
+            {
+                false
+            }
+        });
+        if closed
+        {
+            self.inner.rx_waker.wake();
+        }
+    }
+}
+
+// Receiving half. Await it to get the value
+pub struct Receiver<T>
+{
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Receiver<T>
+{
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError>
+    {
+        lock_irq(&self.inner.state, |state|
+        {
+            match core::mem::replace(state, State::Closed)
+            {
+                State::Value(value) => Ok(value),
+                State::Empty =>
+                {
+                    *state = State::Empty;
+                    Err(TryRecvError::Empty)
+                }
+                State::Closed => Err(TryRecvError::Closed),
+            }
+        })
+    }
+}
+
+impl<T> Future for Receiver<T>
+{
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, RecvError>>
+    {
+        let this = self.get_mut();
+        match this.try_recv()
+        {
+            Ok(value) => return Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        // register waker, then check again: a send between the two calls is not lost
+        this.inner.rx_waker.register(cx.waker());
+        match this.try_recv()
+        {
+            Ok(value) => Poll::Ready(Ok(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T>
+{
+    fn drop(&mut self)
+    {
+        self.inner.receiver_alive.store(false, Ordering::Release);
+    }
+}
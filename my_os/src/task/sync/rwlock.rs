@@ -0,0 +1,250 @@
+use super::WaitList;
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+// highest bit of state: a writer holds the lock. lower bits: number of readers
+const WRITER: usize = 1 << (usize::BITS - 1);
+
+// Async reader-writer lock: any number of readers or a single writer
+// writers are preferred: while one waits, new readers wait too, so a steady stream of readers cannot
+// starve it. A task that already reads must not read again while a writer may be waiting (deadlock)
+pub struct RwLock<T>
+{
+    state: AtomicUsize,
+    writers_waiting: AtomicUsize,   // RwLockWrite futures registered in `writers`
+    readers: WaitList,  // tasks waiting in read()
+    writers: WaitList,  // tasks waiting in write()
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T>
+{
+    pub const fn new(value: T) -> Self
+    {
+        RwLock
+        {
+            state: AtomicUsize::new(0),
+            writers_waiting: AtomicUsize::new(0),
+            readers: WaitList::new(),
+            writers: WaitList::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RwLockRead<T>
+    {
+        RwLockRead { lock: self, key: None }
+    }
+
+    pub fn write(&self) -> RwLockWrite<T>
+    {
+        RwLockWrite { lock: self, key: None }
+    }
+
+    // non blocking. Usable from interrupt handlers. Fails while a writer holds the lock or waits for it
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>>
+    {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop
+        {
+            if state & WRITER != 0 || self.writers_waiting.load(Ordering::Acquire) > 0
+            {
+                return None;
+            }
+            match self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    // non blocking. Usable from interrupt handlers
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>>
+    {
+        if self.state.compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed).is_ok()
+        {
+            Some(RwLockWriteGuard { lock: self, _marker: PhantomData })
+        }
+        else
+        {
+            None
+        }
+    }
+}
+
+// Future returned by RwLock::read
+pub struct RwLockRead<'a, T>
+{
+    lock: &'a RwLock<T>,
+    key: Option<u64>,
+}
+
+impl<'a, T> Future for RwLockRead<'a, T>
+{
+    type Output = RwLockReadGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockReadGuard<'a, T>>
+    {
+        let lock = self.lock;
+        if self.key.is_none()
+        {
+            if let Some(guard) = lock.try_read()
+            {
+                return Poll::Ready(guard);
+            }
+        }
+        // register first, then try again: an unlock between the two calls is not lost
+        lock.readers.register(&mut self.key, cx.waker());
+        match lock.try_read()
+        {
+            Some(guard) =>
+            {
+                if let Some(key) = self.key.take()
+                {
+                    lock.readers.remove(key);
+                }
+                Poll::Ready(guard)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T> Drop for RwLockRead<'a, T>
+{
+    fn drop(&mut self)
+    {
+        // readers are always woken all at once, nothing to hand on
+        if let Some(key) = self.key.take()
+        {
+            self.lock.readers.remove(key);
+        }
+    }
+}
+
+// Future returned by RwLock::write
+pub struct RwLockWrite<'a, T>
+{
+    lock: &'a RwLock<T>,
+    key: Option<u64>,
+}
+
+impl<'a, T> Future for RwLockWrite<'a, T>
+{
+    type Output = RwLockWriteGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<RwLockWriteGuard<'a, T>>
+    {
+        let lock = self.lock;
+        if self.key.is_none()
+        {
+            if let Some(guard) = lock.try_write()
+            {
+                return Poll::Ready(guard);
+            }
+        }
+        if self.key.is_none()
+        {
+            // from now on new readers wait as well
+            lock.writers_waiting.fetch_add(1, Ordering::AcqRel);
+        }
+        lock.writers.register(&mut self.key, cx.waker());
+        match lock.try_write()
+        {
+            Some(guard) =>
+            {
+                if let Some(key) = self.key.take()
+                {
+                    lock.writers.remove(key);
+                    // readers stay out because of WRITER, the guard wakes them
+                    lock.writers_waiting.fetch_sub(1, Ordering::AcqRel);
+                }
+                Poll::Ready(guard)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T> Drop for RwLockWrite<'a, T>
+{
+    fn drop(&mut self)
+    {
+        // last waiting writer gives up: readers held back only by it may go
+        if self.key.is_some() && self.lock.writers_waiting.fetch_sub(1, Ordering::AcqRel) == 1
+        {
+            self.lock.readers.wake_all();
+        }
+        self.lock.writers.cancel(&mut self.key);
+    }
+}
+
+pub struct RwLockReadGuard<'a, T>
+{
+    lock: &'a RwLock<T>,
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T>
+{
+    fn drop(&mut self)
+    {
+        // last reader leaves -> a writer may proceed
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1
+        {
+            self.lock.writers.wake_one();
+        }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T>
+{
+    lock: &'a RwLock<T>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T>
+{
+    fn deref_mut(&mut self) -> &mut T
+    {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T>
+{
+    fn drop(&mut self)
+    {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.readers.wake_all();
+        self.lock.writers.wake_one();
+    }
+}
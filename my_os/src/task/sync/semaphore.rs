@@ -0,0 +1,137 @@
+use super::WaitList;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+
+// Counting semaphore: acquire().await waits until one of `permits` is available
+// add_permits does not allocate, so an interrupt handler can hand out permits (e.g. one per received packet)
+pub struct Semaphore
+{
+    permits: AtomicUsize,
+    waiters: WaitList,
+}
+
+impl Semaphore
+{
+    pub const fn new(permits: usize) -> Self
+    {
+        Semaphore
+        {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitList::new(),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize
+    {
+        self.permits.load(Ordering::Acquire)
+    }
+
+    pub fn acquire(&self) -> Acquire
+    {
+        Acquire { semaphore: self, key: None }
+    }
+
+    // non blocking. Usable from interrupt handlers
+    pub fn try_acquire(&self) -> Option<SemaphorePermit>
+    {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+        loop
+        {
+            if permits == 0
+            {
+                return None;
+            }
+            match self.permits.compare_exchange_weak(permits, permits - 1, Ordering::Acquire, Ordering::Relaxed)
+            {
+                Ok(_) => return Some(SemaphorePermit { semaphore: self }),
+                Err(current) => permits = current,
+            }
+        }
+    }
+
+    // returns n permits and wakes up to n waiting tasks
+    pub fn add_permits(&self, n: usize)
+    {
+        self.permits.fetch_add(n, Ordering::Release);
+        for _ in 0..n
+        {
+            if !self.waiters.wake_one()
+            {
+                break;
+            }
+        }
+    }
+}
+
+// Future returned by Semaphore::acquire
+pub struct Acquire<'a>
+{
+    semaphore: &'a Semaphore,
+    key: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a>
+{
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<SemaphorePermit<'a>>
+    {
+        let semaphore = self.semaphore;
+        if let Some(permit) = semaphore.try_acquire()
+        {
+            if let Some(key) = self.key.take()
+            {
+                semaphore.waiters.remove(key);
+            }
+            return Poll::Ready(permit);
+        }
+
+        // register first, then try again: a release between the two calls is not lost
+        semaphore.waiters.register(&mut self.key, cx.waker());
+        match semaphore.try_acquire()
+        {
+            Some(permit) =>
+            {
+                if let Some(key) = self.key.take()
+                {
+                    semaphore.waiters.remove(key);
+                }
+                Poll::Ready(permit)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<'a> Drop for Acquire<'a>
+{
+    fn drop(&mut self)
+    {
+        self.semaphore.waiters.cancel(&mut self.key);
+    }
+}
+
+// One acquired permit. Returned to the semaphore on drop
+pub struct SemaphorePermit<'a>
+{
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> SemaphorePermit<'a>
+{
+    // consumes the permit without returning it, shrinking the semaphore by one
+    pub fn forget(self)
+    {
+        core::mem::forget(self);
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a>
+{
+    fn drop(&mut self)
+    {
+        self.semaphore.add_permits(1);
+    }
+}
@@ -0,0 +1,271 @@
+// Monotonic clock for the kernel
+//
+// time::now() returns an Instant with nanosecond resolution. It reads the current ClockSource:
+// the TSC, whose frequency is calibrated at boot against PIT channel 2, or the HPET main counter
+// once hpet::init found one. Until calibration the PIT tick count of channel 0 is used,
+// with the resolution of one tick. Switching sources never makes now() jump.
+//
+// PIT channel 0 drives IRQ 0 at TICK_FREQUENCY (see pit::set_frequency)
+//
+// timer::sleep / timer::timeout wait for a point in time. The executor wakes expired timers
+//
+// wall_clock() is the UTC date and time: read from the CMOS RTC at boot and advanced with now()
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+pub mod pit;
+pub mod tsc;
+pub mod rtc;
+pub mod hpet;
+pub mod timer;
+mod datetime;
+
+pub use datetime::DateTime;
+
+// PIT channel 0 frequency set up by init. Timer interrupts per second
+pub const TICK_FREQUENCY: u32 = 100;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Programs the PIT, calibrates the TSC and reads the wall clock from the RTC
+// called from crate::init with interrupts disabled. Busy waits for a few milliseconds
+pub fn init()
+{
+    pit::set_frequency(TICK_FREQUENCY);
+    if tsc::calibrate() > 0
+    {
+        set_clock_source(ClockSource::Tsc);
+    }
+    sync_wall_clock();
+}
+
+// Hardware counter time::now() is based on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource
+{
+    Pit,    // timer interrupt count. Resolution of one tick
+    Tsc,    // calibrated time stamp counter
+    Hpet,   // HPET main counter
+}
+
+static CLOCK_SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+// added to the raw nanoseconds of the clock source, so now() continues where the previous source was
+static CLOCK_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+pub fn clock_source() -> ClockSource
+{
+    match CLOCK_SOURCE.load(Ordering::Relaxed)
+    {
+        1 => ClockSource::Tsc,
+        2 => ClockSource::Hpet,
+        _ => ClockSource::Pit,
+    }
+}
+
+// nanoseconds of the source's own counter. None if the source is not usable (yet)
+fn raw_nanos(source: ClockSource) -> Option<u64>
+{
+    match source
+    {
+        ClockSource::Pit => Some(pit::ticks_to_nanos(pit::ticks())),
+        ClockSource::Tsc => tsc::cycles_to_nanos(tsc::read()),
+        ClockSource::Hpet => hpet::clock_nanos(),
+    }
+}
+
+// Makes `source` the base of now(). Returns false if it is not available
+// (TSC not calibrated, no HPET or one with a 32 bit counter)
+pub fn set_clock_source(source: ClockSource) -> bool
+{
+    if raw_nanos(source).is_none()
+    {
+        return false;
+    }
+    rebase(source, || {});
+    true
+}
+
+// Runs `update` (e.g. a new counter frequency) and continues now() with `source` from the current time
+pub(crate) fn rebase(source: ClockSource, update: impl FnOnce())
+{
+    interrupts::without_interrupts(||
+    {
+        let current = now().as_nanos();
+        update();
+        if let Some(raw) = raw_nanos(source)
+        {
+            CLOCK_OFFSET.store(current.wrapping_sub(raw), Ordering::Relaxed);
+            CLOCK_SOURCE.store(source as u8, Ordering::Relaxed);
+        }
+    });
+}
+
+// Point in time, measured in nanoseconds since boot. Never goes backwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant
+{
+    nanos: u64,
+}
+
+impl Instant
+{
+    // earliest possible Instant (boot)
+    pub const ZERO: Instant = Instant { nanos: 0 };
+
+    pub const fn from_nanos(nanos: u64) -> Instant
+    {
+        Instant { nanos }
+    }
+
+    pub fn now() -> Instant
+    {
+        now()
+    }
+
+    // nanoseconds since boot
+    pub const fn as_nanos(self) -> u64
+    {
+        self.nanos
+    }
+
+    // time elapsed from `earlier` to self. Zero if earlier is later than self
+    pub fn duration_since(self, earlier: Instant) -> Duration
+    {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(self) -> Duration
+    {
+        now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant>
+    {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_add(nanos).map(Instant::from_nanos)
+    }
+
+    pub fn checked_sub(self, duration: Duration) -> Option<Instant>
+    {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.nanos.checked_sub(nanos).map(Instant::from_nanos)
+    }
+}
+
+impl Add<Duration> for Instant
+{
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant
+    {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant
+{
+    fn add_assign(&mut self, duration: Duration)
+    {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant
+{
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant
+    {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant
+{
+    fn sub_assign(&mut self, duration: Duration)
+    {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant
+{
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration
+    {
+        self.duration_since(earlier)
+    }
+}
+
+// seconds since boot with microsecond digits, e.g. "12.345678"
+impl fmt::Display for Instant
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}.{:06}", self.nanos / NANOS_PER_SEC, self.nanos % NANOS_PER_SEC / 1000)
+    }
+}
+
+// Current time since boot
+pub fn now() -> Instant
+{
+    let raw = raw_nanos(clock_source()).unwrap_or(0);
+    Instant::from_nanos(raw.wrapping_add(CLOCK_OFFSET.load(Ordering::Relaxed)))
+}
+
+// time since boot
+pub fn uptime() -> Duration
+{
+    Duration::from_nanos(now().as_nanos())
+}
+
+// Busy waits for `duration`. For short delays in drivers (device reset times and the like)
+// tasks should not use this, it blocks the whole executor
+pub fn spin_wait(duration: Duration)
+{
+    let deadline = now() + duration;
+    while now() < deadline
+    {
+        core::hint::spin_loop();
+    }
+}
+
+// wall clock reference: Unix time in seconds read from the RTC at monotonic time WALL_SYNC_NANOS
+static WALL_SYNC_SECS: AtomicU64 = AtomicU64::new(0);
+static WALL_SYNC_NANOS: AtomicU64 = AtomicU64::new(0);
+
+// Reads the RTC again and makes it the new wall_clock reference
+// the RTC only counts whole seconds, so wall_clock may be off by up to one second
+pub fn sync_wall_clock()
+{
+    let date_time = rtc::read();
+    let secs = if date_time.is_valid() { date_time.unix_timestamp() } else { 0 };
+    interrupts::without_interrupts(||
+    {
+        WALL_SYNC_SECS.store(secs, Ordering::Relaxed);
+        WALL_SYNC_NANOS.store(now().as_nanos(), Ordering::Relaxed);
+    });
+}
+
+// Current UTC date and time
+pub fn wall_clock() -> DateTime
+{
+    DateTime::from_unix_nanos(unix_nanos())
+}
+
+// nanoseconds since 1970-01-01T00:00:00Z
+pub fn unix_nanos() -> u128
+{
+    let (secs, sync_nanos) = interrupts::without_interrupts(||
+    {
+        (WALL_SYNC_SECS.load(Ordering::Relaxed), WALL_SYNC_NANOS.load(Ordering::Relaxed))
+    });
+    u128::from(secs) * u128::from(NANOS_PER_SEC) + u128::from(now().as_nanos().saturating_sub(sync_nanos))
+}
@@ -0,0 +1,119 @@
+// Calendar date and time (proleptic Gregorian, UTC) and conversion from/to Unix time
+
+use core::fmt;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime
+{
+    // field order makes the derived Ord chronological
+    pub year: u16,
+    pub month: u8,          // 1..=12
+    pub day: u8,            // 1..=31
+    pub hour: u8,           // 0..=23
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+}
+
+impl DateTime
+{
+    pub const UNIX_EPOCH: DateTime = DateTime { year: 1970, month: 1, day: 1, hour: 0, minute: 0, second: 0, nanosecond: 0 };
+
+    // true if all fields are in range (day checked against the length of the month)
+    pub fn is_valid(&self) -> bool
+    {
+        (1..=12).contains(&self.month)
+            && self.day >= 1 && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24 && self.minute < 60 && self.second < 60
+            && self.nanosecond < 1_000_000_000
+    }
+
+    // seconds since 1970-01-01T00:00:00Z. Dates before 1970 give 0
+    pub fn unix_timestamp(&self) -> u64
+    {
+        let days = days_from_civil(i64::from(self.year), u32::from(self.month), u32::from(self.day));
+        if days < 0
+        {
+            return 0;
+        }
+        days as u64 * SECS_PER_DAY
+            + u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second)
+    }
+
+    pub fn from_unix_timestamp(secs: u64) -> DateTime
+    {
+        DateTime::from_unix_nanos(u128::from(secs) * 1_000_000_000)
+    }
+
+    pub fn from_unix_nanos(nanos: u128) -> DateTime
+    {
+        let secs = (nanos / 1_000_000_000) as u64;
+        let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+        let secs_of_day = secs % SECS_PER_DAY;
+        DateTime
+        {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+            nanosecond: (nanos % 1_000_000_000) as u32,
+        }
+    }
+}
+
+// ISO 8601, e.g. 2024-03-01T12:34:56Z
+impl fmt::Display for DateTime
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+pub fn is_leap_year(year: u16) -> bool
+{
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+pub fn days_in_month(year: u16, month: u8) -> u8
+{
+    match month
+    {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// days since 1970-01-01 (Howard Hinnant's days_from_civil)
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64
+{
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;                                          // [0, 399]
+    let month_index = i64::from((month + 9) % 12);                              // March = 0
+    let day_of_year = (153 * month_index + 2) / 5 + i64::from(day) - 1;        // [0, 365]
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// inverse of days_from_civil: (year, month, day)
+fn civil_from_days(days: i64) -> (i64, u32, u32)
+{
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;                                       // [0, 146096]
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;                              // March = 0
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
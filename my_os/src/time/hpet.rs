@@ -0,0 +1,440 @@
+// High Precision Event Timer
+//
+// init finds the HPET through its ACPI table, maps the register block (memory::mmio) and starts
+// the main counter. A 64 bit counter then serves as clock source (time::ClockSource::Hpet), a 32 bit
+// one wraps too soon for that. The TSC is calibrated against it either way. Comparators raise interrupts at a counter value (one-shot)
+// or every period (periodic).
+//
+// Interrupt routing: this kernel drives the 8259 PICs, so comparators are delivered through the
+// legacy replacement route: comparator 0 on IRQ 0 and comparator 1 on IRQ 8. Enabling it
+// disconnects the PIT from IRQ 0 and the RTC from IRQ 8. use_as_system_timer does that and keeps the
+// timer tick running on comparator 0. It is the only route: the IO-APIC is not driven by this kernel,
+// so comparators 2 and up cannot raise interrupts
+
+use super::{timer, tsc, ClockSource, Instant, TICK_FREQUENCY};
+use crate::acpi::{self, AcpiError};
+use crate::interrupts::{self, IrqReturn};
+use crate::memory::mmio::{map_mmio, MmioError, MmioRegion};
+use alloc::boxed::Box;
+use conquer_once::spin::OnceCell;
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::PhysAddr;
+
+const REGISTER_BLOCK_LEN: usize = 0x400;
+
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIG: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0f0;
+
+// per comparator registers at 0x100 + 0x20 * n
+fn reg_timer_config(n: u8) -> usize { 0x100 + 0x20 * usize::from(n) }
+fn reg_timer_comparator(n: u8) -> usize { 0x108 + 0x20 * usize::from(n) }
+
+// capabilities register
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+
+// general configuration register
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+// comparator configuration register
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+const TIMER_VALUE_SET: u64 = 1 << 6;    // next comparator write sets the value, not the period
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+// the specification allows at most 100 ns per counter tick
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+// counter ticks the TSC is calibrated over (converted from 10 ms at init)
+const CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+pub enum HpetError
+{
+    Acpi(AcpiError),            // no HPET table (or ACPI tables not found)
+    Mmio(MmioError),
+    NotInitialized,             // hpet::init did not succeed
+    UnsupportedAddressSpace,    // register block not in memory space
+    InvalidPeriod(u64),         // counter period out of spec (femtoseconds)
+    NoSuchComparator(u8),
+    InUse(u8),                  // comparator already claimed
+    PeriodicUnsupported(u8),
+    LegacyRouteUnsupported,     // only comparator 0 and 1 of an HPET with legacy capability
+    Counter32Bit,               // main counter wraps after 2^32 ticks (minutes), too soon for time::now()
+}
+
+impl From<AcpiError> for HpetError
+{
+    fn from(err: AcpiError) -> Self
+    {
+        HpetError::Acpi(err)
+    }
+}
+
+impl From<MmioError> for HpetError
+{
+    fn from(err: MmioError) -> Self
+    {
+        HpetError::Mmio(err)
+    }
+}
+
+struct Hpet
+{
+    registers: MmioRegion,
+    period_fs: u64,         // femtoseconds per counter tick
+    comparators: u8,
+    counter_64bit: bool,
+    legacy_capable: bool,
+    claimed: AtomicU32,     // bit n: comparator n handed out
+}
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+impl Hpet
+{
+    fn read(&self, register: usize) -> u64
+    {
+        self.registers.read::<u64>(register)
+    }
+
+    fn write(&self, register: usize, value: u64)
+    {
+        self.registers.write::<u64>(register, value)
+    }
+
+    fn update(&self, register: usize, f: impl FnOnce(u64) -> u64)
+    {
+        without_interrupts(|| self.write(register, f(self.read(register))));
+    }
+
+    // bits the main counter counts with
+    fn counter_mask(&self) -> u64
+    {
+        if self.counter_64bit { u64::MAX } else { u64::from(u32::MAX) }
+    }
+}
+
+fn hpet() -> Result<&'static Hpet, HpetError>
+{
+    HPET.try_get().map_err(|_| HpetError::NotInitialized)
+}
+
+// Finds and starts the HPET, then calibrates the TSC against it
+// needs the heap and memory::mmio::init. Does nothing if already initialized
+pub fn init() -> Result<(), HpetError>
+{
+    if HPET.is_initialized()
+    {
+        return Ok(());
+    }
+    let table = acpi::find_table(b"HPET")?;
+    // generic address structure at offset 40: address space (0 = memory) and 64 bit address at 44
+    if table.read_u8(40) != 0
+    {
+        return Err(HpetError::UnsupportedAddressSpace);
+    }
+    let base = PhysAddr::new(table.read_u64(44));
+    let registers = unsafe { map_mmio(base, REGISTER_BLOCK_LEN) }?;
+
+    let capabilities = registers.read::<u64>(REG_CAPABILITIES);
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS
+    {
+        return Err(HpetError::InvalidPeriod(period_fs));
+    }
+    let hpet = Hpet
+    {
+        registers,
+        period_fs,
+        comparators: ((capabilities >> 8) & 0x1f) as u8 + 1,
+        counter_64bit: capabilities & CAP_COUNTER_64BIT != 0,
+        legacy_capable: capabilities & CAP_LEGACY_ROUTE != 0,
+        claimed: AtomicU32::new(0),
+    };
+    // all comparators off until claimed. Counter keeps its value, firmware may have started it
+    for n in 0..hpet.comparators
+    {
+        hpet.update(reg_timer_config(n), |config| config & !(TIMER_INTERRUPT_ENABLE | TIMER_FSB_ENABLE));
+    }
+    hpet.update(REG_CONFIG, |config| config | CONFIG_ENABLE);
+    let _ = HPET.try_init_once(|| hpet);
+
+    calibrate_tsc();
+    Ok(())
+}
+
+pub fn is_available() -> bool
+{
+    HPET.is_initialized()
+}
+
+// main counter value. None without HPET. A 32 bit counter wraps to 0 after u32::MAX
+pub fn counter() -> Option<u64>
+{
+    HPET.try_get().ok().map(|hpet| hpet.read(REG_MAIN_COUNTER) & hpet.counter_mask())
+}
+
+// main counter in nanoseconds, for time::ClockSource::Hpet
+// None without HPET or with a 32 bit counter: that wraps after about 5 minutes at 14.3 MHz
+pub fn clock_nanos() -> Option<u64>
+{
+    match HPET.try_get()
+    {
+        Ok(hpet) if hpet.counter_64bit => counter().map(ticks_to_nanos),
+        _ => None,
+    }
+}
+
+// counter frequency in Hz
+pub fn frequency() -> Option<u64>
+{
+    HPET.try_get().ok().map(|hpet| FEMTOS_PER_SEC / hpet.period_fs)
+}
+
+pub fn ticks_to_nanos(ticks: u64) -> u64
+{
+    let period_fs = HPET.try_get().map(|hpet| hpet.period_fs).unwrap_or(0);
+    (u128::from(ticks) * u128::from(period_fs) / u128::from(FEMTOS_PER_NANO)) as u64
+}
+
+pub fn nanos_to_ticks(nanos: u64) -> u64
+{
+    match HPET.try_get()
+    {
+        Ok(hpet) => (u128::from(nanos) * u128::from(FEMTOS_PER_NANO) / u128::from(hpet.period_fs)) as u64,
+        Err(_) => 0,
+    }
+}
+
+// counter value at which time::now() reaches `instant`
+fn counter_at(instant: Instant) -> Option<u64>
+{
+    let counter = counter()?;
+    let ahead = instant.as_nanos().saturating_sub(super::now().as_nanos());
+    Some(counter.wrapping_add(nanos_to_ticks(ahead)))
+}
+
+// TSC cycles over CALIBRATION_TIME measured with the HPET counter. More exact than the PIT
+fn calibrate_tsc()
+{
+    let ticks = nanos_to_ticks(CALIBRATION_TIME.as_nanos() as u64);
+    let mask = HPET.try_get().map(|hpet| hpet.counter_mask()).unwrap_or(u64::MAX);
+    let (cycles, elapsed) = without_interrupts(||
+    {
+        let start_counter = counter().unwrap_or(0);
+        let start_tsc = tsc::read();
+        let mut now = start_counter;
+        // a 32 bit counter may wrap in between
+        while now.wrapping_sub(start_counter) & mask < ticks
+        {
+            now = counter().unwrap_or(now);
+        }
+        (tsc::read() - start_tsc, now.wrapping_sub(start_counter) & mask)
+    });
+    let nanos = ticks_to_nanos(elapsed);
+    if nanos > 0
+    {
+        tsc::set_frequency((u128::from(cycles) * 1_000_000_000 / u128::from(nanos)) as u64);
+    }
+}
+
+// One claimed HPET comparator. Disabled and released on drop
+pub struct Comparator
+{
+    index: u8,
+    irq: u8,
+}
+
+// Claims comparator `index` and routes its interrupt through the legacy route
+// (comparator 0 -> IRQ 0, comparator 1 -> IRQ 8). Does not enable it yet
+pub fn comparator(index: u8) -> Result<Comparator, HpetError>
+{
+    let hpet = hpet()?;
+    if index >= hpet.comparators
+    {
+        return Err(HpetError::NoSuchComparator(index));
+    }
+    let irq = match index
+    {
+        0 if hpet.legacy_capable => 0,
+        1 if hpet.legacy_capable => 8,
+        _ => return Err(HpetError::LegacyRouteUnsupported),
+    };
+    if hpet.claimed.fetch_or(1 << index, Ordering::AcqRel) & (1 << index) != 0
+    {
+        return Err(HpetError::InUse(index));
+    }
+
+    // edge triggered (legacy route requires it), 64 bit if possible, not enabled
+    hpet.update(reg_timer_config(index), |config|
+    {
+        let mut config = config & !(TIMER_LEVEL_TRIGGERED | TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC
+            | TIMER_ROUTE_MASK | TIMER_FSB_ENABLE | TIMER_32BIT_MODE);
+        if !hpet.counter_64bit
+        {
+            config |= TIMER_32BIT_MODE;
+        }
+        config
+    });
+    hpet.update(REG_CONFIG, |config| config | CONFIG_LEGACY_ROUTE);
+    Ok(Comparator { index, irq })
+}
+
+impl Comparator
+{
+    pub fn index(&self) -> u8
+    {
+        self.index
+    }
+
+    // IRQ line the interrupt arrives on (register a handler with interrupts::register_irq)
+    pub fn irq(&self) -> u8
+    {
+        self.irq
+    }
+
+    // raises one interrupt when the main counter reaches `deadline`
+    pub fn set_one_shot(&self, deadline: u64)
+    {
+        let hpet = HPET.get().expect("comparator without HPET");
+        without_interrupts(||
+        {
+            let config = hpet.read(reg_timer_config(self.index)) & !TIMER_PERIODIC;
+            hpet.write(reg_timer_config(self.index), config | TIMER_INTERRUPT_ENABLE);
+            hpet.write(reg_timer_comparator(self.index), deadline);
+        });
+    }
+
+    // raises one interrupt at `instant` (or right away if it has passed)
+    pub fn set_one_shot_at(&self, instant: Instant)
+    {
+        let hpet = HPET.get().expect("comparator without HPET");
+        // at least a few ticks ahead: an edge for a value the counter already passed never comes
+        let minimum = hpet.read(REG_MAIN_COUNTER).wrapping_add(nanos_to_ticks(1000).max(2));
+        let deadline = counter_at(instant).unwrap_or(minimum);
+        let deadline = if (deadline.wrapping_sub(minimum) as i64) < 0 { minimum } else { deadline };
+        self.set_one_shot(deadline);
+    }
+
+    // raises an interrupt every `period`
+    pub fn set_periodic(&self, period: Duration) -> Result<(), HpetError>
+    {
+        let hpet = hpet()?;
+        let config = hpet.read(reg_timer_config(self.index));
+        if config & TIMER_PERIODIC_CAPABLE == 0
+        {
+            return Err(HpetError::PeriodicUnsupported(self.index));
+        }
+        let ticks = nanos_to_ticks(u64::try_from(period.as_nanos()).unwrap_or(u64::MAX)).max(1);
+        without_interrupts(||
+        {
+            hpet.write(reg_timer_config(self.index),
+                config | TIMER_PERIODIC | TIMER_VALUE_SET | TIMER_INTERRUPT_ENABLE);
+            // with VALUE_SET: first write is the next deadline, the second one the period
+            hpet.write(reg_timer_comparator(self.index), hpet.read(REG_MAIN_COUNTER).wrapping_add(ticks));
+            hpet.write(reg_timer_comparator(self.index), ticks);
+        });
+        Ok(())
+    }
+
+    pub fn disable(&self)
+    {
+        if let Ok(hpet) = hpet()
+        {
+            hpet.update(reg_timer_config(self.index), |config| config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC));
+        }
+    }
+}
+
+impl Drop for Comparator
+{
+    fn drop(&mut self)
+    {
+        self.disable();
+        if let Ok(hpet) = hpet()
+        {
+            let claimed = hpet.claimed.fetch_and(!(1 << self.index), Ordering::AcqRel) & !(1 << self.index);
+            // last legacy routed comparator gone: give IRQ 0 and 8 back to PIT and RTC
+            if claimed & 0b11 == 0
+            {
+                hpet.update(REG_CONFIG, |config| config & !CONFIG_LEGACY_ROUTE);
+            }
+        }
+    }
+}
+
+// Timer hardware of use_as_system_timer: periodic tick on comparator 0, timer queue deadlines
+// on comparator 1. The tick is stopped while the CPU idles (tickless idle, see timer::idle_enter)
+pub struct HpetWakeup
+{
+    tick: Comparator,
+    wakeup: Comparator,
+}
+
+fn tick_period() -> Duration
+{
+    Duration::from_nanos(1_000_000_000 / u64::from(TICK_FREQUENCY))
+}
+
+impl timer::WakeupSource for HpetWakeup
+{
+    fn arm(&self, deadline: Instant)
+    {
+        self.wakeup.set_one_shot_at(deadline);
+    }
+
+    fn disarm(&self)
+    {
+        self.wakeup.disable();
+    }
+
+    fn stop_tick(&self) -> bool
+    {
+        self.tick.disable();
+        true
+    }
+
+    fn start_tick(&self)
+    {
+        // checked by use_as_system_timer
+        let _ = self.tick.set_periodic(tick_period());
+    }
+}
+
+// Makes the HPET the system timer:
+// - time::now() reads the main counter
+// - comparator 0 raises the timer tick (IRQ 0) at TICK_FREQUENCY instead of the PIT,
+//   but only while tasks are runnable: it stops when the executor idles
+// - comparator 1 (IRQ 8) wakes the CPU exactly at the next timer queue deadline
+// RTC interrupts (rtc::ticks) stop arriving, IRQ 8 now belongs to the HPET
+// Fails with HpetError::Counter32Bit on an HPET whose counter wraps too soon to be the clock
+pub fn use_as_system_timer() -> Result<(), HpetError>
+{
+    init()?;
+    if !hpet()?.counter_64bit
+    {
+        return Err(HpetError::Counter32Bit);
+    }
+    let tick = comparator(0)?;
+    let wakeup = comparator(1)?;
+    tick.set_periodic(tick_period())?;
+    // the IRQ 8 interrupt only has to end hlt. Expired timers are handled by the executor
+    interrupts::register_irq(wakeup.irq(), || IrqReturn::Handled);
+    super::set_clock_source(ClockSource::Hpet);
+    // both comparators stay claimed for the rest of the run
+    timer::set_wakeup_source(Box::new(HpetWakeup { tick, wakeup }));
+    Ok(())
+}
@@ -0,0 +1,217 @@
+// Programmable interval timer (Intel 8253/8254)
+//
+// channel 0 drives IRQ 0 (the timer interrupt), channel 2 is used for one-shot busy waits
+// when calibrating other clocks. Both count down from a divisor at BASE_FREQUENCY
+//
+// without HPET, PitWakeup gives timer::idle_enter a one-shot wakeup: channel 0 leaves the
+// periodic tick while the CPU idles and fires once at the next timer deadline
+
+use super::{timer, ClockSource, Instant};
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+// input clock of all channels in Hz
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const PORT_B: u16 = 0x61;   // keyboard controller port B: channel 2 gate and output
+
+// command byte: channel in bits 6-7, access mode lobyte/hibyte (0b11) in bits 4-5, operating mode in bits 1-3
+const SELECT_CHANNEL_0: u8 = 0b00 << 6;
+const SELECT_CHANNEL_2: u8 = 0b10 << 6;
+const ACCESS_LOHI: u8 = 0b11 << 4;
+const MODE_INTERRUPT_ON_TERMINAL_COUNT: u8 = 0 << 1;
+const MODE_SQUARE_WAVE: u8 = 3 << 1;
+
+const GATE_2: u8 = 1 << 0;      // port B: channel 2 counts while set
+const SPEAKER: u8 = 1 << 1;     // port B: connects channel 2 output to the speaker
+const OUTPUT_2: u8 = 1 << 5;    // port B: channel 2 output
+
+// port B reads before wait_channel_2 gives up (about a second). Some hardware and emulators
+// never raise the channel 2 output
+const CHANNEL_2_SPIN_LIMIT: u32 = 1_000_000;
+
+// longest one-shot countdown, about 55 ms
+const MAX_COUNT: u64 = 0xffff;
+
+// divisor of channel 0. 65536 (written as 0) is the power-on default of about 18.2 Hz
+static DIVISOR: AtomicU32 = AtomicU32::new(65536);
+
+// timer interrupts (IRQ 0) since boot. Raised by channel 0, or by HPET comparator 0 under legacy replacement
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Programs channel 0 to fire IRQ 0 `hz` times per second (rate generator, square wave)
+// hz is limited to what a 16 bit divisor allows (19 Hz .. BASE_FREQUENCY). Returns the exact frequency in mHz
+pub fn set_frequency(hz: u32) -> u64
+{
+    let divisor = (BASE_FREQUENCY / hz.max(1)).clamp(1, 65536);
+    // ticks counted so far keep their length in time::now() (PIT clock source)
+    super::rebase(super::clock_source(), ||
+    {
+        program_channel_0(MODE_SQUARE_WAVE, Some(divisor));
+        DIVISOR.store(divisor, Ordering::Relaxed);
+    });
+    frequency_millihertz()
+}
+
+// writes mode and count of channel 0. Without count a mode 0 countdown waits, so no interrupt comes
+fn program_channel_0(mode: u8, count: Option<u32>)
+{
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_0);
+    unsafe
+    {
+        command.write(SELECT_CHANNEL_0 | ACCESS_LOHI | mode);
+        if let Some(count) = count
+        {
+            // 65536 does not fit, the PIT reads 0 as 65536
+            data.write(count as u8);
+            data.write((count >> 8) as u8);
+        }
+    }
+}
+
+// current channel 0 frequency in mHz (BASE_FREQUENCY is not a multiple of most divisors)
+pub fn frequency_millihertz() -> u64
+{
+    u64::from(BASE_FREQUENCY) * 1000 / u64::from(DIVISOR.load(Ordering::Relaxed))
+}
+
+// number of timer interrupts since boot
+pub fn ticks() -> u64
+{
+    TICKS.load(Ordering::Relaxed)
+}
+
+// converts a number of channel 0 ticks at the current frequency into nanoseconds
+pub fn ticks_to_nanos(ticks: u64) -> u64
+{
+    let divisor = u128::from(DIVISOR.load(Ordering::Relaxed));
+    (u128::from(ticks) * divisor * 1_000_000_000 / u128::from(BASE_FREQUENCY)) as u64
+}
+
+// called by the timer interrupt handler
+pub(crate) fn tick()
+{
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Busy waits for `count` periods of BASE_FREQUENCY using channel 2 (one-shot, no interrupt)
+// `during` is called right after the countdown started and right after it ended,
+// so callers can read another clock at both ends. Call with interrupts disabled for exact results
+// false if the output never went high (`during` then ran only once)
+pub(crate) fn wait_channel_2(count: u16, mut during: impl FnMut()) -> bool
+{
+    let mut port_b = Port::<u8>::new(PORT_B);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL_2);
+    unsafe
+    {
+        // gate low (stopped) and speaker off while programming
+        let port_b_value = port_b.read() & !(GATE_2 | SPEAKER);
+        port_b.write(port_b_value);
+
+        command.write(SELECT_CHANNEL_2 | ACCESS_LOHI | MODE_INTERRUPT_ON_TERMINAL_COUNT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+
+        // raising the gate starts the countdown. Output goes high at terminal count
+        port_b.write(port_b_value | GATE_2);
+        during();
+        let mut spins = 0;
+        while port_b.read() & OUTPUT_2 == 0
+        {
+            spins += 1;
+            if spins == CHANNEL_2_SPIN_LIMIT
+            {
+                port_b.write(port_b_value);
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        during();
+        port_b.write(port_b_value);
+    }
+    true
+}
+
+// Wakeup source of channel 0 for machines without HPET (see hpet::use_as_system_timer)
+// outside idle the periodic tick keeps running and deadlines have its resolution. While idle the
+// channel counts down once to the deadline, at most MAX_COUNT periods: far deadlines wake the CPU
+// every 55 ms on the way. Needs a clock source other than the PIT, which stops with the tick
+pub struct PitWakeup
+{
+    deadline: AtomicU64,    // nanoseconds of the armed deadline, u64::MAX if none
+    idle: AtomicBool,       // tick stopped, channel 0 in one-shot mode
+}
+
+impl PitWakeup
+{
+    pub fn new() -> Self
+    {
+        PitWakeup { deadline: AtomicU64::new(u64::MAX), idle: AtomicBool::new(false) }
+    }
+
+    // one-shot countdown to the armed deadline, or none at all
+    fn program_one_shot(&self)
+    {
+        let count = match self.deadline.load(Ordering::Relaxed)
+        {
+            u64::MAX => None,
+            deadline =>
+            {
+                let ahead = deadline.saturating_sub(super::now().as_nanos());
+                let count = u128::from(ahead) * u128::from(BASE_FREQUENCY) / 1_000_000_000;
+                Some(count.clamp(1, u128::from(MAX_COUNT)) as u32)
+            }
+        };
+        program_channel_0(MODE_INTERRUPT_ON_TERMINAL_COUNT, count);
+    }
+}
+
+impl timer::WakeupSource for PitWakeup
+{
+    fn arm(&self, deadline: Instant)
+    {
+        self.deadline.store(deadline.as_nanos(), Ordering::Relaxed);
+        if self.idle.load(Ordering::Relaxed)
+        {
+            self.program_one_shot();
+        }
+    }
+
+    fn disarm(&self)
+    {
+        self.deadline.store(u64::MAX, Ordering::Relaxed);
+        if self.idle.load(Ordering::Relaxed)
+        {
+            self.program_one_shot();
+        }
+    }
+
+    fn stop_tick(&self) -> bool
+    {
+        if super::clock_source() == ClockSource::Pit
+        {
+            return false;
+        }
+        self.idle.store(true, Ordering::Relaxed);
+        self.program_one_shot();
+        true
+    }
+
+    fn start_tick(&self)
+    {
+        self.idle.store(false, Ordering::Relaxed);
+        program_channel_0(MODE_SQUARE_WAVE, Some(DIVISOR.load(Ordering::Relaxed)));
+    }
+}
+
+// Makes PitWakeup the timer queue's wakeup source, for tickless idle without HPET. Needs the heap
+pub fn use_as_wakeup_source()
+{
+    timer::set_wakeup_source(Box::new(PitWakeup::new()));
+}
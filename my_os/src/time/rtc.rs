@@ -0,0 +1,325 @@
+// CMOS real-time clock (Motorola MC146818 compatible)
+//
+// read() returns the date and time kept by the battery backed clock. The RTC is assumed to run
+// in UTC (QEMU default). The clock can also raise IRQ 8 periodically or once per second
+// (update ended), see ticks
+
+use super::datetime::DateTime;
+use crate::interrupts::{self, IrqHandle, IrqReturn};
+use crate::task::irq::{IrqEvent, IrqEventStats, IrqSignal, Wait};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+pub const IRQ: u8 = 8;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+const REG_CENTURY: u8 = 0x32;   // not standardized (ACPI FADT names it), but present on PCs and QEMU
+
+const A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const A_RATE_MASK: u8 = 0x0f;
+const B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const B_UPDATE_INTERRUPT: u8 = 1 << 4;
+const B_BINARY: u8 = 1 << 2;    // values are binary instead of BCD
+const B_24_HOUR: u8 = 1 << 1;
+const C_PERIODIC: u8 = 1 << 6;
+const C_UPDATE: u8 = 1 << 4;
+const HOUR_PM: u8 = 1 << 7;     // in 12 hour mode
+
+// serializes the index/data port pairs. Only locked with interrupts disabled (IRQ 8 handler uses it too)
+static CMOS: Mutex<()> = Mutex::new(());
+
+fn read_register(register: u8) -> u8
+{
+    without_interrupts(||
+    {
+        let _cmos = CMOS.lock();
+        unsafe
+        {
+            Port::<u8>::new(INDEX_PORT).write(register);
+            Port::<u8>::new(DATA_PORT).read()
+        }
+    })
+}
+
+// read-modify-write of a register as one step
+fn update_register(register: u8, f: impl FnOnce(u8) -> u8)
+{
+    without_interrupts(||
+    {
+        let _cmos = CMOS.lock();
+        let mut index = Port::<u8>::new(INDEX_PORT);
+        let mut data = Port::<u8>::new(DATA_PORT);
+        unsafe
+        {
+            index.write(register);
+            let value = f(data.read());
+            index.write(register);
+            data.write(value);
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime
+{
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw() -> RawTime
+{
+    // values are undefined while the RTC updates them (about 2 ms once per second)
+    while read_register(REG_STATUS_A) & A_UPDATE_IN_PROGRESS != 0
+    {
+        core::hint::spin_loop();
+    }
+    RawTime
+    {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: read_register(REG_CENTURY),
+    }
+}
+
+fn from_bcd(value: u8) -> u8
+{
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+// Reads the current date and time from the RTC
+// an update can still start between the registers, so read until two reads agree
+pub fn read() -> DateTime
+{
+    let mut raw = read_raw();
+    loop
+    {
+        let again = read_raw();
+        if again == raw
+        {
+            break;
+        }
+        raw = again;
+    }
+    decode(raw, read_register(REG_STATUS_B))
+}
+
+fn decode(raw: RawTime, status_b: u8) -> DateTime
+{
+    let binary = status_b & B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { from_bcd(value) };
+
+    // PM flag is bit 7 of the hour in both BCD and binary mode
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & B_24_HOUR == 0
+    {
+        // 12 hour clock: 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm
+        {
+            hour += 12;
+        }
+    }
+
+    let year = u16::from(convert(raw.year));
+    let century = u16::from(convert(raw.century));
+    // without a sane century register assume 1970..=2069
+    let century = if (19..=99).contains(&century) { century } else if year < 70 { 20 } else { 19 };
+
+    DateTime
+    {
+        year: century * 100 + year,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+        nanosecond: 0,
+    }
+}
+
+// Interrupt the RTC raises on IRQ 8
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource
+{
+    // once per second, right after the clock advanced. Aligned to the wall clock second
+    Update,
+    // `hz` times per second. Power of two from 2 to 8192
+    Periodic { hz: u32 },
+}
+
+struct TickState
+{
+    update: Option<IrqSignal>,
+    periodic: Option<IrqSignal>,
+    handle: Option<IrqHandle>,  // IRQ 8 handler, while any source is enabled
+}
+
+// only locked with interrupts disabled
+static TICKS: Mutex<TickState> = Mutex::new(TickState { update: None, periodic: None, handle: None });
+
+// IRQ 8: register C tells which interrupts are pending. Reading it also acknowledges them,
+// without that read the RTC raises no further interrupts. Handles both sources with one read
+fn rtc_interrupt() -> IrqReturn
+{
+    let flags = read_register(REG_STATUS_C);
+    let ticks = TICKS.lock();
+    if flags & C_UPDATE != 0
+    {
+        if let Some(signal) = &ticks.update
+        {
+            signal.signal();
+        }
+    }
+    if flags & C_PERIODIC != 0
+    {
+        if let Some(signal) = &ticks.periodic
+        {
+            signal.signal();
+        }
+    }
+    if flags & (C_UPDATE | C_PERIODIC) != 0 { IrqReturn::Handled } else { IrqReturn::NotMine }
+}
+
+// Enables an RTC interrupt as tick source. The returned RtcTicks is signaled on every interrupt
+// and disables the interrupt again when dropped. Panics if the source is already in use,
+// or if a periodic rate is not a power of two between 2 and 8192
+pub fn ticks(source: TickSource) -> RtcTicks
+{
+    let rate = match source
+    {
+        TickSource::Update => 0,
+        TickSource::Periodic { hz } =>
+        {
+            assert!(hz.is_power_of_two() && (2..=8192).contains(&hz), "unsupported RTC rate {} Hz", hz);
+            // frequency = 32768 >> (rate - 1)
+            16 - hz.trailing_zeros() as u8
+        }
+    };
+    let (signal, event) = IrqEvent::new();
+    // register_irq allocates, so it is called before taking the lock
+    let handle = interrupts::register_irq(IRQ, rtc_interrupt);
+    let unused_handle = without_interrupts(||
+    {
+        let mut ticks = TICKS.lock();
+        let slot = match source
+        {
+            TickSource::Update => &mut ticks.update,
+            TickSource::Periodic { .. } => &mut ticks.periodic,
+        };
+        assert!(slot.is_none(), "RTC tick source {:?} already in use", source);
+        *slot = Some(signal);
+        match source
+        {
+            TickSource::Update => update_register(REG_STATUS_B, |b| b | B_UPDATE_INTERRUPT),
+            TickSource::Periodic { .. } =>
+            {
+                update_register(REG_STATUS_A, |a| (a & !A_RATE_MASK) | rate);
+                update_register(REG_STATUS_B, |b| b | B_PERIODIC_INTERRUPT);
+            }
+        }
+        // a pending flag from before would otherwise block the first interrupt
+        read_register(REG_STATUS_C);
+        match ticks.handle
+        {
+            Some(_) => Some(handle),
+            None =>
+            {
+                ticks.handle = Some(handle);
+                None
+            }
+        }
+    });
+    if let Some(handle) = unused_handle
+    {
+        interrupts::unregister_irq(handle);
+    }
+    RtcTicks { event, source }
+}
+
+// Stream of RTC interrupts, created by ticks. Items are the number of interrupts since the last one
+pub struct RtcTicks
+{
+    event: IrqEvent,
+    source: TickSource,
+}
+
+impl RtcTicks
+{
+    pub fn wait(&mut self) -> Wait
+    {
+        self.event.wait()
+    }
+
+    pub fn stats(&self) -> IrqEventStats
+    {
+        self.event.stats()
+    }
+}
+
+impl Stream for RtcTicks
+{
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>>
+    {
+        Pin::new(&mut self.event).poll_next(cx)
+    }
+}
+
+impl Drop for RtcTicks
+{
+    fn drop(&mut self)
+    {
+        let source = self.source;
+        let (signal, handle) = without_interrupts(||
+        {
+            let mut ticks = TICKS.lock();
+            let signal = match source
+            {
+                TickSource::Update =>
+                {
+                    update_register(REG_STATUS_B, |b| b & !B_UPDATE_INTERRUPT);
+                    ticks.update.take()
+                }
+                TickSource::Periodic { .. } =>
+                {
+                    update_register(REG_STATUS_B, |b| b & !B_PERIODIC_INTERRUPT);
+                    ticks.periodic.take()
+                }
+            };
+            let handle = if ticks.update.is_none() && ticks.periodic.is_none() { ticks.handle.take() } else { None };
+            (signal, handle)
+        });
+        // free outside the critical section
+        drop(signal);
+        if let Some(handle) = handle
+        {
+            interrupts::unregister_irq(handle);
+        }
+    }
+}
@@ -0,0 +1,344 @@
+// Timer queue: futures that complete at a point in time
+//
+// sleep / sleep_until / timeout register their deadline and waker here. The executor calls
+// wake_expired at the start of every round, so a sleeping task is woken by the first interrupt
+// after its deadline: the periodic timer tick, or the WakeupSource (e.g. an HPET comparator) that
+// is armed for the earliest deadline. Without a wakeup source timers have the resolution of one tick
+//
+// tickless idle: before the executor halts it calls idle_enter, which stops the periodic tick if
+// the wakeup source drives it. The CPU then sleeps until the next deadline or a device interrupt
+// instead of waking TICK_FREQUENCY times per second. idle_exit restarts the tick.
+// wakeup sources: hpet::use_as_system_timer, or pit::use_as_wakeup_source without HPET
+//
+// the queue is only used by tasks and the executor, never by interrupt handlers
+
+use super::{ClockSource, Instant};
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+
+// Hardware that can raise an interrupt at a given time. arm replaces the previous deadline
+// both are called with the queue locked and interrupts disabled: must not block
+pub trait WakeupSource: Send + Sync
+{
+    fn arm(&self, deadline: Instant);
+    fn disarm(&self);
+
+    // sources that also raise the periodic timer tick stop it and return true. For tickless idle
+    fn stop_tick(&self) -> bool
+    {
+        false
+    }
+
+    fn start_tick(&self) {}
+}
+
+struct TimerQueue
+{
+    entries: BTreeMap<(Instant, u64), Waker>,   // ordered by deadline, id keeps equal deadlines apart
+    next_id: u64,
+    armed: Option<Instant>,                     // deadline the wakeup source is armed for
+    wakeup: Option<Box<dyn WakeupSource>>,
+    tick_stopped: bool,
+}
+
+static QUEUE: spin::Mutex<TimerQueue> = spin::Mutex::new(TimerQueue
+{
+    entries: BTreeMap::new(),
+    next_id: 0,
+    armed: None,
+    wakeup: None,
+    tick_stopped: false,
+});
+
+// idle_enter calls, and how many of them stopped the tick
+static IDLE_ENTRIES: AtomicU64 = AtomicU64::new(0);
+static TICKLESS_IDLES: AtomicU64 = AtomicU64::new(0);
+
+fn lock_queue<R>(f: impl FnOnce(&mut TimerQueue) -> R) -> R
+{
+    interrupts::without_interrupts(|| f(&mut QUEUE.lock()))
+}
+
+impl TimerQueue
+{
+    // arms the wakeup source for the earliest deadline if that changed
+    fn rearm(&mut self)
+    {
+        let earliest = self.entries.keys().next().map(|&(deadline, _)| deadline);
+        if earliest == self.armed
+        {
+            return;
+        }
+        self.armed = earliest;
+        if let Some(wakeup) = &self.wakeup
+        {
+            match earliest
+            {
+                Some(deadline) => wakeup.arm(deadline),
+                None => wakeup.disarm(),
+            }
+        }
+    }
+}
+
+// Installs the hardware that wakes the CPU at the next deadline. Replaces (and disarms) the previous one
+pub fn set_wakeup_source(source: Box<dyn WakeupSource>)
+{
+    let previous = lock_queue(|queue|
+    {
+        if let Some(previous) = &queue.wakeup
+        {
+            previous.disarm();
+            if queue.tick_stopped
+            {
+                previous.start_tick();
+                queue.tick_stopped = false;
+            }
+        }
+        let previous = queue.wakeup.replace(source);
+        queue.armed = None;
+        queue.rearm();
+        previous
+    });
+    drop(previous);
+}
+
+// earliest pending deadline
+pub fn next_deadline() -> Option<Instant>
+{
+    lock_queue(|queue| queue.entries.keys().next().map(|&(deadline, _)| deadline))
+}
+
+// whether a timer is due that wake_expired has not woken yet
+pub fn has_expired() -> bool
+{
+    next_deadline().map_or(false, |deadline| deadline <= super::now())
+}
+
+// Wakes all timers whose deadline has passed and arms the wakeup source for the next one
+// returns the number of woken timers. Called by the executor
+pub fn wake_expired() -> usize
+{
+    let now = super::now();
+    let expired: Vec<Waker> = lock_queue(|queue|
+    {
+        let mut expired = Vec::new();
+        while let Some(entry) = queue.entries.first_entry()
+        {
+            if entry.key().0 > now
+            {
+                break;
+            }
+            expired.push(entry.remove());
+        }
+        queue.rearm();
+        expired
+    });
+    // wake outside the lock: a waker may run arbitrary code
+    let count = expired.len();
+    for waker in expired
+    {
+        waker.wake();
+    }
+    count
+}
+
+// Stops the periodic tick before the CPU halts, unless a timer is due already.
+// the next deadline stays armed, so the CPU wakes in time. Returns whether the tick was stopped
+// called by the executor with interrupts disabled, right before hlt
+pub fn idle_enter() -> bool
+{
+    IDLE_ENTRIES.fetch_add(1, Ordering::Relaxed);
+    // with the PIT clock source now() advances only with ticks
+    if super::clock_source() == ClockSource::Pit
+    {
+        return false;
+    }
+    let now = super::now();
+    let stopped = lock_queue(|queue|
+    {
+        let due = queue.entries.keys().next().map_or(false, |&(deadline, _)| deadline <= now);
+        if !queue.tick_stopped && !due
+        {
+            queue.tick_stopped = queue.wakeup.as_ref().map_or(false, |wakeup| wakeup.stop_tick());
+        }
+        queue.tick_stopped
+    });
+    if stopped
+    {
+        TICKLESS_IDLES.fetch_add(1, Ordering::Relaxed);
+    }
+    stopped
+}
+
+// Restarts the periodic tick after idle_enter stopped it. Called by the executor after hlt
+pub fn idle_exit()
+{
+    lock_queue(|queue|
+    {
+        if queue.tick_stopped
+        {
+            if let Some(wakeup) = &queue.wakeup
+            {
+                wakeup.start_tick();
+            }
+            queue.tick_stopped = false;
+        }
+    });
+}
+
+// Counters of tickless idle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleStats
+{
+    pub idle_entries: u64,      // times the executor went idle
+    pub tickless: u64,          // of those, with the periodic tick stopped
+}
+
+pub fn idle_stats() -> IdleStats
+{
+    IdleStats
+    {
+        idle_entries: IDLE_ENTRIES.load(Ordering::Relaxed),
+        tickless: TICKLESS_IDLES.load(Ordering::Relaxed),
+    }
+}
+
+// Future returned by sleep and sleep_until
+pub struct Sleep
+{
+    deadline: Instant,
+    key: Option<u64>,   // id of the queue entry while registered
+}
+
+// completes after `duration`
+pub fn sleep(duration: Duration) -> Sleep
+{
+    sleep_until(super::now().checked_add(duration).unwrap_or(Instant::from_nanos(u64::MAX)))
+}
+
+// completes once time::now() reached `deadline`
+pub fn sleep_until(deadline: Instant) -> Sleep
+{
+    Sleep { deadline, key: None }
+}
+
+impl Sleep
+{
+    pub fn deadline(&self) -> Instant
+    {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool
+    {
+        super::now() >= self.deadline
+    }
+}
+
+impl Future for Sleep
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>
+    {
+        if self.is_elapsed()
+        {
+            if let Some(key) = self.key.take()
+            {
+                let deadline = self.deadline;
+                lock_queue(|queue|
+                {
+                    queue.entries.remove(&(deadline, key));
+                    queue.rearm();
+                });
+            }
+            return Poll::Ready(());
+        }
+        let waker = cx.waker().clone();     // clone before disabling interrupts
+        let deadline = self.deadline;
+        let key = self.key;
+        let (key, previous) = lock_queue(|queue|
+        {
+            let key = key.unwrap_or_else(||
+            {
+                queue.next_id += 1;
+                queue.next_id
+            });
+            // entry may be gone already if wake_expired woke it in between
+            let previous = queue.entries.insert((deadline, key), waker);
+            queue.rearm();
+            (key, previous)
+        });
+        drop(previous);
+        self.key = Some(key);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep
+{
+    fn drop(&mut self)
+    {
+        if let Some(key) = self.key
+        {
+            let deadline = self.deadline;
+            let waker = lock_queue(|queue|
+            {
+                let waker = queue.entries.remove(&(deadline, key));
+                queue.rearm();
+                waker
+            });
+            drop(waker);
+        }
+    }
+}
+
+// Error of timeout: the deadline passed before the future completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+// Future returned by timeout
+pub struct Timeout<F>
+{
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+// Runs `future` for at most `duration`. The future is dropped when the time is up
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F>
+{
+    Timeout { future: Box::pin(future), sleep: sleep(duration) }
+}
+
+impl<F: Future> Future for Timeout<F>
+{
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output>
+    {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx)
+        {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut self.sleep).poll(cx)
+        {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,91 @@
+// Time stamp counter: CPU cycles since reset, read with rdtsc
+//
+// calibrate measures its frequency against PIT channel 2 (hpet::init measures it again against the HPET).
+// Afterwards it can be the time::now() source
+// On CPUs without invariant TSC (see is_invariant) the rate may change with power states
+
+use super::pit;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+// length of one calibration run in PIT periods (about 10 ms)
+const CALIBRATION_COUNT: u16 = 11932;
+// calibration runs. The shortest one is used, longer ones were disturbed (e.g. by SMIs)
+const CALIBRATION_RUNS: usize = 3;
+
+// TSC frequency in Hz. 0 until calibrated
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+// _rdtsc is an unsafe intrinsic on older toolchains and a safe one on newer ones
+#[allow(unused_unsafe)]
+pub fn read() -> u64
+{
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// CPUID 0x8000_0007 EDX bit 8: TSC runs at a constant rate in all power states
+#[allow(unused_unsafe)]
+pub fn is_invariant() -> bool
+{
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    max_extended >= 0x8000_0007 && unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+// Measures the TSC frequency with PIT channel 2. Returns the frequency in Hz
+// 0 if channel 2 does not count (the TSC then stays uncalibrated, time keeps the PIT clock source)
+pub fn calibrate() -> u64
+{
+    let cycles = interrupts::without_interrupts(||
+    {
+        let mut shortest = u64::MAX;
+        for _ in 0..CALIBRATION_RUNS
+        {
+            let mut stamps = [0u64; 2];
+            let mut index = 0;
+            let done = pit::wait_channel_2(CALIBRATION_COUNT, ||
+            {
+                stamps[index] = read();
+                index += 1;
+            });
+            if !done
+            {
+                return None;
+            }
+            shortest = shortest.min(stamps[1] - stamps[0]);
+        }
+        Some(shortest)
+    });
+    let cycles = match cycles
+    {
+        Some(cycles) => cycles,
+        None => return 0,
+    };
+    let frequency = cycles * u64::from(pit::BASE_FREQUENCY) / u64::from(CALIBRATION_COUNT);
+    set_frequency(frequency);
+    frequency
+}
+
+// Sets the TSC frequency measured by another clock (e.g. the HPET)
+// time::now() keeps counting from where it was, so it does not jump
+pub fn set_frequency(frequency: u64)
+{
+    super::rebase(super::clock_source(), || FREQUENCY.store(frequency, Ordering::Relaxed));
+}
+
+// TSC frequency in Hz, None before calibration
+pub fn frequency() -> Option<u64>
+{
+    match FREQUENCY.load(Ordering::Relaxed)
+    {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+// converts a number of TSC cycles into nanoseconds. None before calibration
+pub fn cycles_to_nanos(cycles: u64) -> Option<u64>
+{
+    let frequency = frequency()?;
+    Some((u128::from(cycles) * 1_000_000_000 / u128::from(frequency)) as u64)
+}
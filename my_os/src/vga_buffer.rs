@@ -1,5 +1,15 @@
 use volatile::Volatile;
 use core::fmt;
+use alloc::{boxed::Box, collections::VecDeque};
+use core::ops::Range;
+use x86_64::instructions::port::Port;
+
+// escape sequence parser used by Writer::write_string
+pub mod ansi;
+// virtual consoles: Alt+F1..F6, log console
+pub mod console;
+
+use ansi::{Action, Csi, Parser};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -28,11 +38,11 @@ pub enum Color
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
-struct ColorCode(u8);   // Contains full color byte (foreground, background)
+pub struct ColorCode(u8);   // Contains full color byte (foreground, background)
 
 impl ColorCode
 {
-    fn new(foreground: Color, background: Color) -> ColorCode
+    pub fn new(foreground: Color, background: Color) -> ColorCode
     {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
@@ -54,23 +64,175 @@ struct Buffer   // Volatile ensures that we cant accidently write to it "normall
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+// CRT controller: index register selects one of its registers, data register reads or writes it
+const CRTC_INDEX: u16 = 0x3d4;
+const CRTC_DATA: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;     // bits 0-4: first scanline of the cursor, bit 5: cursor off
+const CRTC_CURSOR_END: u8 = 0x0b;       // bits 0-4: last scanline
+const CRTC_CURSOR_HIGH: u8 = 0x0e;      // cursor cell (row * BUFFER_WIDTH + col), high byte
+const CRTC_CURSOR_LOW: u8 = 0x0f;
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+// a character cell is 16 scanlines high in 80x25 text mode
+const SCANLINES: u8 = 16;
+
+// Shape of the blinking hardware cursor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape
+{
+    Underline,              // last two scanlines (BIOS default)
+    Block,                  // whole cell
+    Scanlines(u8, u8),      // first and last scanline, 0..16
+}
+
+fn crtc_read(register: u8) -> u8
+{
+    unsafe
+    {
+        Port::<u8>::new(CRTC_INDEX).write(register);
+        Port::<u8>::new(CRTC_DATA).read()
+    }
+}
+
+fn crtc_write(register: u8, value: u8)
+{
+    unsafe
+    {
+        Port::<u8>::new(CRTC_INDEX).write(register);
+        Port::<u8>::new(CRTC_DATA).write(value);
+    }
+}
 
-pub struct Writer   // Write to the last line and shifts lines up when a line is full
+pub struct Writer   // Writes at the current position. A newline on the last row shifts lines up
 {
-    column_position: usize,         // keep track of current position in last row
+    row_position: usize,            // row the next character goes to. Starts on the last row
+    column_position: usize,         // keep track of current position in the row
     color_code: ColorCode,          // foreground and background color specification
-    buffer: &'static mut Buffer,    // reference to the VGA buffer
+    buffer: &'static mut Buffer,    // VGA buffer while visible, off-screen buffer of a hidden console otherwise
+    visible: bool,                  // buffer is the VGA buffer, cursor changes go to the hardware
+    off_screen: Option<&'static mut Buffer>, // keeps the screen while hidden, see console::switch_to
+    cursor_hidden: bool,            // show_cursor / hide_cursor, applied when the console becomes visible
+    cursor_shape: CursorShape,
+    parser: Parser,                 // escape sequences in write_string
+    style: Style,                   // colors set by SGR sequences, color_code is derived from it
+    default_style: Style,           // restored by SGR 0, changed by set_color
+    saved_position: (usize, usize), // ESC 7 / CSI s
+    scrollback: Option<Scrollback>, // lines scrolled off the top, see enable_scrollback
+}
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+// Lines that scrolled off the screen, oldest first, and the state of the scrolled back view
+// all memory is allocated by enable_scrollback, writing never allocates (it runs with interrupts off)
+struct Scrollback
+{
+    lines: VecDeque<Line>,
+    capacity: usize,
+    offset: usize,                      // lines the view is scrolled back. 0 shows the live screen
+    live: Box<[Line; BUFFER_HEIGHT]>,   // live screen while the view is scrolled back
+    cursor_visible: bool,               // hardware cursor state before scrolling back
+}
+
+// SGR state. VGA has no bold: bold selects the bright variant of the foreground color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Style
+{
+    foreground: Color,
+    background: Color,
+    bold: bool,
+    reverse: bool,
 }
 
+impl Style
+{
+    const fn new(foreground: Color, background: Color) -> Style
+    {
+        Style { foreground, background, bold: false, reverse: false }
+    }
+
+    fn color_code(&self) -> ColorCode
+    {
+        let foreground = if self.bold { self.foreground.bright() } else { self.foreground };
+        if self.reverse
+        {
+            ColorCode::new(self.background, foreground)
+        }
+        else
+        {
+            ColorCode::new(foreground, self.background)
+        }
+    }
+}
+
+impl Color
+{
+    // the 8 ANSI colors (SGR 30-37) in their order
+    fn from_ansi(index: u16) -> Color
+    {
+        match index
+        {
+            0 => Color::Black,
+            1 => Color::Red,
+            2 => Color::Green,
+            3 => Color::Brown,
+            4 => Color::Blue,
+            5 => Color::Magenta,
+            6 => Color::Cyan,
+            _ => Color::LightGray,
+        }
+    }
+
+    fn bright(self) -> Color
+    {
+        match self
+        {
+            Color::Black => Color::DarkGray,
+            Color::Blue => Color::LightBlue,
+            Color::Green => Color::LightGreen,
+            Color::Cyan => Color::LightCyan,
+            Color::Red => Color::LightRed,
+            Color::Magenta => Color::Pink,
+            Color::Brown => Color::Yellow,
+            Color::LightGray => Color::White,
+            color => color,
+        }
+    }
+}
+
+const TAB_WIDTH: usize = 8;
+
 impl Writer
 {
+    fn new(buffer: &'static mut Buffer, visible: bool) -> Writer
+    {
+        Writer
+        {
+            row_position: BUFFER_HEIGHT - 1,
+            column_position: 0,
+            color_code: ColorCode::new(Color::Yellow, Color::Black),
+            buffer,
+            visible,
+            off_screen: None,
+            cursor_hidden: false,
+            cursor_shape: CursorShape::Underline,
+            parser: Parser::new(),
+            style: Style::new(Color::Yellow, Color::Black),
+            default_style: Style::new(Color::Yellow, Color::Black),
+            saved_position: (0, 0),
+            scrollback: None,
+        }
+    }
+
+    // writes one character without moving the hardware cursor (write_string and set_position move it)
     pub fn write_byte(&mut self, byte: u8)
     {
+        self.snap_to_bottom();
         match byte
         {
-            b'\n' => self.new_line(),   // If the byte is a newline byte, it doesnt print anything
+            b'\n' => self.new_line(),  // If the byte is a newline byte, it doesnt print anything
                                         // calls new_line() instead
             byte=> 
             {
@@ -78,7 +240,7 @@ impl Writer
                 {
                     self.new_line();    
                 }
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
@@ -95,11 +257,33 @@ impl Writer
         }
     }
 
-    // If newlines and characters don't fit into the line anymore,
+    // Continues on the next row. On the last row instead
     // move every character one line up and start at beginning of last line again
     // Iterate over all screen characters and move each character one row up
     fn new_line(&mut self)  
     {
+        self.column_position = 0;
+        if self.row_position + 1 < BUFFER_HEIGHT
+        {
+            self.row_position += 1;
+            return;
+        }
+        if let Some(scrollback) = &mut self.scrollback
+        {
+            if scrollback.capacity > 0
+            {
+                if scrollback.lines.len() == scrollback.capacity
+                {
+                    scrollback.lines.pop_front();
+                }
+                let mut line = [ScreenChar { ascii_character: b' ', color_code: self.color_code }; BUFFER_WIDTH];
+                for (col, character) in line.iter_mut().enumerate()
+                {
+                    *character = self.buffer.chars[0][col].read();
+                }
+                scrollback.lines.push_back(line);
+            }
+        }
         for row in 1..BUFFER_HEIGHT
         {
             for col in 0..BUFFER_WIDTH
@@ -109,7 +293,6 @@ impl Writer
             }
         }
         self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
     }
     // Clears a row by overwritting all its characters with a space character
     fn clear_row(&mut self, row: usize)
@@ -125,16 +308,411 @@ impl Writer
         }
     }
 
-    pub fn write_string(&mut self, s: &str)   // convert to bytes and print one by one
+    // blanks the whole screen and moves to the top left corner
+    pub fn clear_screen(&mut self)
+    {
+        self.snap_to_bottom();
+        for row in 0..BUFFER_HEIGHT
+        {
+            self.clear_row(row);
+        }
+        self.set_position(0, 0);
+    }
+
+    // (row, column) the next character is written to
+    pub fn position(&self) -> (usize, usize)
+    {
+        (self.row_position, self.column_position)
+    }
+
+    // moves the writing position and the hardware cursor. Clamped to the screen
+    pub fn set_position(&mut self, row: usize, column: usize)
+    {
+        self.snap_to_bottom();
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = column.min(BUFFER_WIDTH);
+        self.update_cursor();
+    }
+
+    // column of the current row the next character is written to
+    pub fn column(&self) -> usize
+    {
+        self.column_position
+    }
+
+    // moves the writing position within the current row (e.g. to redraw an edited line)
+    pub fn set_column(&mut self, column: usize)
+    {
+        self.set_position(self.row_position, column);
+    }
+
+    // Writes `s` starting at (row, column) in `color`, without moving the writing position
+    // or the cursor. Cut off at the end of the row
+    pub fn write_at(&mut self, row: usize, column: usize, s: &str, color: ColorCode)
+    {
+        self.snap_to_bottom();
+        if row >= BUFFER_HEIGHT
+        {
+            return;
+        }
+        for (col, byte) in (column..BUFFER_WIDTH).zip(s.bytes())
+        {
+            self.buffer.chars[row][col].write(ScreenChar
+            {
+                ascii_character: printable(byte),
+                color_code: color,
+            });
+        }
+    }
+
+    // puts the hardware cursor on the writing position (behind the last column: on the last column)
+    fn update_cursor(&self)
     {
+        if !self.visible
+        {
+            return;
+        }
+        let column = self.column_position.min(BUFFER_WIDTH - 1);
+        let cell = (self.row_position * BUFFER_WIDTH + column) as u16;
+        crtc_write(CRTC_CURSOR_HIGH, (cell >> 8) as u8);
+        crtc_write(CRTC_CURSOR_LOW, cell as u8);
+    }
+
+    pub fn show_cursor(&mut self)
+    {
+        self.cursor_hidden = false;
+        if self.visible
+        {
+            crtc_write(CRTC_CURSOR_START, crtc_read(CRTC_CURSOR_START) & !CURSOR_DISABLE);
+            self.update_cursor();
+        }
+    }
+
+    pub fn hide_cursor(&mut self)
+    {
+        self.cursor_hidden = true;
+        if self.visible
+        {
+            crtc_write(CRTC_CURSOR_START, crtc_read(CRTC_CURSOR_START) | CURSOR_DISABLE);
+        }
+    }
+
+    pub fn set_cursor_shape(&mut self, shape: CursorShape)
+    {
+        self.cursor_shape = shape;
+        if self.visible
+        {
+            self.apply_cursor_shape();
+        }
+    }
+
+    fn apply_cursor_shape(&self)
+    {
+        let (start, end) = match self.cursor_shape
+        {
+            CursorShape::Underline => (SCANLINES - 2, SCANLINES - 1),
+            CursorShape::Block => (0, SCANLINES - 1),
+            CursorShape::Scanlines(start, end) => (start.min(SCANLINES - 1), end.min(SCANLINES - 1)),
+        };
+        // keep the disable bit and the reserved upper bits
+        crtc_write(CRTC_CURSOR_START, crtc_read(CRTC_CURSOR_START) & 0xe0 | start);
+        crtc_write(CRTC_CURSOR_END, crtc_read(CRTC_CURSOR_END) & 0xe0 | end);
+    }
+
+    // Keeps up to `lines` lines that scroll off the top (BUFFER_WIDTH * 2 bytes each, on the heap)
+    // 0 turns scrollback off again. Needs the heap
+    pub fn enable_scrollback(&mut self, lines: usize)
+    {
+        self.snap_to_bottom();
+        let blank = [ScreenChar { ascii_character: b' ', color_code: self.color_code }; BUFFER_WIDTH];
+        self.scrollback = if lines == 0
+        {
+            None
+        }
+        else
+        {
+            Some(Scrollback
+            {
+                lines: VecDeque::with_capacity(lines),
+                capacity: lines,
+                offset: 0,
+                live: Box::new([blank; BUFFER_HEIGHT]),
+                cursor_visible: true,
+            })
+        };
+    }
+
+    // lines kept in the scrollback
+    pub fn scrollback_len(&self) -> usize
+    {
+        self.scrollback.as_ref().map_or(0, |scrollback| scrollback.lines.len())
+    }
+
+    // lines the view is currently scrolled back (0: live screen)
+    pub fn scroll_offset(&self) -> usize
+    {
+        self.scrollback.as_ref().map_or(0, |scrollback| scrollback.offset)
+    }
+
+    // shows older lines. The live screen comes back with scroll_down or the next output
+    pub fn scroll_up(&mut self, lines: usize)
+    {
+        let offset = self.scroll_offset();
+        self.scroll_to(offset.saturating_add(lines));
+    }
+
+    pub fn scroll_down(&mut self, lines: usize)
+    {
+        let offset = self.scroll_offset();
+        self.scroll_to(offset.saturating_sub(lines));
+    }
+
+    // back to the live screen
+    pub fn snap_to_bottom(&mut self)
+    {
+        if self.scroll_offset() > 0
+        {
+            self.scroll_to(0);
+        }
+    }
+
+    fn scroll_to(&mut self, offset: usize)
+    {
+        let mut scrollback = match self.scrollback.take()
+        {
+            Some(scrollback) => scrollback,
+            None => return,
+        };
+        let offset = offset.min(scrollback.lines.len());
+        if offset == scrollback.offset
+        {
+            self.scrollback = Some(scrollback);
+            return;
+        }
+        if scrollback.offset == 0
+        {
+            // leaving the live screen: keep it and hide the cursor, it belongs to the live screen
+            for (row, line) in scrollback.live.iter_mut().enumerate()
+            {
+                for (col, character) in line.iter_mut().enumerate()
+                {
+                    *character = self.buffer.chars[row][col].read();
+                }
+            }
+            scrollback.cursor_visible = !self.cursor_hidden;
+            self.hide_cursor();
+        }
+        // view row r shows line (history length - offset + r) of history followed by the live screen
+        let first = scrollback.lines.len() - offset;
+        for row in 0..BUFFER_HEIGHT
+        {
+            let index = first + row;
+            let line = match scrollback.lines.get(index)
+            {
+                Some(line) => line,
+                None => &scrollback.live[index - scrollback.lines.len()],
+            };
+            for (col, &character) in line.iter().enumerate()
+            {
+                self.buffer.chars[row][col].write(character);
+            }
+        }
+        scrollback.offset = offset;
+        if offset == 0 && scrollback.cursor_visible
+        {
+            self.show_cursor();
+        }
+        self.scrollback = Some(scrollback);
+    }
+
+    // colors of plain text (and after SGR 0)
+    pub fn set_color(&mut self, foreground: Color, background: Color)
+    {
+        self.default_style = Style::new(foreground, background);
+        self.style = self.default_style;
+        self.color_code = self.style.color_code();
+    }
+
+    pub fn color(&self) -> ColorCode
+    {
+        self.color_code
+    }
+
+    // convert to bytes and print one by one. Escape sequences (see vga_buffer::ansi) are interpreted
+    pub fn write_string(&mut self, s: &str)
+    {
+        self.snap_to_bottom();
         for byte in s.bytes()
         {
-            match byte  // to differentiate printable ASCII bytes
+            match self.parser.advance(byte)
             {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe), // prints a white box if its unprintable
+                Some(Action::Print(byte)) => self.write_byte(printable(byte)),
+                Some(Action::Control(byte)) => self.control(byte),
+                Some(Action::Escape(byte)) => self.escape(byte),
+                Some(Action::Csi(csi)) => self.csi(&csi),
+                None => {}
             }
         }
+        self.update_cursor();
+    }
+
+    fn control(&mut self, byte: u8)
+    {
+        match byte
+        {
+            b'\n' => self.write_byte(byte),
+            b'\r' => self.column_position = 0,
+            b'\t' =>
+            {
+                let next = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column_position < next.min(BUFFER_WIDTH)
+                {
+                    self.write_byte(b' ');
+                }
+            }
+            0x08 => self.column_position = self.column_position.saturating_sub(1),
+            0x07 => {}  // bell
+            _ => self.write_byte(printable(byte)),
+        }
+    }
+
+    fn escape(&mut self, byte: u8)
+    {
+        match byte
+        {
+            b'7' => self.saved_position = self.position(),
+            b'8' => self.restore_position(),
+            // full reset
+            b'c' =>
+            {
+                self.style = self.default_style;
+                self.color_code = self.style.color_code();
+                self.clear_screen();
+            }
+            _ => {}
+        }
+    }
+
+    fn restore_position(&mut self)
+    {
+        let (row, column) = self.saved_position;
+        self.row_position = row;
+        self.column_position = column;
+    }
+
+    // CSI sequences. Rows and columns count from 1, missing counts default to 1
+    fn csi(&mut self, csi: &Csi)
+    {
+        let count = usize::from(csi.param(0, 1));
+        let (row, col) = self.position();
+        match csi.action
+        {
+            b'A' => self.row_position = row.saturating_sub(count),
+            b'B' => self.row_position = (row + count).min(BUFFER_HEIGHT - 1),
+            b'C' => self.column_position = (col + count).min(BUFFER_WIDTH - 1),
+            b'D' => self.column_position = col.min(BUFFER_WIDTH - 1).saturating_sub(count),
+            b'E' => self.set_position((row + count).min(BUFFER_HEIGHT - 1), 0),
+            b'F' => self.set_position(row.saturating_sub(count), 0),
+            b'G' => self.column_position = (count - 1).min(BUFFER_WIDTH - 1),
+            b'H' | b'f' =>
+            {
+                self.row_position = (usize::from(csi.param(0, 1)) - 1).min(BUFFER_HEIGHT - 1);
+                self.column_position = (usize::from(csi.param(1, 1)) - 1).min(BUFFER_WIDTH - 1);
+            }
+            b'J' => self.erase_screen(csi.param(0, 0)),
+            b'K' => self.erase_line(csi.param(0, 0)),
+            b'm' => self.select_graphic_rendition(csi.params()),
+            b's' => self.saved_position = self.position(),
+            b'u' => self.restore_position(),
+            // CSI ?25h / ?25l: cursor on / off
+            b'h' if csi.private && csi.param(0, 0) == 25 => self.show_cursor(),
+            b'l' if csi.private && csi.param(0, 0) == 25 => self.hide_cursor(),
+            _ => {}
+        }
+    }
+
+    fn blank(&mut self, row: usize, columns: Range<usize>)
+    {
+        let blank = ScreenChar { ascii_character: b' ', color_code: self.color_code };
+        for col in columns
+        {
+            self.buffer.chars[row][col].write(blank);
+        }
+    }
+
+    // 0: from the cursor to the end, 1: from the start to the cursor, 2: whole line
+    fn erase_line(&mut self, mode: u16)
+    {
+        let (row, col) = self.position();
+        let col = col.min(BUFFER_WIDTH - 1);
+        match mode
+        {
+            0 => self.blank(row, col..BUFFER_WIDTH),
+            1 => self.blank(row, 0..col + 1),
+            _ => self.blank(row, 0..BUFFER_WIDTH),
+        }
+    }
+
+    // like erase_line for the whole screen. The position does not change
+    fn erase_screen(&mut self, mode: u16)
+    {
+        let row = self.row_position;
+        let (before, after) = match mode
+        {
+            0 => (row..row, row + 1..BUFFER_HEIGHT),
+            1 => (0..row, row..row),
+            _ => (0..BUFFER_HEIGHT, 0..0),
+        };
+        if mode < 2
+        {
+            self.erase_line(mode);
+        }
+        for row in before.chain(after)
+        {
+            self.blank(row, 0..BUFFER_WIDTH);
+        }
+    }
+
+    fn select_graphic_rendition(&mut self, params: &[u16])
+    {
+        let mut params = params.iter();
+        while let Some(&param) = params.next()
+        {
+            let style = &mut self.style;
+            match param
+            {
+                0 => *style = self.default_style,
+                1 => style.bold = true,
+                22 => style.bold = false,
+                7 => style.reverse = true,
+                27 => style.reverse = false,
+                30..=37 => style.foreground = Color::from_ansi(param - 30),
+                39 => style.foreground = self.default_style.foreground,
+                40..=47 => style.background = Color::from_ansi(param - 40),
+                49 => style.background = self.default_style.background,
+                90..=97 => style.foreground = Color::from_ansi(param - 90).bright(),
+                100..=107 => style.background = Color::from_ansi(param - 100).bright(),
+                // 256 colors (38;5;n) and true color (38;2;r;g;b) have no VGA equivalent: arguments skipped
+                38 | 48 => match params.next()
+                {
+                    Some(5) => { params.next(); }
+                    Some(2) => { params.nth(2); }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        self.color_code = self.style.color_code();
+    }
+}
+
+// printable ASCII bytes stay, everything else becomes a white box
+fn printable(byte: u8) -> u8
+{
+    match byte
+    {
+        0x20..=0x7e => byte,
+        _ => 0xfe,
     }
 }
 
@@ -152,12 +730,8 @@ impl fmt::Write for Writer
 // lazy_static initializes itself when accessed for the first time
 lazy_static!
 {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer
-    {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe {&mut *(0xb8000 as *mut Buffer)},
-    });
+    // console 1, the one visible at boot. print! writes here
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new(unsafe {&mut *(0xb8000 as *mut Buffer)}, true));
 }
 
 #[macro_export]
@@ -189,6 +763,31 @@ pub fn _print(args: fmt::Arguments)
     });
 }
 
+// Turns on scrollback of WRITER with room for `lines` lines. Call after the heap is initialized
+pub fn enable_scrollback(lines: usize)
+{
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| WRITER.lock().enable_scrollback(lines));
+}
+
+// Scrolls the view of the visible console by `lines` (positive: back to older lines)
+// Used for Shift+PageUp/PageDown
+pub fn scroll(lines: isize)
+{
+    console::with(console::active(), |writer|
+    {
+        if lines >= 0
+        {
+            writer.scroll_up(lines.unsigned_abs());
+        }
+        else
+        {
+            writer.scroll_down(lines.unsigned_abs());
+        }
+    });
+}
+
 #[test_case]
 fn test_println_output() 
 {
@@ -211,3 +810,62 @@ fn test_println_output()
         }
     });
 }
+
+#[test_case]
+fn test_write_at_and_position()
+{
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let color = ColorCode::new(Color::White, Color::Blue);
+        let before = writer.position();
+        writer.write_at(3, 78, "abc", color);   // cut off at the end of the row
+        assert_eq!(writer.position(), before);
+        assert_eq!(writer.buffer.chars[3][78].read(), ScreenChar { ascii_character: b'a', color_code: color });
+        assert_eq!(writer.buffer.chars[3][79].read().ascii_character, b'b');
+
+        writer.set_position(5, 10);
+        writer.write_string("x\ny");
+        assert_eq!(writer.buffer.chars[5][10].read().ascii_character, b'x');
+        assert_eq!(writer.buffer.chars[6][0].read().ascii_character, b'y');
+        assert_eq!(writer.position(), (6, 1));
+
+        writer.clear_screen();
+        assert_eq!(writer.position(), (0, 0));
+        assert_eq!(writer.buffer.chars[6][0].read().ascii_character, b' ');
+        // back to writing on the last row
+        writer.set_position(BUFFER_HEIGHT - 1, 0);
+    });
+}
+
+#[test_case]
+fn test_ansi_sequences()
+{
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let default = writer.color();
+        // move to row 3 column 5, red on default background, then reset
+        writer.write_string("\x1b[4;6H\x1b[31mE\x1b[0mok");
+        assert_eq!(writer.buffer.chars[3][5].read(), ScreenChar { ascii_character: b'E', color_code: ColorCode::new(Color::Red, Color::Black) });
+        assert_eq!(writer.buffer.chars[3][6].read(), ScreenChar { ascii_character: b'o', color_code: default });
+
+        // save, move, restore; bold bright colors; erase to end of line
+        writer.write_string("\x1b[s\x1b[10;1H\x1b[1;32mG\x1b[u\x1b[0m\x1b[K");
+        assert_eq!(writer.buffer.chars[9][0].read().color_code, ColorCode::new(Color::LightGreen, Color::Black));
+        assert_eq!(writer.position(), (3, 8));
+        assert_eq!(writer.buffer.chars[3][5].read().ascii_character, b'E');
+
+        // carriage return, cursor left, unknown sequence swallowed
+        writer.write_string("\rab\x1b[1Dc\x1b[?1049hd");
+        assert_eq!(writer.buffer.chars[3][0].read().ascii_character, b'a');
+        assert_eq!(writer.buffer.chars[3][1].read().ascii_character, b'c');
+        assert_eq!(writer.buffer.chars[3][2].read().ascii_character, b'd');
+
+        writer.write_string("\x1b[2J");
+        assert_eq!(writer.buffer.chars[3][0].read().ascii_character, b' ');
+        writer.set_position(BUFFER_HEIGHT - 1, 0);
+    });
+}
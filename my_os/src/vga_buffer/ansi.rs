@@ -0,0 +1,158 @@
+// Escape sequence parser for the VGA writer (subset of VT100 / ECMA-48)
+//
+// Parser::advance takes one byte at a time and returns what the writer has to do.
+// understood: C0 controls, ESC 7 / ESC 8, CSI sequences with numeric parameters.
+// Unknown sequences are consumed without effect, so they never show up as garbage
+
+const ESC: u8 = 0x1b;
+
+// parameters kept per CSI sequence, further ones are dropped
+pub const MAX_PARAMS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi
+{
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    pub private: bool,      // '?' after the CSI (DEC private modes)
+    pub action: u8,         // final byte, e.g. b'H' or b'm'
+}
+
+impl Csi
+{
+    // parameter `index`, `default` if missing or 0
+    pub fn param(&self, index: usize, default: u16) -> u16
+    {
+        match self.params[..self.len].get(index)
+        {
+            Some(&0) | None => default,
+            Some(&value) => value,
+        }
+    }
+
+    // all given parameters (an empty list counts as one 0)
+    pub fn params(&self) -> &[u16]
+    {
+        if self.len == 0 { &[0] } else { &self.params[..self.len] }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action
+{
+    Print(u8),          // character to put on screen
+    Control(u8),        // C0 control character (newline, carriage return, ...)
+    Escape(u8),         // ESC followed by this byte
+    Csi(Csi),           // complete control sequence
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State
+{
+    Ground,
+    Escape,
+    Csi,
+    Ignore,     // invalid CSI: skipped up to its final byte
+}
+
+pub struct Parser
+{
+    state: State,
+    csi: Csi,
+}
+
+impl Parser
+{
+    pub const fn new() -> Self
+    {
+        Parser
+        {
+            state: State::Ground,
+            csi: Csi { params: [0; MAX_PARAMS], len: 0, private: false, action: 0 },
+        }
+    }
+
+    pub fn advance(&mut self, byte: u8) -> Option<Action>
+    {
+        // ESC always starts over, even inside a sequence
+        if byte == ESC
+        {
+            self.state = State::Escape;
+            return None;
+        }
+        match self.state
+        {
+            State::Ground => match byte
+            {
+                0x00..=0x1f | 0x7f => Some(Action::Control(byte)),
+                _ => Some(Action::Print(byte)),
+            },
+            State::Escape if byte == b'[' =>
+            {
+                self.state = State::Csi;
+                self.csi = Csi { params: [0; MAX_PARAMS], len: 0, private: false, action: 0 };
+                None
+            }
+            State::Escape =>
+            {
+                self.state = State::Ground;
+                Some(Action::Escape(byte))
+            }
+            State::Csi => self.csi_byte(byte),
+            State::Ignore =>
+            {
+                if (0x40..=0x7e).contains(&byte)
+                {
+                    self.state = State::Ground;
+                }
+                None
+            }
+        }
+    }
+
+    fn csi_byte(&mut self, byte: u8) -> Option<Action>
+    {
+        let csi = &mut self.csi;
+        match byte
+        {
+            b'0'..=b'9' =>
+            {
+                if csi.len == 0
+                {
+                    csi.len = 1;
+                }
+                if csi.len <= MAX_PARAMS
+                {
+                    let param = &mut csi.params[csi.len - 1];
+                    *param = param.saturating_mul(10).saturating_add(u16::from(byte - b'0'));
+                }
+                None
+            }
+            b';' =>
+            {
+                // an empty first parameter still counts
+                csi.len = (csi.len.max(1) + 1).min(MAX_PARAMS + 1);
+                None
+            }
+            b'?' if csi.len == 0 =>
+            {
+                csi.private = true;
+                None
+            }
+            0x40..=0x7e =>
+            {
+                self.state = State::Ground;
+                csi.len = csi.len.min(MAX_PARAMS);
+                csi.action = byte;
+                Some(Action::Csi(*csi))
+            }
+            // controls inside a sequence are executed right away
+            0x00..=0x1f => Some(Action::Control(byte)),
+            _ =>
+            {
+                self.state = State::Ignore;
+                None
+            }
+        }
+    }
+}
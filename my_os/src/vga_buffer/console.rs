@@ -0,0 +1,194 @@
+// Virtual consoles on the VGA text screen, switched with Alt+F1..F6
+//
+// Every console is a Writer with its own screen contents, position, colors, cursor and scrollback.
+// The visible console writes to the VGA buffer, hidden ones into an off-screen buffer on the heap.
+// switch_to copies the screens and hands the VGA buffer over, so writing never has to check
+// which console is visible. The visible console has the input focus (see has_focus).
+//
+// console 1 (index SHELL) is vga_buffer::WRITER, print! writes there. log! writes to console 6 (LOG)
+// and to the serial port with serial::Role::Log
+
+use alloc::{boxed::Box, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use volatile::Volatile;
+use x86_64::instructions::interrupts;
+
+use super::{Buffer, Color, ScreenChar, Writer, BUFFER_HEIGHT, BUFFER_WIDTH, CURSOR_DISABLE, CRTC_CURSOR_START, WRITER};
+use super::{crtc_read, crtc_write};
+use crate::serial::{self, Role};
+
+// Alt+F1 .. Alt+F6
+pub const CONSOLES: usize = 6;
+// interactive console (WRITER, print!, read_line)
+pub const SHELL: usize = 0;
+// kernel log (log!)
+pub const LOG: usize = 5;
+
+// consoles 2..CONSOLES, created by init
+static HIDDEN: OnceCell<Vec<Mutex<Writer>>> = OnceCell::uninit();
+static ACTIVE: AtomicUsize = AtomicUsize::new(SHELL);
+
+fn off_screen_buffer() -> &'static mut Buffer
+{
+    let blank = ScreenChar { ascii_character: b' ', color_code: super::ColorCode::new(Color::Yellow, Color::Black) };
+    let buffer = Box::new(Buffer
+    {
+        chars: core::array::from_fn(|_| core::array::from_fn(|_| Volatile::new(blank))),
+    });
+    // consoles live as long as the kernel
+    Box::leak(buffer)
+}
+
+// Creates the consoles 2..CONSOLES (2 * BUFFER_WIDTH * BUFFER_HEIGHT bytes of heap each, plus one
+// for WRITER). Needs the heap. Before, only console 1 exists and log! writes there as well
+pub fn init()
+{
+    if HIDDEN.is_initialized()
+    {
+        return;
+    }
+    let consoles = (1..CONSOLES).map(|_| Mutex::new(Writer::new(off_screen_buffer(), false))).collect();
+    let spare = off_screen_buffer();
+    interrupts::without_interrupts(move ||
+    {
+        if HIDDEN.try_init_once(|| consoles).is_ok()
+        {
+            WRITER.lock().off_screen = Some(spare);
+        }
+    });
+}
+
+fn console(index: usize) -> Option<&'static Mutex<Writer>>
+{
+    match index
+    {
+        SHELL => Some(&WRITER),
+        _ => HIDDEN.get()?.get(index - 1),
+    }
+}
+
+// Runs `f` on the writer of console `index` with interrupts disabled
+// None if the console does not exist (index >= CONSOLES or before init)
+pub fn with<R>(index: usize, f: impl FnOnce(&mut Writer) -> R) -> Option<R>
+{
+    let console = console(index)?;
+    Some(interrupts::without_interrupts(|| f(&mut console.lock())))
+}
+
+// visible console, it has the input focus
+pub fn active() -> usize
+{
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+// whether key presses are meant for console `index`
+pub fn has_focus(index: usize) -> bool
+{
+    active() == index
+}
+
+// Makes console `index` visible and gives it the input focus. False if it does not exist
+pub fn switch_to(index: usize) -> bool
+{
+    let new = match console(index)
+    {
+        Some(console) => console,
+        None => return false,
+    };
+    interrupts::without_interrupts(||
+    {
+        let old = active();
+        if old == index
+        {
+            return;
+        }
+        let old = console(old).expect("active console exists");
+        let (mut old, mut new) = (old.lock(), new.lock());
+        let vga = old.detach();
+        new.attach(vga);
+        ACTIVE.store(index, Ordering::Relaxed);
+    });
+    true
+}
+
+fn copy_screen(from: &Buffer, to: &mut Buffer)
+{
+    for (from, to) in from.chars.iter().zip(to.chars.iter_mut())
+    {
+        for (from, to) in from.iter().zip(to.iter_mut())
+        {
+            to.write(from.read());
+        }
+    }
+}
+
+impl Writer
+{
+    // saves the screen into the off-screen buffer, which becomes the writing target. Returns the VGA buffer
+    fn detach(&mut self) -> &'static mut Buffer
+    {
+        self.snap_to_bottom();
+        let off_screen = self.off_screen.take().expect("visible console without off-screen buffer");
+        copy_screen(self.buffer, off_screen);
+        self.visible = false;
+        mem::replace(&mut self.buffer, off_screen)
+    }
+
+    // shows the screen in `vga` and writes there from now on. Restores the hardware cursor
+    fn attach(&mut self, vga: &'static mut Buffer)
+    {
+        copy_screen(self.buffer, vga);
+        self.off_screen = Some(mem::replace(&mut self.buffer, vga));
+        self.visible = true;
+        self.apply_cursor_shape();
+        let start = crtc_read(CRTC_CURSOR_START);
+        if self.cursor_hidden
+        {
+            crtc_write(CRTC_CURSOR_START, start | CURSOR_DISABLE);
+        }
+        else
+        {
+            crtc_write(CRTC_CURSOR_START, start & !CURSOR_DISABLE);
+        }
+        self.update_cursor();
+    }
+}
+
+#[macro_export]
+macro_rules! log
+{
+    ($($arg:tt)*) => ($crate::vga_buffer::console::_log(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+// writes to the log console, to console 1 before init. And to the serial log sink, if there is one
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments)
+{
+    use core::fmt::Write;
+
+    let index = if HIDDEN.is_initialized() { LOG } else { SHELL };
+    with(index, |writer| writer.write_fmt(args).unwrap());
+    serial::print_to(Role::Log, args);
+}
+
+// characters of row `row` of console `index`, visible or not
+pub fn read_row(index: usize, row: usize) -> Option<[u8; BUFFER_WIDTH]>
+{
+    if row >= BUFFER_HEIGHT
+    {
+        return None;
+    }
+    with(index, |writer|
+    {
+        let mut characters = [0; BUFFER_WIDTH];
+        for (col, character) in characters.iter_mut().enumerate()
+        {
+            *character = writer.buffer.chars[row][col].read().ascii_character;
+        }
+        characters
+    })
+}
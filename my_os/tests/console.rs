@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::{log, println};
+use my_os::vga_buffer::{self, console, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use x86_64::instructions::interrupts;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+// scrollback and the virtual consoles live on the heap
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    console::init();
+
+    test_main();
+    loop{}
+}
+
+// first character of a screen row, read from VGA memory
+fn screen_char(row: usize) -> u8
+{
+    let cell = 0xb8000 as *const u16;
+    unsafe { cell.add(row * BUFFER_WIDTH).read_volatile() as u8 }
+}
+
+#[test_case]
+fn scrollback_ring()
+{
+    vga_buffer::enable_scrollback(30);
+    // letters a.. on 60 lines: the first ones scroll off into the ring
+    for i in 0..60u8
+    {
+        println!("{}", char::from(b'a' + i % 26));
+    }
+    let (kept, bottom) = interrupts::without_interrupts(||
+    {
+        let writer = WRITER.lock();
+        (writer.scrollback_len(), writer.position())
+    });
+    assert_eq!(kept, 30);
+    assert_eq!(bottom, (BUFFER_HEIGHT - 1, 0));
+    // line 59 is the last printed one, directly above the empty last row
+    assert_eq!(screen_char(BUFFER_HEIGHT - 2), b'a' + 59 % 26);
+
+    vga_buffer::scroll(10);
+    assert_eq!(screen_char(BUFFER_HEIGHT - 2), b'a' + 49 % 26);
+    // past the oldest kept line: stops there
+    vga_buffer::scroll(100);
+    let offset = interrupts::without_interrupts(|| WRITER.lock().scroll_offset());
+    assert_eq!(offset, 30);
+
+    vga_buffer::scroll(-25);
+    assert_eq!(screen_char(BUFFER_HEIGHT - 2), b'a' + 54 % 26);
+
+    // new output snaps back to the live screen
+    println!("z");
+    let offset = interrupts::without_interrupts(|| WRITER.lock().scroll_offset());
+    assert_eq!(offset, 0);
+    assert_eq!(screen_char(BUFFER_HEIGHT - 2), b'z');
+    assert_eq!(screen_char(BUFFER_HEIGHT - 3), b'a' + 59 % 26);
+}
+
+#[test_case]
+fn virtual_consoles()
+{
+    assert_eq!(console::active(), console::SHELL);
+    println!("shell");
+    log!("logged");
+    console::with(2, |writer| writer.write_string("third\n")).expect("console 3 exists");
+    assert!(console::with(console::CONSOLES, |_| ()).is_none());
+
+    // hidden consoles keep their own screen
+    assert_eq!(screen_char(BUFFER_HEIGHT - 2), b's');
+    assert_eq!(console::read_row(console::LOG, BUFFER_HEIGHT - 2).unwrap()[0], b'l');
+
+    assert!(console::switch_to(2));
+    assert!(console::has_focus(2));
+    assert_eq!(screen_char(BUFFER_HEIGHT - 2), b't');
+    // print! still goes to the shell console, off-screen now
+    println!("back");
+    assert_eq!(screen_char(BUFFER_HEIGHT - 2), b't');
+    assert_eq!(console::read_row(console::SHELL, BUFFER_HEIGHT - 2).unwrap()[0], b'b');
+
+    assert!(!console::switch_to(console::CONSOLES));
+    assert!(console::switch_to(console::SHELL));
+    assert_eq!(screen_char(BUFFER_HEIGHT - 2), b'b');
+    assert_eq!(screen_char(BUFFER_HEIGHT - 3), b's');
+}
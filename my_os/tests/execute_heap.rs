@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use my_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+entry_point!(main);
+
+// Places a `ret` instruction on the heap and jumps to it
+// heap pages are mapped NO_EXECUTE, so the instruction fetch must page fault
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("execute_heap::execute_heap...\t");
+
+    my_os::gdt::init();
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    unsafe { memory::protect_kernel(&mut mapper) }
+        .expect("kernel section protection failed");
+
+    let code = Box::new([0xc3u8; 16]); // ret
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    panic!("executing heap memory did not page fault");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable =
+    {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+// fetch from a present NX page -> PROTECTION_VIOLATION and INSTRUCTION_FETCH must both be set
+extern "x86-interrupt" fn test_page_fault_handler(_stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
+{
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH)
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    else
+    {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault {:?}\n", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,333 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use my_os::task::{executor::{Executor, TaskState}, JoinError, Priority, Task};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop{}
+}
+
+// task spawned through a Spawner from inside a task runs in the next round, not the current one
+#[test_case]
+fn spawner_from_task()
+{
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let counter = Arc::new(AtomicUsize::new(0));
+
+    let child_counter = counter.clone();
+    executor.spawn(Task::new(async move
+    {
+        let counter = child_counter.clone();
+        spawner.spawn(async move
+        {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        child_counter.fetch_add(1, Ordering::SeqCst);
+    }));
+
+    executor.run_ready_tasks();
+    assert_eq!(counter.load(Ordering::SeqCst), 1);
+    executor.run_ready_tasks();
+    assert_eq!(counter.load(Ordering::SeqCst), 2);
+}
+
+// cloned spawners all feed the same executor
+#[test_case]
+fn cloned_spawners()
+{
+    let mut executor = Executor::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let spawner = executor.spawner();
+    for _ in 0..10
+    {
+        let counter = counter.clone();
+        spawner.clone().spawn(async move
+        {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+    executor.run_ready_tasks();
+    assert_eq!(counter.load(Ordering::SeqCst), 10);
+}
+
+// awaiting a JoinHandle yields the output of the spawned future
+#[test_case]
+fn join_handle_output()
+{
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let result = Arc::new(AtomicUsize::new(0));
+
+    let parent_result = result.clone();
+    executor.spawn(Task::new(async move
+    {
+        let handle = spawner.spawn(async { 6 * 7 });
+        let value = handle.await.expect("task was cancelled");
+        parent_result.store(value, Ordering::SeqCst);
+    }));
+
+    for _ in 0..3
+    {
+        executor.run_ready_tasks();
+    }
+    assert_eq!(result.load(Ordering::SeqCst), 42);
+}
+
+// aborted task never runs to completion and its handle reports Cancelled
+#[test_case]
+fn join_handle_abort()
+{
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+    let ran = Arc::new(AtomicUsize::new(0));
+    let result = Arc::new(AtomicUsize::new(0));
+
+    let child_ran = ran.clone();
+    let handle = spawner.spawn(async move
+    {
+        child_ran.fetch_add(1, Ordering::SeqCst);
+    });
+    handle.abort();
+
+    let parent_result = result.clone();
+    executor.spawn(Task::new(async move
+    {
+        if handle.await == Err(JoinError::Cancelled)
+        {
+            parent_result.store(1, Ordering::SeqCst);
+        }
+    }));
+
+    for _ in 0..3
+    {
+        executor.run_ready_tasks();
+    }
+    assert_eq!(ran.load(Ordering::SeqCst), 0);
+    assert_eq!(result.load(Ordering::SeqCst), 1);
+}
+
+// more tasks than the ready queue capacity: no panic, every task still runs
+#[test_case]
+fn spawn_beyond_queue_capacity()
+{
+    let mut executor = Executor::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    // enough to overflow, few enough for the heap: every task is alive until the executor runs
+    let n = executor.queue_stats().capacity + 20;
+    for _ in 0..n
+    {
+        let counter = counter.clone();
+        executor.spawn(Task::new(async move
+        {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    executor.run_ready_tasks();
+    assert_eq!(counter.load(Ordering::SeqCst), n);
+
+    let stats = executor.queue_stats();
+    assert!(stats.overflows > 0);
+    assert_eq!(stats.tasks, 0);
+}
+
+// future that wakes itself `wakes` times per poll until it was polled `polls` times
+struct SelfWaking
+{
+    polls: Arc<AtomicUsize>,
+    remaining: usize,
+    wakes: usize,
+}
+
+impl Future for SelfWaking
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>
+    {
+        self.polls.fetch_add(1, Ordering::SeqCst);
+        if self.remaining == 0
+        {
+            return Poll::Ready(());
+        }
+        self.remaining -= 1;
+        for _ in 0..self.wakes
+        {
+            cx.waker().wake_by_ref();
+        }
+        Poll::Pending
+    }
+}
+
+// several wakeups before the next poll queue the task only once
+#[test_case]
+fn wakeups_are_deduplicated()
+{
+    let mut executor = Executor::new();
+    let polls = Arc::new(AtomicUsize::new(0));
+    executor.spawn(Task::new(SelfWaking { polls: polls.clone(), remaining: 1, wakes: 10 }));
+
+    executor.run_ready_tasks();
+    assert_eq!(polls.load(Ordering::SeqCst), 2);
+
+    let stats = executor.queue_stats();
+    assert_eq!(stats.deduplicated, 9);
+    assert_eq!(stats.tasks, 0);
+}
+
+// future that records `id` in `log` every time it is polled, self waking until polled `polls` times
+fn logging_task(log: &Arc<Mutex<Vec<usize>>>, id: usize, polls: usize) -> impl Future<Output = ()> + Send
+{
+    let log = log.clone();
+    let counter = Arc::new(AtomicUsize::new(0));
+    async move
+    {
+        let mut waking = SelfWaking { polls: counter, remaining: polls - 1, wakes: 1 };
+        core::future::poll_fn(|cx|
+        {
+            log.lock().push(id);
+            Pin::new(&mut waking).poll(cx)
+        }).await
+    }
+}
+
+// ready tasks of a higher priority run first, regardless of spawn order
+#[test_case]
+fn higher_priority_first()
+{
+    let mut executor = Executor::new();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    executor.spawn(Task::new(logging_task(&log, 2, 1)).with_priority(Priority::Background));
+    executor.spawn(Task::new(logging_task(&log, 1, 1)).with_priority(Priority::Interactive));
+    executor.spawn(Task::new(logging_task(&log, 0, 1)).with_priority(Priority::Realtime));
+    executor.run_ready_tasks();
+    assert_eq!(*log.lock(), [0, 1, 2]);
+}
+
+// a task waking itself forever is polled at most a few times per round
+#[test_case]
+fn poll_budget_per_round()
+{
+    let mut executor = Executor::new();
+    let polls = Arc::new(AtomicUsize::new(0));
+    executor.spawn(Task::new(SelfWaking { polls: polls.clone(), remaining: usize::MAX, wakes: 1 }));
+
+    executor.run_ready_tasks();
+    let first_round = polls.load(Ordering::SeqCst);
+    assert!(first_round > 0 && first_round < 10);
+
+    executor.run_ready_tasks();
+    assert_eq!(polls.load(Ordering::SeqCst), 2 * first_round);
+}
+
+// busy interactive tasks do not keep a background task waiting for the whole round
+#[test_case]
+fn background_not_starved()
+{
+    let mut executor = Executor::new();
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let busy = 20;
+    for id in 0..busy
+    {
+        executor.spawn(Task::new(logging_task(&log, id, 1000)));
+    }
+    executor.spawn(Task::new(logging_task(&log, busy, 1)).with_priority(Priority::Background));
+    executor.run_ready_tasks();
+
+    let log = log.lock();
+    let position = log.iter().position(|&id| id == busy).expect("background task never ran");
+    assert!(position < busy * 2, "background task ran only after {} polls", position);
+}
+
+// stats report names, poll and wakeup counts and whether a task is waiting
+#[test_case]
+fn executor_stats()
+{
+    let mut executor = Executor::new();
+    let polls = Arc::new(AtomicUsize::new(0));
+    executor.spawn(Task::new(core::future::pending::<()>()).with_name("idle"));
+    executor.spawn(Task::new(SelfWaking { polls, remaining: usize::MAX, wakes: 1 }).with_name("spinner"));
+    executor.run_ready_tasks();
+
+    let stats = executor.stats();
+    assert_eq!(stats.tasks.len(), 2);
+
+    let idle = stats.tasks.iter().find(|task| task.name == "idle").unwrap();
+    assert_eq!(idle.polls, 1);
+    assert_eq!(idle.state, TaskState::Waiting);
+
+    let spinner = stats.tasks.iter().find(|task| task.name == "spinner").unwrap();
+    assert!(spinner.polls > idle.polls);
+    assert!(spinner.wakeups >= spinner.polls);
+    assert_eq!(spinner.state, TaskState::Ready);
+    assert!(spinner.poll_cycles > 0);
+}
+
+// a StatsHandle gets stats only when it asks, built at the end of the round
+#[test_case]
+fn stats_handle()
+{
+    let mut executor = Executor::new();
+    let handle = executor.stats_handle();
+    executor.spawn(Task::new(core::future::pending::<()>()).with_name("idle"));
+    executor.run_ready_tasks();
+    assert!(handle.last().is_none());
+
+    handle.request();
+    executor.run_ready_tasks();
+    let stats = handle.last().unwrap();
+    assert_eq!(stats.tasks.len(), 1);
+    assert_eq!(stats.tasks[0].name, "idle");
+    assert_eq!(stats.tasks[0].polls, 1);
+
+    // a task of the executor itself waits for the end of the round
+    let seen = Arc::new(AtomicUsize::new(0));
+    let task_seen = seen.clone();
+    executor.spawn(Task::new(async move
+    {
+        let stats = handle.fetch().await;
+        task_seen.store(stats.tasks.len(), Ordering::SeqCst);
+    }).with_name("ps"));
+    executor.run_ready_tasks();
+    executor.run_ready_tasks();
+    // idle and ps itself
+    assert_eq!(seen.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,195 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use futures_util::stream::StreamExt;
+use my_os::interrupts::{self, IrqReturn, PIC_1_OFFSET};
+use my_os::task::executor::Executor;
+use my_os::task::irq::{IrqEvent, IrqStream};
+use my_os::task::Task;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop{}
+}
+
+// values beyond the capacity are dropped and counted
+#[test_case]
+fn stream_overflow_accounting()
+{
+    let (sender, mut stream) = IrqStream::new(4);
+    for i in 0..6u8
+    {
+        assert_eq!(sender.push(i), i < 4);
+    }
+    let stats = stream.stats();
+    assert_eq!((stats.received, stats.dropped, stats.queued), (6, 2, 4));
+    assert_eq!(stream.try_next(), Some(0));
+}
+
+// task reading the stream is woken by push
+#[test_case]
+fn stream_wakes_task()
+{
+    let mut executor = Executor::new();
+    let (sender, mut stream) = IrqStream::new(8);
+    let sum = Arc::new(AtomicUsize::new(0));
+
+    let total = sum.clone();
+    executor.spawn(Task::new(async move
+    {
+        while let Some(value) = stream.next().await
+        {
+            total.fetch_add(value, Ordering::SeqCst);
+        }
+    }));
+    executor.run_ready_tasks();
+    sender.push(3);
+    sender.push(4);
+    executor.run_ready_tasks();
+    assert_eq!(sum.load(Ordering::SeqCst), 7);
+}
+
+// signals before the wait are reported together
+#[test_case]
+fn event_coalesces_signals()
+{
+    let mut executor = Executor::new();
+    let (signal, mut event) = IrqEvent::new();
+    let seen = Arc::new(AtomicUsize::new(0));
+
+    signal.signal();
+    signal.signal();
+    let count = seen.clone();
+    executor.spawn(Task::new(async move
+    {
+        let signals = event.wait().await;
+        count.store(signals as usize, Ordering::SeqCst);
+        assert_eq!(event.stats().coalesced, 1);
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(seen.load(Ordering::SeqCst), 2);
+}
+
+// register installs the handler on the IDT vector of the line
+#[test_case]
+fn registered_handler_runs()
+{
+    let mut event = IrqEvent::register(11, || IrqReturn::Handled);
+    unsafe { asm!("int {}", const PIC_1_OFFSET + 11) };
+    assert_eq!(event.stats().signals, 1);
+
+    let mut executor = Executor::new();
+    let seen = Arc::new(AtomicUsize::new(0));
+    let count = seen.clone();
+    executor.spawn(Task::new(async move
+    {
+        count.store(event.wait().await as usize, Ordering::SeqCst);
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(seen.load(Ordering::SeqCst), 1);
+}
+
+// all handlers on a shared line run, unclaimed interrupts are counted
+#[test_case]
+fn shared_irq_chaining()
+{
+    let calls = Arc::new(AtomicUsize::new(0));
+    let first_calls = calls.clone();
+    let first = interrupts::register_irq(10, move ||
+    {
+        first_calls.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::NotMine
+    });
+    let second_calls = calls.clone();
+    let second = interrupts::register_irq(10, move ||
+    {
+        second_calls.fetch_add(10, Ordering::SeqCst);
+        IrqReturn::Handled
+    });
+
+    let fired = interrupts::interrupt_count(PIC_1_OFFSET + 10);
+    unsafe { asm!("int {}", const PIC_1_OFFSET + 10) };
+    assert_eq!(calls.load(Ordering::SeqCst), 11);
+    assert_eq!(interrupts::unhandled_count(10), 0);
+
+    assert!(interrupts::unregister_irq(second));
+    assert!(!interrupts::unregister_irq(second));
+    unsafe { asm!("int {}", const PIC_1_OFFSET + 10) };
+    assert_eq!(calls.load(Ordering::SeqCst), 12);
+    assert_eq!(interrupts::unhandled_count(10), 1);
+    assert_eq!(interrupts::interrupt_count(PIC_1_OFFSET + 10), fired + 2);
+
+    interrupts::unregister_irq(first);
+}
+
+// `int` does not go through the PIC, so the in-service bit of IRQ 7 is clear: looks exactly like a spurious IRQ 7
+#[test_case]
+fn spurious_irq7_detected()
+{
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handler_calls = calls.clone();
+    let handle = interrupts::register_irq(7, move ||
+    {
+        handler_calls.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::Handled
+    });
+
+    let spurious = interrupts::spurious_count();
+    let fired = interrupts::interrupt_count(PIC_1_OFFSET + 7);
+    unsafe { asm!("int {}", const PIC_1_OFFSET + 7) };
+    assert_eq!(interrupts::spurious_count(), spurious + 1);
+    assert_eq!(interrupts::interrupt_count(PIC_1_OFFSET + 7), fired);
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+    interrupts::unregister_irq(handle);
+}
+
+#[test_case]
+fn interrupt_report()
+{
+    let handle = interrupts::register_irq(9, || IrqReturn::Handled);
+    unsafe { asm!("int {}", const PIC_1_OFFSET + 9) };
+
+    let report = interrupts::report();
+    assert!(report.lines[9].count >= 1);
+    assert_eq!(report.lines[9].handlers, 1);
+    let text = alloc::format!("{}", report);
+    assert!(text.contains("CPU0"));
+    assert!(text.contains("   9:"));
+    assert!(text.contains("SPU:"));
+
+    interrupts::unregister_irq(handle);
+}
@@ -0,0 +1,266 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+use my_os::task::keyboard::{self, ActiveLayout, ActiveScancodeSet, Edit, History, KeyEventStream, Layout, LineEditor,
+    ScancodeSetKind};
+use futures_util::stream::Stream;
+use my_os::time;
+use x86_64::instructions::port::Port;
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Keyboard};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop{}
+}
+
+fn type_str(editor: &mut LineEditor, history: &History, text: &str)
+{
+    for character in text.chars()
+    {
+        editor.handle_key(DecodedKey::Unicode(character), history);
+    }
+}
+
+fn raw(editor: &mut LineEditor, history: &History, key: KeyCode) -> Edit
+{
+    editor.handle_key(DecodedKey::RawKey(key), history)
+}
+
+#[test_case]
+fn insert_and_move_cursor()
+{
+    let history = History::new(4);
+    let mut editor = LineEditor::new(40);
+    type_str(&mut editor, &history, "helo");
+    raw(&mut editor, &history, KeyCode::ArrowLeft);
+    type_str(&mut editor, &history, "l");
+    assert_eq!(editor.line(), "hello");
+    assert_eq!(editor.cursor(), 4);
+
+    raw(&mut editor, &history, KeyCode::Home);
+    assert_eq!(editor.cursor(), 0);
+    assert_eq!(raw(&mut editor, &history, KeyCode::ArrowLeft), Edit::Unchanged);
+    raw(&mut editor, &history, KeyCode::End);
+    assert_eq!(editor.cursor(), 5);
+    assert_eq!(editor.handle_key(DecodedKey::Unicode('\n'), &history), Edit::Submit("hello".into()));
+}
+
+#[test_case]
+fn backspace_delete_and_kill()
+{
+    let history = History::new(4);
+    let mut editor = LineEditor::new(40);
+    type_str(&mut editor, &history, "abcdef");
+    raw(&mut editor, &history, KeyCode::Home);
+    raw(&mut editor, &history, KeyCode::ArrowRight);
+    // backspace removes 'a', delete removes 'c'
+    type_str(&mut editor, &history, "\u{8}");
+    raw(&mut editor, &history, KeyCode::ArrowRight);
+    type_str(&mut editor, &history, "\u{7f}");
+    assert_eq!(editor.line(), "bdef");
+    assert_eq!(editor.cursor(), 1);
+
+    raw(&mut editor, &history, KeyCode::ArrowRight);
+    // Ctrl-U deletes everything before the cursor
+    type_str(&mut editor, &history, "\u{15}");
+    assert_eq!(editor.line(), "ef");
+    assert_eq!(editor.cursor(), 0);
+    assert_eq!(editor.handle_key(DecodedKey::Unicode('\u{3}'), &history), Edit::Cancel);
+}
+
+#[test_case]
+fn line_length_limit()
+{
+    let history = History::new(4);
+    let mut editor = LineEditor::new(3);
+    type_str(&mut editor, &history, "abcd");
+    assert_eq!(editor.line(), "abc");
+}
+
+#[test_case]
+fn history_navigation()
+{
+    let mut history = History::new(2);
+    history.push("first");
+    history.push("second");
+    history.push("second");
+    history.push("");
+    history.push("third");
+    // capacity 2, repetitions and empty lines skipped
+    assert_eq!(history.len(), 2);
+    assert_eq!(history.get(0), Some("second"));
+
+    let mut editor = LineEditor::new(40);
+    type_str(&mut editor, &history, "draft");
+    raw(&mut editor, &history, KeyCode::ArrowUp);
+    assert_eq!(editor.line(), "third");
+    raw(&mut editor, &history, KeyCode::ArrowUp);
+    assert_eq!(editor.line(), "second");
+    assert_eq!(raw(&mut editor, &history, KeyCode::ArrowUp), Edit::Unchanged);
+    raw(&mut editor, &history, KeyCode::ArrowDown);
+    raw(&mut editor, &history, KeyCode::ArrowDown);
+    assert_eq!(editor.line(), "draft");
+    assert_eq!(editor.cursor(), 5);
+}
+
+// presses and releases the key with the given make code, returns what it decoded to
+fn press(keyboard: &mut Keyboard<ActiveLayout, ActiveScancodeSet>, make: &[u8], release: &[u8]) -> Option<DecodedKey>
+{
+    let mut decoded = None;
+    for &byte in make.iter().chain(release)
+    {
+        if let Ok(Some(event)) = keyboard.add_byte(byte)
+        {
+            decoded = decoded.or(keyboard.process_keyevent(event));
+        }
+    }
+    decoded
+}
+
+#[test_case]
+fn layout_switch()
+{
+    let mut decoder = Keyboard::new(ActiveScancodeSet::new(), ActiveLayout, HandleControl::Ignore);
+    // set 1 make code of the key right of T
+    assert_eq!(press(&mut decoder, &[0x15], &[0x95]), Some(DecodedKey::Unicode('y')));
+    keyboard::set_layout(Layout::De105);
+    assert_eq!(keyboard::layout(), Layout::De105);
+    assert_eq!(press(&mut decoder, &[0x15], &[0x95]), Some(DecodedKey::Unicode('z')));
+    keyboard::set_layout(Layout::Us104);
+}
+
+#[test_case]
+fn scancode_set_2()
+{
+    let mut decoder = Keyboard::new(ActiveScancodeSet::new(), ActiveLayout, HandleControl::Ignore);
+    keyboard::set_scancode_set(ScancodeSetKind::Set2).unwrap();
+    assert_eq!(keyboard::scancode_set(), ScancodeSetKind::Set2);
+    // set 2: same key is 0x35, release is prefixed with 0xf0
+    assert_eq!(press(&mut decoder, &[0x35], &[0xf0, 0x35]), Some(DecodedKey::Unicode('y')));
+    keyboard::set_scancode_set(ScancodeSetKind::Set1).unwrap();
+    assert_eq!(press(&mut decoder, &[0x15], &[0x95]), Some(DecodedKey::Unicode('y')));
+}
+
+#[test_case]
+fn keyboard_leds()
+{
+    keyboard::set_leds(keyboard::Leds { scroll_lock: false, num_lock: true, caps_lock: true }).unwrap();
+    keyboard::set_leds(keyboard::Leds::default()).unwrap();
+}
+
+// 8042 command 0xd2: the controller hands `byte` to the CPU as if the keyboard had sent it (IRQ 1)
+fn inject(bytes: &[u8])
+{
+    let mut command = Port::<u8>::new(0x64);
+    let mut data = Port::<u8>::new(0x60);
+    for &byte in bytes
+    {
+        unsafe
+        {
+            while command.read() & 0b10 != 0 {}
+            command.write(0xd2);
+            while command.read() & 0b10 != 0 {}
+            data.write(byte);
+            // wait until the interrupt handler took it
+            while command.read() & 0b1 != 0 {}
+        }
+    }
+}
+
+fn poll_line(keys: &mut KeyEventStream) -> Poll<Result<alloc::string::String, keyboard::ReadLineError>>
+{
+    let waker = futures_util::task::noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut line = Box::pin(keyboard::read_line_from(keys));
+    line.as_mut().poll(&mut context)
+}
+
+// both lines are typed before the first read_line_from: the second one finds its keys in the stream
+#[test_case]
+fn read_lines_typed_ahead()
+{
+    let mut keys = KeyEventStream::new();
+    // set 1: a, b, Shift down, Enter, c, Shift up, Enter. Shift is still down for the second line
+    inject(&[0x1e, 0x9e, 0x30, 0xb0, 0x2a, 0x1c, 0x9c, 0x2e, 0xae, 0xaa, 0x1c, 0x9c]);
+    assert_eq!(poll_line(&mut keys), Poll::Ready(Ok("ab".into())));
+    assert_eq!(poll_line(&mut keys), Poll::Ready(Ok("C".into())));
+    assert!(poll_line(&mut keys).is_pending());
+}
+
+// keys the stream has decoded so far
+fn drain(keys: &mut KeyEventStream) -> alloc::vec::Vec<DecodedKey>
+{
+    let waker = futures_util::task::noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut decoded = alloc::vec::Vec::new();
+    while let Poll::Ready(Some(event)) = Pin::new(&mut *keys).poll_next(&mut context)
+    {
+        decoded.extend(event.key);
+    }
+    decoded
+}
+
+// presses a lock key and waits until both ACKs of the LED update came through the stream
+fn toggle_caps_lock(keys: &mut KeyEventStream)
+{
+    let expected = keys.stats().received + 4;
+    inject(&[0x3a, 0xba]);
+    for _ in 0..100
+    {
+        drain(keys);
+        if keys.stats().received >= expected
+        {
+            break;
+        }
+        time::spin_wait(Duration::from_millis(1));
+    }
+    assert_eq!(keys.stats().received, expected);
+}
+
+// Caps Lock sets the LEDs without blocking: command and LED byte each go out on the previous ACK
+#[test_case]
+fn lock_key_leds()
+{
+    let mut keys = KeyEventStream::new();
+    toggle_caps_lock(&mut keys);
+    inject(&[0x1e, 0x9e]);
+    assert_eq!(drain(&mut keys), [DecodedKey::Unicode('A')]);
+    toggle_caps_lock(&mut keys);
+    inject(&[0x1e, 0x9e]);
+    assert_eq!(drain(&mut keys), [DecodedKey::Unicode('a')]);
+}
@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use my_os::memory::mmio::{self, map_mmio, MMIO_SIZE, MMIO_START};
+use x86_64::PhysAddr;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    mmio::init(mapper, frame_allocator);
+
+    test_main();
+    loop{}
+}
+
+// VGA text buffer is device memory that is safe to poke at in tests
+const VGA_BUFFER: u64 = 0xb8000;
+
+// region lands inside the MMIO area and keeps the offset into its first page
+#[test_case]
+fn region_in_mmio_area()
+{
+    let region = unsafe { map_mmio(PhysAddr::new(VGA_BUFFER + 0x10), 0x20) }.unwrap();
+    let virt = region.virt_addr().as_u64();
+    assert!(virt >= MMIO_START && virt < MMIO_START + MMIO_SIZE);
+    assert_eq!(virt & 0xfff, 0x10);
+    assert_eq!(region.len(), 0x20);
+}
+
+// writing through the region shows up in the VGA buffer and reads back the same
+#[test_case]
+fn read_write_roundtrip()
+{
+    let region = unsafe { map_mmio(PhysAddr::new(VGA_BUFFER), 80 * 25 * 2) }.unwrap();
+    let last_row = 24 * 80 * 2;
+    region.write::<u16>(last_row, 0x0e41);     // yellow 'A'
+    assert_eq!(region.read::<u16>(last_row), 0x0e41);
+    region.write::<u8>(last_row, b' ');
+}
+
+// dropped regions give their virtual range back for reuse
+#[test_case]
+fn drop_releases_virtual_range()
+{
+    let first = unsafe { map_mmio(PhysAddr::new(VGA_BUFFER), 4096) }.unwrap();
+    let virt = first.virt_addr();
+    drop(first);
+    let second = unsafe { map_mmio(PhysAddr::new(VGA_BUFFER), 4096) }.unwrap();
+    assert_eq!(second.virt_addr(), virt);
+}
+
+// many map/unmap cycles must not run out of virtual space
+#[test_case]
+fn many_mappings()
+{
+    for _ in 0..(MMIO_SIZE / 4096) + 1
+    {
+        let region = unsafe { map_mmio(PhysAddr::new(VGA_BUFFER), 2) }.unwrap();
+        region.read::<u16>(0);
+    }
+}
+
+// lengths past the MMIO area fail before any page count is computed
+#[test_case]
+fn oversized_mapping()
+{
+    let too_large = unsafe { map_mmio(PhysAddr::new(VGA_BUFFER + 0x10), MMIO_SIZE as usize) };
+    assert!(matches!(too_large, Err(mmio::MmioError::TooLarge)));
+    let overflowing = unsafe { map_mmio(PhysAddr::new(VGA_BUFFER + 0x10), usize::MAX) };
+    assert!(matches!(overflowing, Err(mmio::MmioError::TooLarge)));
+}
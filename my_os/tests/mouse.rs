@@ -0,0 +1,135 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::Stream;
+use my_os::ps2;
+use my_os::task::mouse::{MouseButton, MouseEvent, MouseKind, MouseStream};
+use x86_64::instructions::port::Port;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop{}
+}
+
+// 8042 command 0xd3: the controller hands `byte` to the CPU as if the mouse had sent it (IRQ 12)
+fn inject(bytes: &[u8])
+{
+    let mut command = Port::<u8>::new(0x64);
+    let mut data = Port::<u8>::new(0x60);
+    for &byte in bytes
+    {
+        unsafe
+        {
+            while command.read() & 0b10 != 0 {}
+            command.write(0xd3);
+            while command.read() & 0b10 != 0 {}
+            data.write(byte);
+            // wait until the interrupt handler took it
+            while command.read() & 0b1 != 0 {}
+        }
+    }
+}
+
+// everything the stream has decoded so far
+fn drain(stream: &mut MouseStream) -> Vec<MouseEvent>
+{
+    let waker = futures_util::task::noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut events = Vec::new();
+    while let Poll::Ready(Some(event)) = Pin::new(&mut *stream).poll_next(&mut context)
+    {
+        events.push(event);
+    }
+    events
+}
+
+#[test_case]
+fn controller_ports()
+{
+    // QEMU emulates keyboard and mouse
+    assert_eq!(ps2::ports(), Some(ps2::Ports { first: true, second: true }));
+}
+
+#[test_case]
+fn mouse_packets()
+{
+    let mut mouse = MouseStream::new().unwrap();
+    // QEMU's mouse understands the IntelliMouse extensions
+    assert_ne!(mouse.kind(), MouseKind::Standard);
+    drain(&mut mouse);
+
+    // left button down, 5 right, 3 up, wheel one step
+    inject(&[0b0000_1001, 5, 3, 0x01]);
+    assert_eq!(drain(&mut mouse), [
+        MouseEvent::Moved { dx: 5, dy: -3 },
+        MouseEvent::Scrolled(1),
+        MouseEvent::ButtonDown(MouseButton::Left),
+    ]);
+
+    // button released, 2 left (negative x)
+    inject(&[0b0001_1000, 0xfe, 0, 0]);
+    assert_eq!(drain(&mut mouse), [
+        MouseEvent::Moved { dx: -2, dy: 0 },
+        MouseEvent::ButtonUp(MouseButton::Left),
+    ]);
+
+    // out of sync byte (bit 3 clear) is skipped
+    inject(&[0x00, 0b0000_1010, 0, 0, 0]);
+    assert_eq!(drain(&mut mouse), [MouseEvent::ButtonDown(MouseButton::Right)]);
+}
+
+// a keystroke waiting in the controller is not taken as the mouse's ACK or ID
+#[test_case]
+fn mouse_reply_skips_keyboard_byte()
+{
+    let mut command = Port::<u8>::new(0x64);
+    let mut data = Port::<u8>::new(0x60);
+    let mut id = [0];
+    x86_64::instructions::interrupts::without_interrupts(||
+    {
+        // 8042 command 0xd2: 'a' pressed, as if the keyboard had sent it
+        unsafe
+        {
+            while command.read() & 0b10 != 0 {}
+            command.write(0xd2);
+            while command.read() & 0b10 != 0 {}
+            data.write(0x1e);
+        }
+        // get device ID
+        ps2::mouse_command(0xf2, &[], &mut id)
+    }).unwrap();
+    assert!(matches!(id[0], 0 | 3 | 4), "mouse ID {:#x}", id[0]);
+}
@@ -0,0 +1,168 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::task::{Context, Poll};
+use core::time::Duration;
+use my_os::serial::{self, ComPort, LineConfig, Parity, Role, SerialError, StopBits};
+use my_os::task::keyboard::ReadLineError;
+use my_os::task::serial::{read_line_from, SerialKeys};
+use my_os::{serial_print, serial_println, time};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+// interrupt handler registration needs the heap. The tests run with buffered transmit
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    serial::init_tx_buffer();
+
+    test_main();
+    loop{}
+}
+
+// "types" `bytes` on COM1 through loopback mode and waits until the interrupt handler queued them
+// a few bytes at a time: the receive FIFO holds only 16
+fn type_bytes(keys: &SerialKeys, bytes: &[u8])
+{
+    // queued output (test names, echo) must not come back as input
+    serial::flush();
+    serial::set_loopback(ComPort::Com1, true);
+    for chunk in bytes.chunks(8)
+    {
+        let expected = keys.stats().received + chunk.len() as u64;
+        for &byte in chunk
+        {
+            serial_print!("{}", char::from(byte));
+        }
+        // the UART interrupts on a full FIFO or after a few idle character times
+        for _ in 0..100
+        {
+            if keys.stats().received >= expected
+            {
+                break;
+            }
+            time::spin_wait(Duration::from_millis(1));
+        }
+        assert_eq!(keys.stats().received, expected);
+    }
+    serial::set_loopback(ComPort::Com1, false);
+}
+
+fn poll_line(keys: &mut SerialKeys) -> Poll<Result<alloc::string::String, ReadLineError>>
+{
+    let waker = futures_util::task::noop_waker();
+    let mut context = Context::from_waker(&waker);
+    let mut line = Box::pin(read_line_from(keys));
+    line.as_mut().poll(&mut context)
+}
+
+#[test_case]
+fn read_line_with_terminal_keys()
+{
+    let mut keys = SerialKeys::new();
+    // x a b, DEL (backspace), Left, c, Home (SS3), Delete, CR LF
+    type_bytes(&keys, b"xab\x7f\x1b[Dc\x1bOH\x1b[3~\r\n");
+    assert_eq!(keys.stats().dropped, 0);
+    assert_eq!(poll_line(&mut keys), Poll::Ready(Ok("ca".into())));
+    // LF of CR LF is not a second, empty line
+    assert!(poll_line(&mut keys).is_pending());
+}
+
+#[test_case]
+fn read_line_interrupted()
+{
+    let mut keys = SerialKeys::new();
+    type_bytes(&keys, b"abc\x03");
+    assert_eq!(poll_line(&mut keys), Poll::Ready(Err(ReadLineError::Interrupted)));
+    type_bytes(&keys, b"line\n");
+    assert_eq!(poll_line(&mut keys), Poll::Ready(Ok("line".into())));
+}
+
+// printing only queues, the THR-empty interrupt sends
+#[test_case]
+fn buffered_transmit()
+{
+    let before = serial::tx_stats(ComPort::Com1);
+    for i in 0..100
+    {
+        serial_println!("buffered line {:3} of 100", i);
+    }
+    serial::flush();
+    let after = serial::tx_stats(ComPort::Com1);
+    let queued = after.queued - before.queued;
+    assert_eq!(queued, 100 * 25);
+    // the rest was sent by flush
+    assert!(after.sent - before.sent + after.blocking - before.blocking >= queued);
+    assert!(after.sent > before.sent);
+}
+
+#[test_case]
+fn detected_ports()
+{
+    // QEMU is started with one serial port
+    assert!(serial::is_present(ComPort::Com1));
+    assert_eq!(serial::config(ComPort::Com1), Some(LineConfig::DEFAULT));
+    for port in ComPort::ALL.iter().copied().filter(|&port| !serial::is_present(port))
+    {
+        assert_eq!(serial::configure(port, LineConfig::DEFAULT), Err(SerialError::NotPresent(port)));
+        assert_eq!(serial::assign(Role::Log, Some(port)), Err(SerialError::NotPresent(port)));
+        assert_eq!(serial::config(port), None);
+    }
+}
+
+#[test_case]
+fn line_settings()
+{
+    let config = LineConfig { baud: 115_200, data_bits: 7, parity: Parity::Even, stop_bits: StopBits::Two };
+    assert_eq!(alloc::format!("{}", config), "115200 7E2");
+    assert_eq!(serial::configure(ComPort::Com1, LineConfig { baud: 1234, ..config }), Err(SerialError::InvalidBaud(1234)));
+    assert_eq!(serial::configure(ComPort::Com1, LineConfig { data_bits: 9, ..config }), Err(SerialError::InvalidDataBits(9)));
+
+    serial::configure(ComPort::Com1, config).unwrap();
+    assert_eq!(serial::config(ComPort::Com1), Some(config));
+    // loopback works with any settings
+    let mut keys = SerialKeys::open(ComPort::Com1);
+    type_bytes(&keys, b"7e2\n");
+    assert_eq!(poll_line(&mut keys), Poll::Ready(Ok("7e2".into())));
+    serial::configure(ComPort::Com1, LineConfig::DEFAULT).unwrap();
+}
+
+#[test_case]
+fn roles()
+{
+    assert_eq!(serial::port_for(Role::Console), Some(ComPort::Com1));
+    assert_eq!(serial::port_for(Role::Test), Some(ComPort::Com1));
+    assert_eq!(serial::port_for(Role::Debugger), None);
+
+    serial::assign(Role::Debugger, Some(ComPort::Com1)).unwrap();
+    assert_eq!(serial::port_for(Role::Debugger), Some(ComPort::Com1));
+    serial::assign(Role::Debugger, None).unwrap();
+    assert_eq!(serial::port_for(Role::Debugger), None);
+}
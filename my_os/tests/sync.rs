@@ -0,0 +1,401 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use my_os::task::executor::Executor;
+use my_os::task::sync::{broadcast, mpsc, oneshot, Mutex, Notify, RwLock, Semaphore};
+use my_os::task::Task;
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop{}
+}
+
+// gives up the CPU once: wakes itself and returns Pending on the first poll
+struct YieldNow(bool);
+
+impl Future for YieldNow
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()>
+    {
+        if self.0
+        {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+fn yield_now() -> YieldNow
+{
+    YieldNow(false)
+}
+
+// runs rounds until every task finished or waits for something that never comes
+fn run(executor: &mut Executor)
+{
+    for _ in 0..32
+    {
+        executor.run_ready_tasks();
+    }
+}
+
+// guard is held across an await point, second task waits for it
+#[test_case]
+fn mutex_held_across_await()
+{
+    let mut executor = Executor::new();
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let first = log.clone();
+    executor.spawn(Task::new(async move
+    {
+        let mut guard = first.lock().await;
+        guard.push(1);
+        yield_now().await;
+        guard.push(2);
+    }));
+    let second = log.clone();
+    executor.spawn(Task::new(async move
+    {
+        second.lock().await.push(3);
+    }));
+
+    run(&mut executor);
+    assert_eq!(*log.try_lock().unwrap(), [1, 2, 3]);
+}
+
+#[test_case]
+fn mutex_try_lock()
+{
+    let mutex = Mutex::new(0);
+    let guard = mutex.try_lock().unwrap();
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert!(mutex.try_lock().is_some());
+}
+
+// readers share the lock, a writer excludes them
+#[test_case]
+fn rwlock_readers_and_writer()
+{
+    let lock = RwLock::new(5);
+    let first = lock.try_read().unwrap();
+    let second = lock.try_read().unwrap();
+    assert_eq!(*first + *second, 10);
+    assert!(lock.try_write().is_none());
+    drop(first);
+    drop(second);
+
+    let mut writer = lock.try_write().unwrap();
+    *writer = 6;
+    assert!(lock.try_read().is_none());
+    drop(writer);
+    assert_eq!(*lock.try_read().unwrap(), 6);
+}
+
+#[test_case]
+fn rwlock_writer_waits_for_readers()
+{
+    let mut executor = Executor::new();
+    let lock = Arc::new(RwLock::new(Vec::new()));
+
+    let reader = lock.clone();
+    executor.spawn(Task::new(async move
+    {
+        let guard = reader.read().await;
+        yield_now().await;
+        assert!(guard.is_empty());
+    }));
+    let writer = lock.clone();
+    executor.spawn(Task::new(async move
+    {
+        writer.write().await.push(1);
+    }));
+
+    run(&mut executor);
+    assert_eq!(*lock.try_read().unwrap(), [1]);
+}
+
+// a waiting writer keeps new readers out, it gets the lock once the current ones leave
+#[test_case]
+fn rwlock_writer_preferred()
+{
+    let mut executor = Executor::new();
+    let lock = Arc::new(RwLock::new(0));
+    let reader = lock.try_read().unwrap();
+
+    let writer = lock.clone();
+    executor.spawn(Task::new(async move
+    {
+        *writer.write().await = 1;
+    }));
+    run(&mut executor);
+    assert!(lock.try_read().is_none());
+
+    drop(reader);
+    run(&mut executor);
+    assert_eq!(*lock.try_read().unwrap(), 1);
+}
+
+// never more tasks inside than permits
+#[test_case]
+fn semaphore_limits_concurrency()
+{
+    let mut executor = Executor::new();
+    let semaphore = Arc::new(Semaphore::new(2));
+    let active = Arc::new(AtomicUsize::new(0));
+    let max_active = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..5
+    {
+        let semaphore = semaphore.clone();
+        let active = active.clone();
+        let max_active = max_active.clone();
+        let done = done.clone();
+        executor.spawn(Task::new(async move
+        {
+            let _permit = semaphore.acquire().await;
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            max_active.fetch_max(now, Ordering::SeqCst);
+            yield_now().await;
+            active.fetch_sub(1, Ordering::SeqCst);
+            done.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+
+    run(&mut executor);
+    assert_eq!(done.load(Ordering::SeqCst), 5);
+    assert_eq!(max_active.load(Ordering::SeqCst), 2);
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+#[test_case]
+fn semaphore_forget_and_add_permits()
+{
+    let semaphore = Semaphore::new(1);
+    semaphore.try_acquire().unwrap().forget();
+    assert!(semaphore.try_acquire().is_none());
+    semaphore.add_permits(2);
+    assert_eq!(semaphore.available_permits(), 2);
+}
+
+// notify_one before anybody waits is stored, notify_waiters is not
+#[test_case]
+fn notify_permit()
+{
+    let mut executor = Executor::new();
+    let notify = Arc::new(Notify::new());
+    let woken = Arc::new(AtomicUsize::new(0));
+
+    notify.notify_waiters();
+    notify.notify_one();
+    for _ in 0..2
+    {
+        let notify = notify.clone();
+        let woken = woken.clone();
+        executor.spawn(Task::new(async move
+        {
+            notify.notified().await;
+            woken.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    run(&mut executor);
+    assert_eq!(woken.load(Ordering::SeqCst), 1);
+
+    notify.notify_waiters();
+    run(&mut executor);
+    assert_eq!(woken.load(Ordering::SeqCst), 2);
+}
+
+// every notify_one goes to its own waiter, none is left over as permit
+#[test_case]
+fn notify_one_per_waiter()
+{
+    let mut executor = Executor::new();
+    let notify = Arc::new(Notify::new());
+    let woken = Arc::new(AtomicUsize::new(0));
+
+    for _ in 0..2
+    {
+        let notify = notify.clone();
+        let woken = woken.clone();
+        executor.spawn(Task::new(async move
+        {
+            notify.notified().await;
+            woken.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    run(&mut executor);
+    assert_eq!(woken.load(Ordering::SeqCst), 0);
+
+    notify.notify_one();
+    notify.notify_one();
+    // a task that was not waiting must not take a notification
+    let waker = futures_util::task::noop_waker();
+    let mut late = Box::pin(notify.notified());
+    assert!(late.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+    drop(late);
+
+    run(&mut executor);
+    assert_eq!(woken.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn mpsc_bounded()
+{
+    let mut executor = Executor::new();
+    let (tx, mut rx) = mpsc::channel(2);
+    assert_eq!(tx.try_send(1), Ok(()));
+    assert_eq!(tx.try_send(2), Ok(()));
+    assert_eq!(tx.try_send(3), Err(mpsc::TrySendError::Full(3)));
+
+    // send waits for the receiver to make room
+    let sender = tx.clone();
+    executor.spawn(Task::new(async move
+    {
+        sender.send(3).await.unwrap();
+        sender.send(4).await.unwrap();
+    }));
+    drop(tx);
+
+    let received = Arc::new(spin::Mutex::new(Vec::new()));
+    let log = received.clone();
+    executor.spawn(Task::new(async move
+    {
+        while let Some(value) = rx.recv().await
+        {
+            log.lock().push(value);
+        }
+    }));
+
+    run(&mut executor);
+    assert_eq!(*received.lock(), [1, 2, 3, 4]);
+}
+
+#[test_case]
+fn mpsc_unbounded_closed()
+{
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    for i in 0..200
+    {
+        tx.send(i).unwrap();
+    }
+    assert_eq!(rx.len(), 200);
+    assert_eq!(rx.try_recv(), Ok(0));
+    drop(tx);
+    assert_eq!(rx.try_recv(), Ok(1));
+
+    let (tx, rx) = mpsc::unbounded_channel::<u32>();
+    drop(rx);
+    assert!(tx.is_closed());
+    assert_eq!(tx.send(1), Err(mpsc::SendError(1)));
+}
+
+#[test_case]
+fn oneshot_send_and_close()
+{
+    let mut executor = Executor::new();
+    let (tx, rx) = oneshot::channel();
+    let (closed_tx, closed_rx) = oneshot::channel::<u32>();
+    let results = Arc::new(spin::Mutex::new(Vec::new()));
+
+    let log = results.clone();
+    executor.spawn(Task::new(async move
+    {
+        log.lock().push(rx.await);
+        log.lock().push(closed_rx.await);
+    }));
+    executor.run_ready_tasks();
+    assert_eq!(tx.send(7), Ok(()));
+    drop(closed_tx);
+
+    run(&mut executor);
+    assert_eq!(*results.lock(), [Ok(7), Err(oneshot::RecvError)]);
+}
+
+#[test_case]
+fn broadcast_every_receiver()
+{
+    let (tx, mut first) = broadcast::channel(4);
+    let mut second = tx.subscribe();
+    assert_eq!(tx.send(1), Ok(2));
+    assert_eq!(first.try_recv(), Ok(1));
+    assert_eq!(second.try_recv(), Ok(1));
+    assert_eq!(first.try_recv(), Err(broadcast::TryRecvError::Empty));
+    drop(tx);
+    assert_eq!(first.try_recv(), Err(broadcast::TryRecvError::Closed));
+}
+
+// slow receiver loses the oldest values and is told how many
+#[test_case]
+fn broadcast_lagged()
+{
+    let mut executor = Executor::new();
+    let (tx, mut rx) = broadcast::channel(2);
+    for i in 0..5
+    {
+        tx.send(i).unwrap();
+    }
+    drop(tx);
+
+    let results = Arc::new(spin::Mutex::new(Vec::new()));
+    let log = results.clone();
+    executor.spawn(Task::new(async move
+    {
+        loop
+        {
+            let result = rx.recv().await;
+            log.lock().push(result);
+            if result == Err(broadcast::RecvError::Closed)
+            {
+                break;
+            }
+        }
+    }));
+
+    run(&mut executor);
+    assert_eq!(*results.lock(),
+        [Err(broadcast::RecvError::Lagged(3)), Ok(3), Ok(4), Err(broadcast::RecvError::Closed)]);
+}
@@ -0,0 +1,158 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::future::Future;
+use core::time::Duration;
+use my_os::time::{self, pit, rtc, timer, tsc, DateTime, Instant};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+// heap is needed by rtc::ticks (interrupt handler registration)
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop{}
+}
+
+#[test_case]
+fn tsc_calibrated()
+{
+    let frequency = tsc::frequency().expect("TSC not calibrated");
+    // anything between 100 MHz and 10 GHz is plausible
+    assert!(frequency > 100_000_000 && frequency < 10_000_000_000);
+}
+
+#[test_case]
+fn pit_frequency()
+{
+    // 1193182 / 11931 = 100.007 Hz
+    assert_eq!(pit::frequency_millihertz() / 1000, u64::from(time::TICK_FREQUENCY));
+}
+
+#[test_case]
+fn now_is_monotonic()
+{
+    let mut last = time::now();
+    for _ in 0..1000
+    {
+        let now = time::now();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+// TSC clock and PIT interrupts agree: 100 ms are 10 ticks at 100 Hz
+#[test_case]
+fn clock_matches_timer_interrupts()
+{
+    let start_ticks = pit::ticks();
+    let start = Instant::now();
+    time::spin_wait(Duration::from_millis(100));
+    let elapsed = start.elapsed();
+    let ticks = pit::ticks() - start_ticks;
+
+    assert!(elapsed >= Duration::from_millis(100));
+    assert!(ticks >= 9 && ticks <= 11, "{} timer ticks in 100 ms", ticks);
+}
+
+#[test_case]
+fn instant_arithmetic()
+{
+    let start = Instant::from_nanos(1_000);
+    let later = start + Duration::from_micros(2);
+    assert_eq!(later.as_nanos(), 3_000);
+    assert_eq!(later - start, Duration::from_nanos(2_000));
+    assert_eq!(start - later, Duration::ZERO);
+    assert_eq!(start.checked_sub(Duration::from_micros(2)), None);
+}
+
+#[test_case]
+fn unix_timestamp_conversion()
+{
+    let leap_day = DateTime { year: 2000, month: 2, day: 29, hour: 12, minute: 30, second: 15, nanosecond: 0 };
+    assert_eq!(leap_day.unix_timestamp(), 951_827_415);
+    assert_eq!(DateTime::from_unix_timestamp(951_827_415), leap_day);
+    assert_eq!(DateTime::from_unix_timestamp(0), DateTime::UNIX_EPOCH);
+    // no leap day in 2023
+    assert!(!DateTime { year: 2023, ..leap_day }.is_valid());
+}
+
+#[test_case]
+fn date_time_display()
+{
+    let date_time = DateTime::from_unix_timestamp(1_709_296_496);
+    assert_eq!(alloc::format!("{}", date_time), "2024-03-01T12:34:56Z");
+}
+
+// RTC of the test machine is set to the host time
+#[test_case]
+fn wall_clock_plausible()
+{
+    let rtc_time = rtc::read();
+    assert!(rtc_time.is_valid());
+    assert!(rtc_time.year >= 2020);
+
+    let wall_clock = time::wall_clock();
+    let difference = wall_clock.unix_timestamp() as i64 - rtc_time.unix_timestamp() as i64;
+    assert!(difference.abs() <= 1, "wall clock {} vs RTC {}", wall_clock, rtc_time);
+}
+
+#[test_case]
+fn rtc_periodic_ticks()
+{
+    let ticks = rtc::ticks(rtc::TickSource::Periodic { hz: 1024 });
+    time::spin_wait(Duration::from_millis(50));
+    let signals = ticks.stats().signals;
+    // about 51 interrupts
+    assert!(signals >= 40 && signals <= 60, "{} RTC interrupts in 50 ms", signals);
+}
+
+// without HPET channel 0 counts down once to the deadline while idle, instead of ticking
+#[test_case]
+fn pit_tickless_idle()
+{
+    pit::use_as_wakeup_source();
+    let mut sleep = alloc::boxed::Box::pin(timer::sleep(Duration::from_millis(30)));
+    let waker = futures_util::task::noop_waker();
+    let _ = sleep.as_mut().poll(&mut core::task::Context::from_waker(&waker));
+
+    x86_64::instructions::interrupts::disable();
+    assert!(timer::idle_enter());
+    let start_ticks = pit::ticks();
+    x86_64::instructions::interrupts::enable_and_hlt();
+    timer::idle_exit();
+    // one interrupt at the deadline instead of three ticks
+    assert_eq!(pit::ticks(), start_ticks + 1);
+    assert!(sleep.is_elapsed());
+
+    // periodic tick again
+    time::spin_wait(Duration::from_millis(30));
+    assert!(pit::ticks() >= start_ticks + 3);
+}
@@ -0,0 +1,220 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(my_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::future::Future;
+use core::time::Duration;
+use my_os::interrupts::{register_irq, unregister_irq, IrqReturn};
+use my_os::task::executor::Executor;
+use my_os::task::Task;
+use my_os::time::{self, hpet, timer, ClockSource, Instant};
+
+entry_point!(main);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+// HPET needs the heap and memory::mmio for its register block and the ACPI tables
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::allocator;
+    use my_os::memory::{self, mmio, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    my_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe
+    {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    mmio::init(mapper, frame_allocator);
+    // QEMU's pc and q35 machines both have an HPET
+    hpet::init().expect("HPET initialization failed");
+
+    test_main();
+    loop{}
+}
+
+// drives the executor until `done` is set or `limit` passed
+fn run_until(executor: &mut Executor, done: &AtomicBool, limit: Duration)
+{
+    let deadline = time::now() + limit;
+    while !done.load(Ordering::SeqCst) && time::now() < deadline
+    {
+        executor.run_ready_tasks();
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn hpet_counter_runs()
+{
+    let frequency = hpet::frequency().unwrap();
+    // specification: at least 10 MHz
+    assert!(frequency >= 10_000_000, "HPET at {} Hz", frequency);
+
+    let start = hpet::counter().unwrap();
+    time::spin_wait(Duration::from_millis(1));
+    let ticks = hpet::counter().unwrap() - start;
+    let nanos = hpet::ticks_to_nanos(ticks);
+    assert!(nanos >= 1_000_000 && nanos < 5_000_000, "1 ms measured as {} ns", nanos);
+}
+
+#[test_case]
+fn hpet_clock_source()
+{
+    let before = time::now();
+    assert!(time::set_clock_source(ClockSource::Hpet));
+    assert_eq!(time::clock_source(), ClockSource::Hpet);
+    // switching does not make now() jump back
+    assert!(time::now() >= before);
+    assert!(time::set_clock_source(ClockSource::Tsc));
+}
+
+#[test_case]
+fn comparator_one_shot()
+{
+    let comparator = hpet::comparator(1).unwrap();
+    assert_eq!(comparator.irq(), 8);
+    assert!(matches!(hpet::comparator(1), Err(hpet::HpetError::InUse(1))));
+
+    static FIRED: AtomicU64 = AtomicU64::new(0);
+    let handle = register_irq(comparator.irq(), ||
+    {
+        FIRED.fetch_add(1, Ordering::SeqCst);
+        IrqReturn::Handled
+    });
+    comparator.set_one_shot_at(time::now() + Duration::from_millis(5));
+    time::spin_wait(Duration::from_millis(2));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 0);
+    time::spin_wait(Duration::from_millis(10));
+    assert_eq!(FIRED.load(Ordering::SeqCst), 1);
+
+    unregister_irq(handle);
+}
+
+#[test_case]
+fn only_legacy_route()
+{
+    // comparators 2 and up have no legacy route, and there is no other
+    assert!(matches!(hpet::comparator(2),
+        Err(hpet::HpetError::LegacyRouteUnsupported) | Err(hpet::HpetError::NoSuchComparator(2))));
+}
+
+#[test_case]
+fn sleep_wakes_task()
+{
+    let mut executor = Executor::new();
+    let done = Arc::new(AtomicBool::new(false));
+    let woken_at = Arc::new(AtomicU64::new(0));
+    let start = Instant::now();
+    {
+        let done = done.clone();
+        let woken_at = woken_at.clone();
+        executor.spawn(Task::new(async move
+        {
+            timer::sleep(Duration::from_millis(30)).await;
+            woken_at.store(Instant::now().as_nanos(), Ordering::SeqCst);
+            done.store(true, Ordering::SeqCst);
+        }));
+    }
+    executor.run_ready_tasks();
+    assert!(timer::next_deadline().is_some());
+    run_until(&mut executor, &done, Duration::from_secs(1));
+
+    assert!(done.load(Ordering::SeqCst));
+    let slept = Instant::from_nanos(woken_at.load(Ordering::SeqCst)) - start;
+    assert!(slept >= Duration::from_millis(30));
+    assert_eq!(timer::next_deadline(), None);
+}
+
+#[test_case]
+fn timeout_elapses()
+{
+    let mut executor = Executor::new();
+    let done = Arc::new(AtomicBool::new(false));
+    {
+        let done = done.clone();
+        executor.spawn(Task::new(async move
+        {
+            let slow = timer::timeout(Duration::from_millis(10), timer::sleep(Duration::from_secs(10))).await;
+            assert_eq!(slow, Err(timer::Elapsed));
+            let fast = timer::timeout(Duration::from_secs(10), async { 7 }).await;
+            assert_eq!(fast, Ok(7));
+            done.store(true, Ordering::SeqCst);
+        }));
+    }
+    run_until(&mut executor, &done, Duration::from_secs(1));
+    assert!(done.load(Ordering::SeqCst));
+    // the dropped inner sleep left no entry behind
+    assert_eq!(timer::next_deadline(), None);
+}
+
+// HPET as system timer: ticks keep coming and sleeping tasks are woken by the comparator interrupt
+#[test_case]
+fn hpet_system_timer()
+{
+    hpet::use_as_system_timer().unwrap();
+    assert_eq!(time::clock_source(), ClockSource::Hpet);
+
+    let start_ticks = time::pit::ticks();
+    time::spin_wait(Duration::from_millis(100));
+    let ticks = time::pit::ticks() - start_ticks;
+    assert!(ticks >= 9 && ticks <= 11, "{} timer ticks in 100 ms", ticks);
+
+    let mut executor = Executor::new();
+    let done = Arc::new(AtomicBool::new(false));
+    {
+        let done = done.clone();
+        executor.spawn(Task::new(async move
+        {
+            timer::sleep(Duration::from_millis(3)).await;
+            done.store(true, Ordering::SeqCst);
+        }));
+    }
+    run_until(&mut executor, &done, Duration::from_secs(1));
+    assert!(done.load(Ordering::SeqCst));
+}
+
+// runs after hpet_system_timer: with the tick stopped only the armed deadline raises an interrupt
+#[test_case]
+fn tickless_idle()
+{
+    let before = timer::idle_stats();
+    let sleep = timer::sleep(Duration::from_millis(50));
+    let mut sleep = alloc::boxed::Box::pin(sleep);
+    // registers the deadline and arms comparator 1
+    let waker = futures_util::task::noop_waker();
+    let _ = sleep.as_mut().poll(&mut core::task::Context::from_waker(&waker));
+
+    x86_64::instructions::interrupts::disable();
+    assert!(timer::idle_enter());
+    let start_ticks = time::pit::ticks();
+    x86_64::instructions::interrupts::enable_and_hlt();
+    timer::idle_exit();
+    // woken by the deadline, not by a tick
+    assert_eq!(time::pit::ticks(), start_ticks);
+    assert!(sleep.is_elapsed());
+
+    let after = timer::idle_stats();
+    assert_eq!(after.idle_entries, before.idle_entries + 1);
+    assert_eq!(after.tickless, before.tickless + 1);
+
+    // tick runs again
+    time::spin_wait(Duration::from_millis(30));
+    assert!(time::pit::ticks() > start_ticks);
+}
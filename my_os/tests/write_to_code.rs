@@ -0,0 +1,70 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use my_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+entry_point!(main);
+
+// Writes into the kernel's own .text section after memory::protect_kernel
+// With W^X and CR0.WP, the write must end in a page fault instead of patching the code
+fn main(boot_info: &'static BootInfo) -> !
+{
+    use my_os::memory;
+    use x86_64::VirtAddr;
+
+    serial_print!("write_to_code::write_to_code...\t");
+
+    my_os::gdt::init();
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::protect_kernel(&mut mapper) }
+        .expect("kernel section protection failed");
+
+    let code = main as fn(&'static BootInfo) -> ! as *mut u8;
+    unsafe { core::ptr::write_volatile(code, 0x90) }; // nop
+
+    panic!("write to .text did not page fault");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    my_os::test_panic_handler(info)
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable =
+    {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+// write to a present page -> PROTECTION_VIOLATION and CAUSED_BY_WRITE must both be set
+extern "x86-interrupt" fn test_page_fault_handler(_stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
+{
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    else
+    {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault {:?}\n", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}